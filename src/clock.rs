@@ -0,0 +1,36 @@
+//! Where the monitor reads the time from and waits on. Hold times, recovery
+//! timing, maintenance windows and every sleep go through a [`Clock`], so
+//! tests can step a [`MockClock`](crate::mock::MockClock) forward instead of
+//! sleeping.
+
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Monotonic time, for measuring how long something took or has held
+    fn now(&self) -> Instant;
+    /// Wall-clock time, for timestamps and maintenance windows
+    fn utc_now(&self) -> DateTime<Utc>;
+    /// Waits for `duration` to pass on this clock
+    fn sleep(&self, duration: Duration);
+}
+
+/// The operating system's clocks; what the daemon uses.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
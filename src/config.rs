@@ -0,0 +1,887 @@
+//! Resolved settings, and parsers for the env files and WireGuard configs
+//! they can come from.
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::hooks::SwitchHooks;
+use crate::ip::IpAddress;
+
+/// Settings read from `<PREFIX>_*` environment variables. These sit between
+/// command line arguments and the config file in precedence.
+#[derive(Debug, Default, PartialEq)]
+pub struct EnvOverrides {
+    pub config: Option<PathBuf>,
+    pub peer_ip: Option<String>,
+    pub primary: Option<String>,
+    pub secondary: Option<String>,
+    pub interfaces: Option<String>, // comma separated, most preferred first
+    pub interval: Option<u64>,
+    pub speedtest_interval: Option<u64>,
+    pub speed_threshold: Option<u8>,
+    pub test_ips: Option<String>,
+    pub route_all_traffic: Option<bool>,
+}
+
+impl EnvOverrides {
+    pub fn from_env_prefix(prefix: &str) -> Result<Self> {
+        Self::from_vars(prefix, std::env::vars())
+    }
+
+    /// Reads `<PREFIX>_*` settings from `(key, value)` pairs, such as
+    /// `std::env::vars()` or a parsed env file.
+    pub fn from_vars<K, V>(prefix: &str, vars: impl IntoIterator<Item = (K, V)>) -> Result<Self>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let vars: HashMap<String, String> = vars.into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+        Self::from_lookup(prefix, |key| vars.get(key).cloned())
+    }
+
+    /// Reads `<PREFIX>_*` variables from a `KEY=VALUE` env file, as written by
+    /// container entrypoints.
+    pub fn from_env_file(prefix: &str, path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read env file {:?}", path))?;
+        Self::from_vars(prefix, parse_env_file(&content))
+    }
+
+    /// The interfaces these overrides ask for, most preferred first:
+    /// `<PREFIX>_INTERFACES`, or else `current`, with `_PRIMARY` and
+    /// `_SECONDARY` taking the first two places.
+    pub fn interface_slots(&self, current: &[String]) -> Vec<Option<String>> {
+        let listed = self.interfaces.as_deref().map(parse_list).unwrap_or_else(|| current.to_vec());
+        interface_slots(self.primary.clone(), self.secondary.clone(), listed.into_iter().map(Some).collect())
+    }
+
+    pub fn from_lookup(prefix: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let var = |name: &str| lookup(&format!("{}_{}", prefix, name));
+        let parse = |name: &str| -> Result<Option<u64>> {
+            var(name)
+                .map(|v| v.trim().parse::<u64>().with_context(|| format!("Invalid value for {}_{}: {}", prefix, name, v)))
+                .transpose()
+        };
+
+        Ok(Self {
+            config: var("CONFIG").map(PathBuf::from),
+            peer_ip: var("PEER_IP"),
+            primary: var("PRIMARY"),
+            secondary: var("SECONDARY"),
+            interfaces: var("INTERFACES"),
+            interval: parse("INTERVAL")?,
+            speedtest_interval: parse("SPEEDTEST_INTERVAL")?,
+            speed_threshold: parse("SPEED_THRESHOLD")?
+                .map(|v| u8::try_from(v).with_context(|| format!("{}_SPEED_THRESHOLD out of range: {}", prefix, v)))
+                .transpose()?,
+            test_ips: var("TEST_IPS"),
+            route_all_traffic: var("ROUTE_ALL_TRAFFIC").map(|v| matches!(v.trim(), "1" | "true" | "yes")),
+        })
+    }
+}
+
+/// Fills the configured interface slots (most preferred first), letting a
+/// separately given primary and secondary take the first two. A name the list already has further down is moved up;
+/// any other name replaces what was configured in that slot.
+pub fn interface_slots(primary: Option<String>, secondary: Option<String>, mut slots: Vec<Option<String>>) -> Vec<Option<String>> {
+    for (index, name) in [(0, primary), (1, secondary)] {
+        let Some(name) = name else { continue };
+        while slots.len() <= index {
+            slots.push(None);
+        }
+        match slots.iter().skip(index + 1).position(|s| s.as_ref() == Some(&name)) {
+            Some(pos) => {
+                let moved = slots.remove(index + 1 + pos);
+                slots.insert(index, moved);
+            }
+            None => slots[index] = Some(name),
+        }
+    }
+    slots
+}
+
+/// Splits a comma separated list, dropping empty entries.
+pub fn parse_list(list: &str) -> Vec<String> {
+    list.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect()
+}
+
+pub fn parse_env_file(content: &str) -> HashMap<String, String> {
+    content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"').and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value);
+            Some((key.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// A prefix kept off the managed default route in route-all mode.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RouteExclusion {
+    pub prefix: String, // e.g. "192.168.1.0/24" or "203.0.113.5"
+    pub interface: Option<String>,
+    pub gateway: Option<String>,
+}
+
+impl RouteExclusion {
+    pub fn validate(&self) -> Result<()> {
+        IpAddress::parse_cidr(&self.prefix).with_context(|| format!("Invalid route exclusion prefix {:?}", self.prefix))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct MaintenanceWindowConfig {
+    pub start: String, // RFC 3339, e.g. "2026-10-20T02:00:00+02:00"
+    pub end: String,
+    pub inhibit_failover: Option<bool>,
+    pub inhibit_failback: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub inhibit_failover: bool,
+    pub inhibit_failback: bool,
+}
+
+impl MaintenanceWindow {
+    pub fn from_config(config: &MaintenanceWindowConfig) -> Result<Self> {
+        let parse = |value: &str| -> Result<SystemTime> {
+            chrono::DateTime::parse_from_rfc3339(value)
+                .map(SystemTime::from)
+                .with_context(|| format!("Invalid maintenance window time '{}' (expected RFC 3339)", value))
+        };
+        let window = Self {
+            start: parse(&config.start)?,
+            end: parse(&config.end)?,
+            inhibit_failover: config.inhibit_failover.unwrap_or(true),
+            inhibit_failback: config.inhibit_failback.unwrap_or(true),
+        };
+        if window.end <= window.start {
+            return Err(anyhow::anyhow!("Maintenance window ends before it starts: {} - {}", config.start, config.end));
+        }
+        Ok(window)
+    }
+
+    pub fn contains(&self, time: SystemTime) -> bool {
+        self.start <= time && time < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProbeStrategy {
+    /// Probe every interface each cycle
+    All,
+    /// Probe only the active interface; standby interfaces are probed every
+    /// `standby_probe_cycles` cycles or as soon as the active one fails
+    ActiveFirst,
+}
+
+/// How to choose between working interfaces whose speed latencies are
+/// within `tie_break_epsilon_ms` of each other, instead of always taking the
+/// most preferred one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TieBreak {
+    /// Take each of the tied interfaces in turn, one per cycle
+    RoundRobin,
+    /// Pick at random each cycle, the faster ones more often
+    Weighted,
+}
+
+/// How much each measurement counts in an interface's health score (see
+/// [`compute_health_score`](crate::compute_health_score)). Only the ratios
+/// matter.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScoringWeights {
+    pub latency: f64,
+    pub loss: f64,
+    pub link_quality: f64,
+    pub bandwidth: f64,
+}
+
+impl Default for ScoringWeights {
+    fn default() -> Self {
+        Self { latency: 0.4, loss: 0.3, link_quality: 0.2, bandwidth: 0.1 }
+    }
+}
+
+/// Everything a [`FailoverMonitor`](crate::FailoverMonitor) needs to know,
+/// fully resolved: defaults applied and values validated. The binary builds
+/// it from the command line, environment and config file.
+#[derive(Debug, Clone)]
+pub struct FailoverConfig {
+    pub peer_ip: IpAddress,
+    pub interfaces: Vec<String>, // most preferred first, at least two
+    pub test_ips: Vec<String>,
+    pub check_interval: Duration,
+    pub speed_check_interval: Duration,
+    pub speed_threshold: u8,
+    pub tie_break: Option<TieBreak>,
+    pub tie_break_epsilon_ms: f64,
+    pub scoring_weights: ScoringWeights,
+    pub max_acceptable_latency_ms: Option<f64>,
+    pub smart_recovery: bool,
+    pub smart_recovery_success_rate: f64,
+    pub traceroute_on_failure: bool,
+    pub traceroute_max_hops: u8,
+    pub traceroute_min_interval: Duration,
+    pub startup_delay: Duration,
+    pub startup_probes: u8,
+    pub probe_strategy: ProbeStrategy,
+    pub standby_probe_cycles: u32,
+    pub bandwidth_check_enabled: bool,
+    pub min_bandwidth_mbps: Option<f64>,
+    pub probe_gateway_first: bool,
+    pub status_window: u8,
+    pub min_hold_time: Duration,
+    pub consecutive_better_checks: u32,
+    pub route_cache_ttl: Duration,
+    pub interval_jitter_pct: u8,
+    pub spread_probes: bool,
+    pub auto_bring_up_interfaces: bool, // `ip link set up` interfaces found down
+    pub link_flap_cooldown: Duration, // minimum time between bring-ups of one interface
+    pub state_dump_file: Option<PathBuf>,
+    pub state_file: Option<PathBuf>, // active interface kept across restarts
+    pub capture: Option<CaptureSettings>,
+    pub switch_hooks: SwitchHooks,
+    pub send_gratuitous_arp: bool,
+    pub peer_cache_threshold: u32,
+    pub peer_exclusion: Duration,
+    pub route_all_traffic: bool,
+    pub pre_failover_flush: bool,
+    pub reassert_routes: bool,
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    pub post_switch_confirm_target: Option<String>,
+    pub post_switch_mtu_check: Option<u16>,
+    pub connection_drain_wait: Option<Duration>,
+    pub wg_endpoint_check: Option<(SocketAddr, Duration)>, // endpoint, timeout
+    pub route_exclusions: Vec<RouteExclusion>,
+    pub failover_to_no_route: bool,
+    pub probe_src_addrs: HashMap<String, String>,
+    pub max_loss_pct: u8, // packet loss beyond which an interface counts as failed
+    pub interface_max_loss_pct: HashMap<String, u8>, // overrides max_loss_pct for single interfaces
+    pub preferred_interface: Option<String>, // favoured while working; failover still follows `interfaces`
+    pub connectivity_netns: Option<String>,
+    pub speed_netns: Option<String>,
+    pub ping_timeout: u8, // seconds; for probe methods without a probe_timeouts entry
+    pub probe_timeouts: HashMap<ProbeMethod, u8>,
+    pub static_arp: Vec<(String, String)>, // neighbour IP, MAC address
+    pub external_control_file: Option<PathBuf>,
+    pub env_file: Option<PathBuf>, // watched for peer and interface changes
+    pub env_prefix: String,
+    pub wg_config_file: Option<PathBuf>, // watched for peer endpoint changes
+}
+
+// Float settings compare with a small tolerance so a value that round-trips
+// through a reload (e.g. 0.1 parsed twice) still counts as unchanged
+pub const CONFIG_FLOAT_EPSILON: f64 = 1e-9;
+
+pub fn config_floats_eq(a: Option<f64>, b: Option<f64>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => (a - b).abs() < CONFIG_FLOAT_EPSILON,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+impl PartialEq for FailoverConfig {
+    fn eq(&self, other: &Self) -> bool {
+        // Destructured, so a new field cannot be left out by accident
+        let Self { peer_ip, interfaces, test_ips, check_interval, speed_check_interval, speed_threshold,
+            tie_break, tie_break_epsilon_ms, scoring_weights, max_acceptable_latency_ms, smart_recovery, smart_recovery_success_rate, traceroute_on_failure,
+            traceroute_max_hops, traceroute_min_interval, startup_delay, startup_probes, probe_strategy,
+            standby_probe_cycles, bandwidth_check_enabled, min_bandwidth_mbps, probe_gateway_first, status_window,
+            min_hold_time, consecutive_better_checks, route_cache_ttl, interval_jitter_pct, spread_probes,
+            auto_bring_up_interfaces, link_flap_cooldown, state_dump_file, state_file, capture, switch_hooks, send_gratuitous_arp, peer_cache_threshold,
+            peer_exclusion, route_all_traffic, pre_failover_flush, reassert_routes, maintenance_windows,
+            post_switch_confirm_target, post_switch_mtu_check, connection_drain_wait, wg_endpoint_check,
+            route_exclusions, failover_to_no_route, probe_src_addrs, max_loss_pct, interface_max_loss_pct,
+            preferred_interface, connectivity_netns, speed_netns, ping_timeout, probe_timeouts, static_arp, external_control_file,
+            env_file, env_prefix, wg_config_file,
+        } = self;
+        *peer_ip == other.peer_ip
+            && *interfaces == other.interfaces
+            && *test_ips == other.test_ips
+            && *check_interval == other.check_interval
+            && *speed_check_interval == other.speed_check_interval
+            && *speed_threshold == other.speed_threshold
+            && *tie_break == other.tie_break
+            && config_floats_eq(Some(*tie_break_epsilon_ms), Some(other.tie_break_epsilon_ms))
+            && *scoring_weights == other.scoring_weights
+            && config_floats_eq(*max_acceptable_latency_ms, other.max_acceptable_latency_ms)
+            && *smart_recovery == other.smart_recovery
+            && config_floats_eq(Some(*smart_recovery_success_rate), Some(other.smart_recovery_success_rate))
+            && *traceroute_on_failure == other.traceroute_on_failure
+            && *traceroute_max_hops == other.traceroute_max_hops
+            && *traceroute_min_interval == other.traceroute_min_interval
+            && *startup_delay == other.startup_delay
+            && *startup_probes == other.startup_probes
+            && *probe_strategy == other.probe_strategy
+            && *standby_probe_cycles == other.standby_probe_cycles
+            && *bandwidth_check_enabled == other.bandwidth_check_enabled
+            && config_floats_eq(*min_bandwidth_mbps, other.min_bandwidth_mbps)
+            && *probe_gateway_first == other.probe_gateway_first
+            && *status_window == other.status_window
+            && *min_hold_time == other.min_hold_time
+            && *consecutive_better_checks == other.consecutive_better_checks
+            && *route_cache_ttl == other.route_cache_ttl
+            && *interval_jitter_pct == other.interval_jitter_pct
+            && *spread_probes == other.spread_probes
+            && *auto_bring_up_interfaces == other.auto_bring_up_interfaces
+            && *link_flap_cooldown == other.link_flap_cooldown
+            && *state_dump_file == other.state_dump_file
+            && *state_file == other.state_file
+            && *capture == other.capture
+            && *switch_hooks == other.switch_hooks
+            && *send_gratuitous_arp == other.send_gratuitous_arp
+            && *peer_cache_threshold == other.peer_cache_threshold
+            && *peer_exclusion == other.peer_exclusion
+            && *route_all_traffic == other.route_all_traffic
+            && *pre_failover_flush == other.pre_failover_flush
+            && *reassert_routes == other.reassert_routes
+            && *maintenance_windows == other.maintenance_windows
+            && *post_switch_confirm_target == other.post_switch_confirm_target
+            && *post_switch_mtu_check == other.post_switch_mtu_check
+            && *connection_drain_wait == other.connection_drain_wait
+            && *wg_endpoint_check == other.wg_endpoint_check
+            && *route_exclusions == other.route_exclusions
+            && *failover_to_no_route == other.failover_to_no_route
+            && *probe_src_addrs == other.probe_src_addrs
+            && *max_loss_pct == other.max_loss_pct
+            && *interface_max_loss_pct == other.interface_max_loss_pct
+            && *preferred_interface == other.preferred_interface
+            && *connectivity_netns == other.connectivity_netns
+            && *speed_netns == other.speed_netns
+            && *ping_timeout == other.ping_timeout
+            && *probe_timeouts == other.probe_timeouts
+            && *static_arp == other.static_arp
+            && *external_control_file == other.external_control_file
+            && *env_file == other.env_file
+            && *env_prefix == other.env_prefix
+            && *wg_config_file == other.wg_config_file
+    }
+}
+
+impl FailoverConfig {
+    /// Checks the settings the monitor relies on being in range; the builder
+    /// calls it, and anyone filling in the struct by hand should too.
+    pub fn validate(&self) -> Result<()> {
+        if !(self.smart_recovery_success_rate > 0.0 && self.smart_recovery_success_rate <= 1.0) {
+            return Err(anyhow::anyhow!(
+                "smart_recovery_success_rate must be above 0.0 and at most 1.0, got {}", self.smart_recovery_success_rate));
+        }
+        if self.tie_break_epsilon_ms.is_nan() || self.tie_break_epsilon_ms < 0.0 {
+            return Err(anyhow::anyhow!("tie_break_epsilon_ms must not be negative, got {}", self.tie_break_epsilon_ms));
+        }
+        let ScoringWeights { latency, loss, link_quality, bandwidth } = self.scoring_weights;
+        let weights = [latency, loss, link_quality, bandwidth];
+        if weights.iter().any(|w| w.is_nan() || *w < 0.0) || weights.iter().sum::<f64>() <= 0.0 {
+            return Err(anyhow::anyhow!("scoring_weights must not be negative and must not all be zero, got {:?}", self.scoring_weights));
+        }
+        if !(1..=60).contains(&self.ping_timeout) {
+            return Err(anyhow::anyhow!("ping_timeout must be between 1 and 60 seconds, got {}", self.ping_timeout));
+        }
+        if let Some(pct) = std::iter::once(&self.max_loss_pct).chain(self.interface_max_loss_pct.values()).find(|pct| **pct > 100) {
+            return Err(anyhow::anyhow!("max_loss_pct must be between 0 and 100, got {}", pct));
+        }
+        Ok(())
+    }
+}
+
+/// Builds a [`FailoverConfig`] for library users, with the daemon's defaults
+/// for everything not set. Setters win over `<PREFIX>_*` environment values.
+#[derive(Debug, Default)]
+pub struct FailoverConfigBuilder {
+    peer_ip: Option<String>,
+    interfaces: Option<Vec<String>>,
+    test_ips: Option<Vec<String>>,
+    check_interval: Option<Duration>,
+    speed_check_interval: Option<Duration>,
+    speed_threshold: Option<u8>,
+    route_all_traffic: Option<bool>,
+    smart_recovery_success_rate: Option<f64>,
+    preferred_interface: Option<String>,
+    state_file: Option<PathBuf>,
+    max_interfaces: Option<usize>,
+    env: EnvOverrides,
+    env_prefix: Option<String>,
+}
+
+impl FailoverConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from the `<PREFIX>_*` environment variables, so several monitors
+    /// in one process can each be configured under their own prefix.
+    pub fn from_env_prefix(prefix: &str) -> Result<Self> {
+        Self::from_env_vars(prefix, std::env::vars())
+    }
+
+    /// Like [`from_env_prefix`](Self::from_env_prefix), reading from the
+    /// given `(key, value)` pairs instead of the process environment.
+    pub fn from_env_vars<K, V>(prefix: &str, vars: impl IntoIterator<Item = (K, V)>) -> Result<Self>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        Ok(Self { env: EnvOverrides::from_vars(prefix, vars)?, env_prefix: Some(prefix.to_string()), ..Self::default() })
+    }
+
+    pub fn peer_ip(mut self, peer_ip: impl Into<String>) -> Self {
+        self.peer_ip = Some(peer_ip.into());
+        self
+    }
+
+    /// Most preferred first; replaces any primary/secondary from the environment.
+    pub fn interfaces<S: Into<String>>(mut self, interfaces: impl IntoIterator<Item = S>) -> Self {
+        self.interfaces = Some(interfaces.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn test_ips<S: Into<String>>(mut self, test_ips: impl IntoIterator<Item = S>) -> Self {
+        self.test_ips = Some(test_ips.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn check_interval(mut self, interval: Duration) -> Self {
+        self.check_interval = Some(interval);
+        self
+    }
+
+    pub fn speed_check_interval(mut self, interval: Duration) -> Self {
+        self.speed_check_interval = Some(interval);
+        self
+    }
+
+    pub fn speed_threshold(mut self, pct: u8) -> Self {
+        self.speed_threshold = Some(pct);
+        self
+    }
+
+    pub fn route_all_traffic(mut self, enabled: bool) -> Self {
+        self.route_all_traffic = Some(enabled);
+        self
+    }
+
+    /// Turns on smart recovery: failback waits until this share of the checks
+    /// in the verification window pass. Must be above 0.0 and at most 1.0.
+    pub fn smart_recovery_success_rate(mut self, rate: f64) -> Self {
+        self.smart_recovery_success_rate = Some(rate);
+        self
+    }
+
+    /// Favoured while it works, even over interfaces listed before it.
+    pub fn preferred_interface(mut self, iface: impl Into<String>) -> Self {
+        self.preferred_interface = Some(iface.into());
+        self
+    }
+
+    /// Persists the active interface across restarts; off unless set.
+    pub fn state_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// Defaults to [`DEFAULT_MAX_INTERFACES`].
+    pub fn max_interfaces(mut self, max: usize) -> Self {
+        self.max_interfaces = Some(max);
+        self
+    }
+
+    pub fn build(self) -> Result<FailoverConfig> {
+        let env = self.env;
+        let prefix = self.env_prefix.unwrap_or_else(|| "WG_FAILOVER".to_string());
+        let peer_ip = self.peer_ip.or_else(|| env.peer_ip.clone()).context("Peer IP is required")?;
+        let peer_ip = IpAddress::resolve(&peer_ip).with_context(|| format!("Invalid peer {:?}", peer_ip))?;
+        let interfaces = match self.interfaces {
+            Some(interfaces) => interfaces,
+            None => {
+                let mut slots = env.interface_slots(&[]);
+                slots.resize(slots.len().max(2), None);
+                // Only the first two can be missing
+                slots.into_iter().zip(["primary", "secondary"].into_iter().chain(std::iter::repeat("")))
+                    .map(|(iface, role)| iface.with_context(|| format!(
+                        "{} interface is required ({}_{} or {}_INTERFACES)", role, prefix, role.to_uppercase(), prefix)))
+                    .collect::<Result<_>>()?
+            }
+        };
+        if interfaces.len() < 2 {
+            return Err(anyhow::anyhow!("At least two interfaces are required, got {}", interfaces.len()));
+        }
+        check_interface_count(interfaces.len(), self.max_interfaces.unwrap_or(DEFAULT_MAX_INTERFACES))?;
+        if let Some(preferred) = self.preferred_interface.as_ref().filter(|p| !interfaces.contains(p)) {
+            return Err(anyhow::anyhow!("preferred_interface {} is not one of the interfaces {:?}", preferred, interfaces));
+        }
+        let test_ips = self.test_ips
+            .or_else(|| env.test_ips.as_deref().map(parse_list))
+            .unwrap_or_else(|| DEFAULT_TEST_IPS.iter().map(|ip| ip.to_string()).chain([peer_ip.to_string()]).collect());
+
+        let config = FailoverConfig {
+            test_ips,
+            interfaces,
+            check_interval: self.check_interval.or(env.interval.map(Duration::from_secs)).unwrap_or(Duration::from_secs(30)),
+            speed_check_interval: self.speed_check_interval
+                .or(env.speedtest_interval.map(Duration::from_secs))
+                .unwrap_or(Duration::from_secs(300)),
+            speed_threshold: self.speed_threshold.or(env.speed_threshold).unwrap_or(20),
+            tie_break: None,
+            tie_break_epsilon_ms: DEFAULT_TIE_BREAK_EPSILON_MS,
+            scoring_weights: ScoringWeights::default(),
+            max_acceptable_latency_ms: None,
+            smart_recovery: self.smart_recovery_success_rate.is_some(),
+            smart_recovery_success_rate: self.smart_recovery_success_rate.unwrap_or(0.9),
+            traceroute_on_failure: false,
+            traceroute_max_hops: 15,
+            traceroute_min_interval: Duration::from_secs(600),
+            startup_delay: Duration::ZERO,
+            startup_probes: 3,
+            probe_strategy: ProbeStrategy::All,
+            standby_probe_cycles: 10,
+            bandwidth_check_enabled: false,
+            min_bandwidth_mbps: None,
+            probe_gateway_first: true,
+            status_window: 1,
+            min_hold_time: Duration::ZERO,
+            consecutive_better_checks: 1,
+            route_cache_ttl: Duration::from_secs(5),
+            interval_jitter_pct: 0,
+            spread_probes: false,
+            auto_bring_up_interfaces: false,
+            link_flap_cooldown: Duration::from_secs(DEFAULT_LINK_FLAP_COOLDOWN_SECS),
+            state_dump_file: None,
+            state_file: self.state_file,
+            capture: None,
+            switch_hooks: SwitchHooks {
+                pre_hook_aborts: true,
+                timeout: Duration::from_secs(crate::hooks::DEFAULT_HOOK_TIMEOUT_SECS),
+                ..SwitchHooks::default()
+            },
+            send_gratuitous_arp: false,
+            peer_cache_threshold: 5,
+            peer_exclusion: Duration::from_secs(60),
+            route_all_traffic: self.route_all_traffic.or(env.route_all_traffic).unwrap_or(false),
+            pre_failover_flush: true,
+            reassert_routes: true,
+            maintenance_windows: Vec::new(),
+            post_switch_confirm_target: None,
+            post_switch_mtu_check: None,
+            connection_drain_wait: None,
+            wg_endpoint_check: None,
+            route_exclusions: Vec::new(),
+            failover_to_no_route: false,
+            probe_src_addrs: HashMap::new(),
+            max_loss_pct: DEFAULT_MAX_LOSS_PCT,
+            interface_max_loss_pct: HashMap::new(),
+            preferred_interface: self.preferred_interface,
+            connectivity_netns: None,
+            speed_netns: None,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            probe_timeouts: HashMap::new(),
+            static_arp: Vec::new(),
+            external_control_file: None,
+            env_file: None,
+            env_prefix: prefix,
+            wg_config_file: None,
+            peer_ip,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+pub const DEFAULT_PING_TIMEOUT: u8 = 2;
+
+pub const DEFAULT_LINK_FLAP_COOLDOWN_SECS: u64 = 30;
+
+pub const DEFAULT_TIE_BREAK_EPSILON_MS: f64 = 1.0;
+
+// More than this share of probe packets lost makes an interface count as
+// failed, even if some of them got through
+pub const DEFAULT_MAX_LOSS_PCT: u8 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProbeMethod {
+    Ping,
+}
+
+impl std::str::FromStr for ProbeMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ping" => Ok(ProbeMethod::Ping),
+            other => Err(anyhow::anyhow!("Unknown probe method '{}' (supported: ping)", other)),
+        }
+    }
+}
+
+pub fn parse_probe_timeouts(raw: &HashMap<String, u8>) -> Result<HashMap<ProbeMethod, u8>> {
+    let mut timeouts = HashMap::new();
+    for (name, timeout) in raw {
+        // Lets one config carry timeouts for probe methods a newer build
+        // supports without this one refusing to start
+        let method: ProbeMethod = match name.parse() {
+            Ok(method) => method,
+            Err(e) => {
+                warn!("Ignoring probe_timeouts.{}: {}", name, e);
+                continue;
+            }
+        };
+        if !(1..=60).contains(timeout) {
+            return Err(anyhow::anyhow!("probe_timeouts.{} must be between 1 and 60 seconds, got {}", name, timeout));
+        }
+        timeouts.insert(method, *timeout);
+    }
+    Ok(timeouts)
+}
+
+// Every cycle probes each test IP on each interface, so the cost grows with
+// interfaces x test IPs; this keeps a templated config from ballooning it
+pub const DEFAULT_MAX_INTERFACES: usize = 8;
+
+pub fn check_interface_count(count: usize, max_interfaces: usize) -> Result<()> {
+    if count > max_interfaces {
+        return Err(anyhow::anyhow!(
+            "{} interfaces configured but max_interfaces is {}; raise max_interfaces ([monitoring] in the config file) to monitor more",
+            count, max_interfaces
+        ));
+    }
+    Ok(())
+}
+
+pub const DEFAULT_STATE_FILE: &str = "/var/lib/wg-failover/state.json";
+
+pub const DEFAULT_TEST_IPS: [&str; 3] = ["8.8.8.8", "1.1.1.1", "208.67.222.222"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureSettings {
+    pub interface: Option<String>,
+    pub dir: PathBuf,
+    pub max_file_size_mb: u64,
+    pub retention_count: usize,
+}
+
+/// Returns the `Endpoint` of the first `[Peer]` in a wg-quick style config.
+pub fn parse_wg_endpoint(content: &str) -> Option<String> {
+    crate::wireguard::parse_wg_config(content).ok()?.endpoint().map(str::to_string)
+}
+
+/// Resolves a WireGuard endpoint (`1.2.3.4:51820`, `[2001:db8::1]:51820` or
+/// `vpn.example.com:51820`) to an address.
+pub fn resolve_wg_endpoint(endpoint: &str) -> Result<SocketAddr> {
+    use std::net::ToSocketAddrs;
+    if let Ok(addr) = endpoint.parse() {
+        return Ok(addr);
+    }
+    endpoint.to_socket_addrs()
+        .with_context(|| format!("Failed to resolve WireGuard endpoint {:?}", endpoint))?
+        .next()
+        .with_context(|| format!("WireGuard endpoint {:?} resolved to no addresses", endpoint))
+}
+
+pub fn peer_from_wg_config(path: &Path) -> Result<SocketAddr> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read WireGuard config {:?}", path))?;
+    let endpoint = parse_wg_endpoint(&content)
+        .with_context(|| format!("No [Peer] Endpoint in {:?}", path))?;
+    resolve_wg_endpoint(&endpoint)
+}
+
+// Adds or removes the peer from the connectivity test IPs as configured by
+// include_peer_in_quorum; without the setting the list is used as given.
+// The speed comparison always measures the peer directly, so it is unaffected.
+pub fn apply_peer_quorum_setting(mut test_ips: Vec<String>, peer_ip: &str, include: Option<bool>) -> Result<Vec<String>> {
+    match include {
+        Some(true) if !test_ips.iter().any(|ip| ip == peer_ip) => test_ips.push(peer_ip.to_string()),
+        Some(false) => {
+            test_ips.retain(|ip| ip != peer_ip);
+            if test_ips.is_empty() {
+                return Err(anyhow::anyhow!("include_peer_in_quorum = false leaves no test IPs to probe"));
+            }
+        }
+        _ => {}
+    }
+    Ok(test_ips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn env_overrides_with_different_prefixes_are_independent() {
+        let env = vars(&[
+            ("WG_VPN0_PEER_IP", "203.0.113.1"),
+            ("WG_VPN0_PRIMARY", "eth0"),
+            ("WG_VPN0_INTERVAL", "15"),
+            ("WG_VPN1_PEER_IP", "198.51.100.7"),
+            ("WG_VPN1_SECONDARY", "wlan0"),
+        ]);
+
+        let vpn0 = EnvOverrides::from_vars("WG_VPN0", env.clone()).unwrap();
+        let vpn1 = EnvOverrides::from_vars("WG_VPN1", env).unwrap();
+
+        assert_eq!(vpn0.peer_ip.as_deref(), Some("203.0.113.1"));
+        assert_eq!(vpn0.primary.as_deref(), Some("eth0"));
+        assert_eq!(vpn0.secondary, None);
+        assert_eq!(vpn0.interval, Some(15));
+
+        assert_eq!(vpn1.peer_ip.as_deref(), Some("198.51.100.7"));
+        assert_eq!(vpn1.primary, None);
+        assert_eq!(vpn1.secondary.as_deref(), Some("wlan0"));
+        assert_eq!(vpn1.interval, None);
+
+        assert!(EnvOverrides::from_vars("WG_VPN0", vars(&[("WG_VPN0_INTERVAL", "soon")])).is_err());
+    }
+
+    #[test]
+    fn builders_with_different_prefixes_build_independent_configs() {
+        let env = vars(&[
+            ("WG_VPN0_PEER_IP", "203.0.113.1"),
+            ("WG_VPN0_PRIMARY", "eth0"),
+            ("WG_VPN0_SECONDARY", "wlan0"),
+            ("WG_VPN0_INTERVAL", "15"),
+            ("WG_VPN1_PEER_IP", "[2001:db8::7]"),
+            ("WG_VPN1_INTERFACES", "eth1, wwan0, wlan1"),
+            ("WG_VPN1_TEST_IPS", "9.9.9.9, 1.0.0.1"),
+        ]);
+
+        let vpn0 = FailoverConfigBuilder::from_env_vars("WG_VPN0", env.clone()).unwrap().build().unwrap();
+        let vpn1 = FailoverConfigBuilder::from_env_vars("WG_VPN1", env.clone()).unwrap().build().unwrap();
+
+        assert_eq!(vpn0.peer_ip.to_string(), "203.0.113.1");
+        assert_eq!(vpn0.interfaces, ["eth0", "wlan0"]);
+        assert_eq!(vpn0.check_interval, Duration::from_secs(15));
+        assert_eq!(vpn0.test_ips, ["8.8.8.8", "1.1.1.1", "208.67.222.222", "203.0.113.1"]);
+        assert_eq!(vpn0.env_prefix, "WG_VPN0");
+        // Two monitors must not share the daemon's state file by default
+        assert_eq!(vpn0.state_file, None);
+
+        assert_eq!(vpn1.peer_ip.to_string(), "2001:db8::7");
+        assert_eq!(vpn1.interfaces, ["eth1", "wwan0", "wlan1"]);
+        assert_eq!(vpn1.check_interval, Duration::from_secs(30));
+        assert_eq!(vpn1.test_ips, ["9.9.9.9", "1.0.0.1"]);
+        assert_eq!(vpn1.env_prefix, "WG_VPN1");
+
+        // Setters win over the environment
+        let vpn0 = FailoverConfigBuilder::from_env_vars("WG_VPN0", env).unwrap()
+            .peer_ip("192.0.2.1")
+            .interfaces(["eth0", "eth1", "wlan0"])
+            .build()
+            .unwrap();
+        assert_eq!(vpn0.peer_ip.to_string(), "192.0.2.1");
+        assert_eq!(vpn0.interfaces, ["eth0", "eth1", "wlan0"]);
+        assert_eq!(vpn0.check_interval, Duration::from_secs(15));
+
+        assert!(FailoverConfigBuilder::new().peer_ip("192.0.2.1").interfaces(["eth0"]).build().is_err());
+        assert!(FailoverConfigBuilder::new().interfaces(["eth0", "wlan0"]).build().is_err());
+    }
+
+    #[test]
+    fn smart_recovery_success_rate_must_be_a_fraction_above_zero() {
+        let build = |rate| FailoverConfigBuilder::new().peer_ip("192.0.2.1").interfaces(["eth0", "wlan0"])
+            .smart_recovery_success_rate(rate).build();
+        let config = build(1.0).unwrap();
+        assert!(config.smart_recovery);
+        for rate in [0.0, -0.5, 1.01, f64::NAN] {
+            assert!(build(rate).is_err(), "{} accepted", rate);
+        }
+    }
+
+    #[test]
+    fn parse_env_file_handles_comments_quotes_and_export() {
+        let vars = parse_env_file("# injected by entrypoint\nexport WG_FAILOVER_PRIMARY=eth1\nWG_FAILOVER_PEER_IP=\"203.0.113.9\"\n\nWG_FAILOVER_SECONDARY='wwan0'\n");
+        assert_eq!(vars.get("WG_FAILOVER_PRIMARY").map(String::as_str), Some("eth1"));
+        assert_eq!(vars.get("WG_FAILOVER_PEER_IP").map(String::as_str), Some("203.0.113.9"));
+        assert_eq!(vars.get("WG_FAILOVER_SECONDARY").map(String::as_str), Some("wwan0"));
+        assert_eq!(vars.len(), 3);
+    }
+
+    #[test]
+    fn peer_quorum_setting_adds_or_removes_the_peer() {
+        let ips = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let base = ips(&["1.1.1.1", "203.0.113.1"]);
+
+        assert_eq!(apply_peer_quorum_setting(base.clone(), "203.0.113.1", None).unwrap(), base);
+        assert_eq!(apply_peer_quorum_setting(base.clone(), "203.0.113.1", Some(true)).unwrap(), base);
+        assert_eq!(apply_peer_quorum_setting(base.clone(), "203.0.113.1", Some(false)).unwrap(), ips(&["1.1.1.1"]));
+        assert_eq!(
+            apply_peer_quorum_setting(ips(&["1.1.1.1"]), "203.0.113.1", Some(true)).unwrap(),
+            base
+        );
+        assert!(apply_peer_quorum_setting(ips(&["203.0.113.1"]), "203.0.113.1", Some(false)).is_err());
+    }
+
+    #[test]
+    fn parse_wg_endpoint_reads_first_peer() {
+        let config = "\
+[Interface]
+PrivateKey = aGVsbG8=
+Address = 10.0.0.2/32
+# Endpoint = 192.0.2.99:51820 in a comment is ignored
+
+[Peer]
+PublicKey = d29ybGQ=
+AllowedIPs = 0.0.0.0/0
+endpoint = 203.0.113.7:51820 # trailing comment
+
+[Peer]
+Endpoint = 198.51.100.1:51820
+";
+        assert_eq!(parse_wg_endpoint(config).as_deref(), Some("203.0.113.7:51820"));
+        assert_eq!(parse_wg_endpoint("[Interface]\nListenPort = 51820\n"), None);
+        assert_eq!(resolve_wg_endpoint("[2001:db8::1]:51820").unwrap().ip().to_string(), "2001:db8::1");
+    }
+
+    #[test]
+    fn interface_count_is_capped() {
+        assert!(check_interface_count(2, DEFAULT_MAX_INTERFACES).is_ok());
+        assert!(check_interface_count(8, 8).is_ok());
+        let err = check_interface_count(50, 8).unwrap_err().to_string();
+        assert!(err.contains("50 interfaces") && err.contains("max_interfaces is 8"));
+
+        let nine: Vec<String> = (0..9).map(|i| format!("eth{}", i)).collect();
+        let builder = || FailoverConfigBuilder::new().peer_ip("203.0.113.1").interfaces(nine.clone());
+        assert!(builder().build().is_err());
+        assert_eq!(builder().max_interfaces(9).build().unwrap().interfaces.len(), 9);
+    }
+
+    #[test]
+    fn unknown_probe_methods_are_ignored() {
+        let raw = HashMap::from([("ping".to_string(), 5), ("tcp".to_string(), 3), ("dns".to_string(), 1)]);
+        assert_eq!(parse_probe_timeouts(&raw).unwrap(), HashMap::from([(ProbeMethod::Ping, 5)]));
+        assert!(parse_probe_timeouts(&HashMap::from([("ping".to_string(), 0)])).is_err());
+    }
+
+    #[test]
+    fn route_exclusion_validates_prefixes() {
+        let exclusion = |prefix: &str| RouteExclusion { prefix: prefix.to_string(), interface: None, gateway: None };
+        assert!(exclusion("192.168.1.0/24").validate().is_ok());
+        assert!(exclusion("203.0.113.5").validate().is_ok());
+        assert!(exclusion("fd00::/8").validate().is_ok());
+        assert!(exclusion("192.168.1.0/33").validate().is_err());
+        assert!(exclusion("lan").validate().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Errors from the network functions that callers may want to tell apart,
+//! such as a missing ping binary versus a peer that simply did not answer.
+
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum FailoverError {
+    /// The program ran but failed without a usable answer
+    CommandExecution { command: String, message: String },
+    /// The program could not be started, or talking to it failed
+    Io { command: String, source: io::Error },
+    /// The program needs root or a capability (usually CAP_NET_RAW) we lack
+    InsufficientPermissions(String),
+    /// The interface does not exist in this network namespace
+    InterfaceNotFound(String),
+    /// The operation is not available on this platform
+    UnsupportedOs(String),
+    /// A configured address or prefix does not parse
+    InvalidAddress(String),
+    /// A config file (e.g. a wg-quick config) does not parse
+    InvalidConfig(String),
+}
+
+/// How much attention an error needs, from "try again" to "page someone".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorSeverity {
+    /// Retrying straight away will most likely work
+    Transient,
+    /// Failed this time; may clear up by the next cycle
+    Recoverable,
+    /// Will keep failing until the system or the config is changed
+    Permanent,
+    /// The daemon cannot do its job at all
+    Critical,
+}
+
+impl fmt::Display for ErrorSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorSeverity::Transient => "transient",
+            ErrorSeverity::Recoverable => "recoverable",
+            ErrorSeverity::Permanent => "permanent",
+            ErrorSeverity::Critical => "critical",
+        })
+    }
+}
+
+pub type FailoverResult<T> = Result<T, FailoverError>;
+
+impl FailoverError {
+    pub(crate) fn command(command: &str, message: impl fmt::Display) -> Self {
+        FailoverError::CommandExecution { command: command.to_string(), message: message.to_string() }
+    }
+
+    pub(crate) fn io(command: &str, source: io::Error) -> Self {
+        FailoverError::Io { command: command.to_string(), source }
+    }
+
+    pub fn severity(&self) -> ErrorSeverity {
+        match self {
+            FailoverError::Io { source, .. } => match source.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::TimedOut => ErrorSeverity::Transient,
+                // The program is not installed, or not executable by us
+                io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => ErrorSeverity::Permanent,
+                _ => ErrorSeverity::Recoverable,
+            },
+            FailoverError::CommandExecution { .. } => ErrorSeverity::Recoverable,
+            FailoverError::InterfaceNotFound(_) | FailoverError::UnsupportedOs(_) | FailoverError::InvalidAddress(_)
+            | FailoverError::InvalidConfig(_) => ErrorSeverity::Permanent,
+            FailoverError::InsufficientPermissions(_) => ErrorSeverity::Critical,
+        }
+    }
+
+    /// Whether the same call may succeed later without anyone intervening.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self.severity(), ErrorSeverity::Transient | ErrorSeverity::Recoverable)
+    }
+}
+
+impl fmt::Display for FailoverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailoverError::CommandExecution { command, message } => write!(f, "{} failed: {}", command, message),
+            FailoverError::Io { command, source } => write!(f, "{} failed: {}", command, source),
+            FailoverError::InsufficientPermissions(message) => write!(f, "insufficient permissions: {}", message),
+            FailoverError::InterfaceNotFound(iface) => write!(f, "interface {} not found", iface),
+            FailoverError::UnsupportedOs(what) => write!(f, "{} is not supported on this platform", what),
+            FailoverError::InvalidAddress(message) => write!(f, "invalid address: {}", message),
+            FailoverError::InvalidConfig(message) => write!(f, "invalid config: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for FailoverError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FailoverError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Forwards log records to a Graylog GELF UDP input, in addition to the
+//! normal env_logger output.
+
+use anyhow::{Context, Result};
+use log::{Level, Log, Metadata, Record};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::OnceLock;
+
+use crate::report::json_escape;
+
+struct Sink {
+    socket: UdpSocket,
+    endpoint: SocketAddr,
+    host: String,
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+struct GelfLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for GelfLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        self.inner.log(record);
+        if let Some(sink) = SINK.get() {
+            // Best effort: a lost log datagram must never disturb failover
+            let _ = sink.socket.send_to(encode(record, &sink.host).as_bytes(), sink.endpoint);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+pub fn init_logger() {
+    let inner = env_logger::Builder::from_default_env().build();
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(GelfLogger { inner })).expect("logger already initialized");
+}
+
+pub fn set_endpoint(endpoint: &str) -> Result<()> {
+    let endpoint = endpoint.to_socket_addrs()
+        .with_context(|| format!("Invalid gelf_endpoint {:?}, expected host:port", endpoint))?
+        .next()
+        .with_context(|| format!("gelf_endpoint {:?} did not resolve", endpoint))?;
+    let socket = UdpSocket::bind(if endpoint.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })
+        .context("Failed to create GELF socket")?;
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .unwrap_or_else(|_| "wg-failover".to_string());
+    let _ = SINK.set(Sink { socket, endpoint, host });
+    Ok(())
+}
+
+// GELF 1.1 payload; level uses syslog severities
+fn encode(record: &Record, host: &str) -> String {
+    let level = match record.level() {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    let timestamp = chrono::Utc::now().timestamp_millis() as f64 / 1000.0;
+    format!(
+        r#"{{"version":"1.1","host":"{}","short_message":"{}","timestamp":{:.3},"level":{},"_target":"{}","_app":"wg-failover"}}"#,
+        json_escape(host),
+        json_escape(&record.args().to_string()),
+        timestamp,
+        level,
+        json_escape(record.target()),
+    )
+}
@@ -0,0 +1,387 @@
+//! The on_switch command and webhook run when the active interface changes.
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::ErrorSeverity;
+use crate::network::find_in_path;
+use crate::report::{json_object, json_opt, json_str};
+
+pub const DEFAULT_HOOK_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookTiming {
+    /// Before the route change; a failing hook can abort the switch
+    Pre,
+    /// After the active interface has changed
+    #[default]
+    Post,
+    /// Both before and after (WG_HOOK_PHASE tells them apart)
+    Both,
+}
+
+/// What to run when the active interface changes.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SwitchHooks {
+    pub command: Option<String>, // passed to `sh -c`
+    pub webhook: Option<WebhookUrl>,
+    pub timing: HookTiming,
+    pub pre_hook_aborts: bool,
+    pub timeout: Duration,
+}
+
+/// A change of the active interface, as handed to the hooks. `None` means
+/// no interface: before the first route, or after the route was removed.
+#[derive(Debug, Clone)]
+pub struct SwitchEvent {
+    pub old_iface: Option<String>,
+    pub new_iface: Option<String>,
+    pub reason: String,
+    pub peer: String,
+}
+
+impl SwitchEvent {
+    pub fn env(&self, phase: &str) -> [(&'static str, String); 5] {
+        [
+            ("WG_OLD_IFACE", self.old_iface.clone().unwrap_or_default()),
+            ("WG_NEW_IFACE", self.new_iface.clone().unwrap_or_default()),
+            ("WG_REASON", self.reason.clone()),
+            ("WG_PEER", self.peer.clone()),
+            ("WG_HOOK_PHASE", phase.to_string()),
+        ]
+    }
+
+    pub fn to_json(&self) -> String {
+        json_object(&[
+            ("old_iface", json_opt(self.old_iface.as_deref(), json_str)),
+            ("new_iface", json_opt(self.new_iface.as_deref(), json_str)),
+            ("reason", json_str(&self.reason)),
+            ("peer", json_str(&self.peer)),
+        ])
+    }
+}
+
+/// A probe error that will not go away by itself, as sent to the webhook.
+/// Receivers tell it from a [`SwitchEvent`] by its `alert` key.
+#[derive(Debug, Clone)]
+pub struct ErrorAlert {
+    pub iface: String,
+    pub severity: ErrorSeverity,
+    pub message: String,
+    pub peer: String,
+}
+
+impl ErrorAlert {
+    pub fn to_json(&self) -> String {
+        json_object(&[
+            ("alert", json_str(&self.message)),
+            ("severity", json_str(&self.severity.to_string())),
+            ("iface", json_str(&self.iface)),
+            ("peer", json_str(&self.peer)),
+        ])
+    }
+}
+
+/// A more preferred interface that answers again but is not failed back to
+/// yet, as sent to the webhook. Receivers tell it from a [`SwitchEvent`] by
+/// its `recovering` key, e.g. to show the interface as yellow or to warm up
+/// connections before the failback.
+#[derive(Debug, Clone)]
+pub struct RecoveryNotice {
+    pub iface: String,
+    pub active_iface: Option<String>,
+    pub reason: String,
+    pub peer: String,
+}
+
+impl RecoveryNotice {
+    pub fn to_json(&self) -> String {
+        json_object(&[
+            ("recovering", json_str(&self.iface)),
+            ("active_iface", json_opt(self.active_iface.as_deref(), json_str)),
+            ("reason", json_str(&self.reason)),
+            ("peer", json_str(&self.peer)),
+        ])
+    }
+}
+
+impl SwitchHooks {
+    /// Runs the pre-switch hook, if configured, and returns whether the switch
+    /// may go ahead. Blocks for at most the hook timeout.
+    pub fn run_pre(&self, event: &SwitchEvent) -> bool {
+        let Some(command) = self.command.as_ref().filter(|_| self.timing != HookTiming::Post) else {
+            return true;
+        };
+        match run_switch_command(command, event, "pre", self.timeout) {
+            Ok(()) => true,
+            Err(e) if self.pre_hook_aborts => {
+                warn!("Pre-switch hook failed: {:#}, not switching to {}", e, event.new_iface.as_deref().unwrap_or("?"));
+                false
+            }
+            Err(e) => {
+                warn!("Pre-switch hook failed: {:#}, switching anyway", e);
+                true
+            }
+        }
+    }
+
+    /// Fires the post-switch hook and the webhook in the background, so a slow
+    /// receiver never holds up the main loop.
+    pub fn notify(&self, event: &SwitchEvent) {
+        if let Some(command) = self.command.clone().filter(|_| self.timing != HookTiming::Pre) {
+            let (event, timeout) = (event.clone(), self.timeout);
+            thread::spawn(move || {
+                if let Err(e) = run_switch_command(&command, &event, "post", timeout) {
+                    warn!("on_switch hook failed: {:#}", e);
+                }
+            });
+        }
+        if let Some(url) = self.webhook.clone() {
+            let (event, timeout) = (event.clone(), self.timeout);
+            thread::spawn(move || {
+                if let Err(e) = post_webhook(&url, &event.to_json(), timeout) {
+                    warn!("on_switch webhook to {} failed: {:#}", url.host_header(), e);
+                }
+            });
+        }
+    }
+
+    /// Posts the alert to the webhook in the background, if one is configured.
+    pub fn alert(&self, alert: &ErrorAlert) {
+        self.post_in_background(alert.to_json(), "Alert");
+    }
+
+    /// Posts the notice to the webhook in the background, if one is configured.
+    pub fn notify_recovering(&self, notice: &RecoveryNotice) {
+        self.post_in_background(notice.to_json(), "Recovery");
+    }
+
+    fn post_in_background(&self, body: String, what: &'static str) {
+        if let Some(url) = self.webhook.clone() {
+            let timeout = self.timeout;
+            thread::spawn(move || {
+                if let Err(e) = post_webhook(&url, &body, timeout) {
+                    warn!("{} webhook to {} failed: {:#}", what, url.host_header(), e);
+                }
+            });
+        }
+    }
+}
+
+/// Runs an on_switch command with the event in its environment. The command
+/// gets its own process group, which is killed if it outlives `timeout`.
+fn run_switch_command(command: &str, event: &SwitchEvent, phase: &str, timeout: Duration) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+    // Command: sh -c <on_switch>
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .envs(event.env(phase))
+        .stdin(Stdio::null())
+        .process_group(0)
+        .spawn()
+        .with_context(|| format!("Failed to start {:?}", command))?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            }
+            return Err(anyhow::anyhow!("{:?} exited with {}", command, status));
+        }
+        if Instant::now() >= deadline {
+            // SAFETY: kill(2) on the process group we just created
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            let _ = child.wait();
+            return Err(anyhow::anyhow!("{:?} still running after {}s, killed", command, timeout.as_secs_f64()));
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Checks that the program an on_switch command starts exists and is
+/// executable, so a typo surfaces at startup instead of at the next failover.
+pub fn validate_hook_command(command: &str) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let program = command.split_whitespace().next().context("on_switch is empty")?;
+    let path = if program.contains('/') {
+        PathBuf::from(program)
+    } else {
+        find_in_path(program).with_context(|| format!("on_switch program {:?} not found in PATH", program))?
+    };
+    let metadata = std::fs::metadata(&path).with_context(|| format!("on_switch program {:?} does not exist", path))?;
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        return Err(anyhow::anyhow!("on_switch program {:?} is not an executable file", path));
+    }
+    Ok(())
+}
+
+/// Target of on_switch_webhook. Only plain http:// is supported; put a local
+/// relay in front for HTTPS endpoints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl WebhookUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        // The webhook is a plain-socket HTTP/1.1 POST; there is no TLS client
+        if url.starts_with("https://") {
+            return Err(anyhow::anyhow!(
+                "on_switch_webhook {:?}: https:// is not supported, use an http:// URL (e.g. a local relay) or post from an on_switch command",
+                url));
+        }
+        let rest = url.strip_prefix("http://")
+            .with_context(|| format!("on_switch_webhook {:?} must start with http://", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, after) = bracketed.split_once(']')
+                    .with_context(|| format!("on_switch_webhook {:?} has an unclosed [", url))?;
+                match after {
+                    "" => (host, None),
+                    _ => (host, Some(after.strip_prefix(':')
+                        .with_context(|| format!("Invalid host in on_switch_webhook {:?}", url))?)),
+                }
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(anyhow::anyhow!("on_switch_webhook {:?} has no host", url));
+        }
+        let port = port
+            .map(|p| p.parse::<u16>().with_context(|| format!("Invalid port in on_switch_webhook {:?}", url)))
+            .transpose()?
+            .unwrap_or(80);
+        Ok(WebhookUrl { host: host.to_string(), port, path: path.to_string() })
+    }
+
+    pub fn host_header(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// POSTs the JSON body (HTTP/1.1, Connection: close) and expects a 2xx
+/// status. Connect, write and read each give up after `timeout`.
+fn post_webhook(url: &WebhookUrl, body: &str, timeout: Duration) -> Result<()> {
+    use std::io::{Read, Write};
+    use std::net::{TcpStream, ToSocketAddrs};
+    let addr = (url.host.as_str(), url.port).to_socket_addrs()
+        .with_context(|| format!("Could not resolve {}", url.host))?
+        .next()
+        .with_context(|| format!("{} has no addresses", url.host))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: wg-failover/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path, url.host_header(), env!("CARGO_PKG_VERSION"), body.len(), body)?;
+
+    // Only the status line matters
+    let mut head = Vec::new();
+    let mut buf = [0u8; 256];
+    while !head.contains(&b'\n') {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let status_line = String::from_utf8_lossy(&head);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    let mut parts = status_line.split_whitespace();
+    let status = parts.next()
+        .filter(|version| version.starts_with("HTTP/"))
+        .and(parts.next())
+        .and_then(|code| code.parse::<u16>().ok())
+        .with_context(|| format!("Invalid HTTP response {:?}", status_line))?;
+    if !(200..300).contains(&status) {
+        return Err(anyhow::anyhow!("server answered {:?}", status_line));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switch_hook_command_gets_event_env() {
+        let event = SwitchEvent {
+            old_iface: Some("eth0".to_string()),
+            new_iface: Some("wwan0".to_string()),
+            reason: "Primary eth0 failed. Decision: use Secondary.".to_string(),
+            peer: "203.0.113.1".to_string(),
+        };
+        let timeout = Duration::from_secs(5);
+        let check = r#"test "$WG_OLD_IFACE/$WG_NEW_IFACE/$WG_PEER/$WG_HOOK_PHASE" = eth0/wwan0/203.0.113.1/post && test -n "$WG_REASON""#;
+        assert!(run_switch_command(check, &event, "post", timeout).is_ok());
+        assert!(run_switch_command("exit 3", &event, "pre", timeout).unwrap_err().to_string().contains("exit status: 3"));
+
+        let started = Instant::now();
+        assert!(run_switch_command("sleep 10", &event, "pre", Duration::from_millis(200)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        assert!(validate_hook_command("sh -c true").is_ok());
+        assert!(validate_hook_command("/nonexistent/notify-switch --all").is_err());
+        assert!(validate_hook_command("no-such-program-wg-failover").is_err());
+    }
+
+    #[test]
+    fn switch_webhook_posts_json() {
+        use std::io::{Read, Write};
+        assert_eq!(WebhookUrl::parse("http://hooks.example:8080/wg?x=1").unwrap(),
+                   WebhookUrl { host: "hooks.example".to_string(), port: 8080, path: "/wg?x=1".to_string() });
+        assert_eq!(WebhookUrl::parse("http://[::1]").unwrap().host_header(), "[::1]:80");
+        let err = WebhookUrl::parse("https://hooks.example/").unwrap_err().to_string();
+        assert!(err.contains("https:// is not supported"), "{}", err);
+        assert!(WebhookUrl::parse("http://:80/").is_err());
+        assert!(WebhookUrl::parse("http://hooks.example:http/").is_err());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = WebhookUrl::parse(&format!("http://{}/notify", listener.local_addr().unwrap())).unwrap();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for answer in ["HTTP/1.1 204 No Content\r\n\r\n", "HTTP/1.1 500 Internal Server Error\r\n\r\n"] {
+                let (mut conn, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"}") {
+                    let n = conn.read(&mut buf).unwrap();
+                    assert!(n > 0, "connection closed before the body was sent");
+                    request.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                conn.write_all(answer.as_bytes()).unwrap();
+            }
+            requests
+        });
+        let event = SwitchEvent { old_iface: Some("eth0".to_string()), new_iface: None, reason: "All \"down\"".to_string(), peer: "203.0.113.1".to_string() };
+        let timeout = Duration::from_millis(500);
+        assert!(post_webhook(&url, &event.to_json(), timeout).is_ok());
+        assert!(post_webhook(&url, &event.to_json(), timeout).is_err());
+        let request = &server.join().unwrap()[0];
+        assert!(request.starts_with("POST /notify HTTP/1.1\r\n"));
+        assert!(request.ends_with(r#"{"old_iface":"eth0","new_iface":null,"reason":"All \"down\"","peer":"203.0.113.1"}"#));
+
+        let notice = RecoveryNotice { iface: "eth0".to_string(), active_iface: Some("wwan0".to_string()), reason: "recovering (1/3)".to_string(), peer: "203.0.113.1".to_string() };
+        assert_eq!(notice.to_json(), r#"{"recovering":"eth0","active_iface":"wwan0","reason":"recovering (1/3)","peer":"203.0.113.1"}"#);
+    }
+}
@@ -0,0 +1,216 @@
+//! Typed IP addresses, so a malformed peer or prefix is rejected when the
+//! config is loaded instead of when `ip route` or `ping` is first run with it.
+
+use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+
+use crate::error::{FailoverError, FailoverResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Ipv4Address(Ipv4Addr);
+
+/// Accepts the bracketed form (`[2001:db8::1]`) too; displays without brackets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Ipv6Address(Ipv6Addr);
+
+/// Deserializes from a plain string (`"192.0.2.1"`) or, in TOML, an annotated
+/// table that also fixes the family (`{ v6 = "2001:db8::1" }`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "AddressRepr")]
+pub enum IpAddress {
+    V4(Ipv4Address),
+    V6(Ipv6Address),
+}
+
+fn invalid(input: &str, what: &str) -> FailoverError {
+    FailoverError::InvalidAddress(format!("{:?} is not {}", input, what))
+}
+
+impl Ipv4Address {
+    pub fn addr(&self) -> Ipv4Addr {
+        self.0
+    }
+}
+
+impl Ipv6Address {
+    pub fn addr(&self) -> Ipv6Addr {
+        self.0
+    }
+}
+
+impl IpAddress {
+    pub fn addr(&self) -> IpAddr {
+        match self {
+            IpAddress::V4(v4) => IpAddr::V4(v4.0),
+            IpAddress::V6(v6) => IpAddr::V6(v6.0),
+        }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self, IpAddress::V6(_))
+    }
+
+    /// 32 for IPv4, 128 for IPv6.
+    pub fn max_prefix_len(&self) -> u8 {
+        if self.is_ipv6() { 128 } else { 32 }
+    }
+
+    /// Parses `192.168.1.0/24` or `fd00::/8`. A bare address is a host
+    /// prefix, so its length is [`max_prefix_len`](Self::max_prefix_len).
+    pub fn parse_cidr(input: &str) -> FailoverResult<(Self, u8)> {
+        let (addr, len) = match input.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (input, None),
+        };
+        let addr = Self::try_from(addr)?;
+        let len = match len {
+            None => addr.max_prefix_len(),
+            Some(len) => len.parse::<u8>().ok()
+                .filter(|len| *len <= addr.max_prefix_len())
+                .ok_or_else(|| invalid(input, "a prefix with a valid length"))?,
+        };
+        Ok((addr, len))
+    }
+
+    /// Parses `host` as an address, or else looks it up and takes the first
+    /// answer, so a peer may be given by name.
+    pub fn resolve(host: &str) -> FailoverResult<Self> {
+        if let Ok(addr) = Self::try_from(host) {
+            return Ok(addr);
+        }
+        let mut addrs = (host, 0).to_socket_addrs().map_err(|e| FailoverError::io(&format!("resolving {}", host), e))?;
+        addrs.next().map(|a| a.ip().into()).ok_or_else(|| invalid(host, "a resolvable host name"))
+    }
+}
+
+impl TryFrom<&str> for Ipv4Address {
+    type Error = FailoverError;
+
+    fn try_from(input: &str) -> FailoverResult<Self> {
+        input.parse().map(Self).map_err(|_| invalid(input, "an IPv4 address"))
+    }
+}
+
+impl TryFrom<&str> for Ipv6Address {
+    type Error = FailoverError;
+
+    fn try_from(input: &str) -> FailoverResult<Self> {
+        let bare = input.strip_prefix('[').and_then(|a| a.strip_suffix(']')).unwrap_or(input);
+        bare.parse().map(Self).map_err(|_| invalid(input, "an IPv6 address"))
+    }
+}
+
+impl TryFrom<&str> for IpAddress {
+    type Error = FailoverError;
+
+    fn try_from(input: &str) -> FailoverResult<Self> {
+        Ipv4Address::try_from(input).map(IpAddress::V4)
+            .or_else(|_| Ipv6Address::try_from(input).map(IpAddress::V6))
+            .map_err(|_| invalid(input, "an IP address"))
+    }
+}
+
+// For serde's try_from
+impl TryFrom<String> for Ipv4Address {
+    type Error = FailoverError;
+
+    fn try_from(input: String) -> FailoverResult<Self> {
+        Self::try_from(input.as_str())
+    }
+}
+
+impl TryFrom<String> for Ipv6Address {
+    type Error = FailoverError;
+
+    fn try_from(input: String) -> FailoverResult<Self> {
+        Self::try_from(input.as_str())
+    }
+}
+
+impl From<IpAddr> for IpAddress {
+    fn from(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(v4) => IpAddress::V4(Ipv4Address(v4)),
+            IpAddr::V6(v6) => IpAddress::V6(Ipv6Address(v6)),
+        }
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for Ipv6Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for IpAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpAddress::V4(v4) => v4.fmt(f),
+            IpAddress::V6(v6) => v6.fmt(f),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AddressRepr {
+    Plain(String),
+    Annotated(Annotated),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Annotated {
+    V4(Ipv4Address),
+    V6(Ipv6Address),
+}
+
+impl TryFrom<AddressRepr> for IpAddress {
+    type Error = FailoverError;
+
+    fn try_from(repr: AddressRepr) -> FailoverResult<Self> {
+        match repr {
+            AddressRepr::Plain(addr) => Self::try_from(addr.as_str()),
+            AddressRepr::Annotated(Annotated::V4(v4)) => Ok(IpAddress::V4(v4)),
+            AddressRepr::Annotated(Annotated::V6(v6)) => Ok(IpAddress::V6(v6)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_parse_display_and_deserialize() {
+        assert_eq!(Ipv4Address::try_from("192.0.2.1").unwrap().to_string(), "192.0.2.1");
+        assert!(Ipv4Address::try_from("2001:db8::1").is_err());
+        assert_eq!(Ipv6Address::try_from("[2001:db8::1]").unwrap().to_string(), "2001:db8::1");
+        assert!(IpAddress::try_from("192.0.2.300").is_err());
+        assert!(IpAddress::try_from("2001:db8::1").unwrap().is_ipv6());
+
+        assert_eq!(IpAddress::parse_cidr("192.168.1.0/24").unwrap().1, 24);
+        assert_eq!(IpAddress::parse_cidr("fd00::1").unwrap().1, 128);
+        assert!(IpAddress::parse_cidr("192.168.1.0/33").is_err());
+        assert!(IpAddress::parse_cidr("lan/8").is_err());
+
+        #[derive(Deserialize)]
+        struct Peer {
+            ip: IpAddress,
+        }
+        let peer = |toml: &str| toml::from_str::<Peer>(toml).map(|p| p.ip);
+        assert_eq!(peer(r#"ip = "203.0.113.1""#).unwrap().to_string(), "203.0.113.1");
+        assert!(peer(r#"ip = { v6 = "2001:db8::7" }"#).unwrap().is_ipv6());
+        assert!(peer(r#"ip = { v4 = "2001:db8::7" }"#).is_err());
+        assert!(peer(r#"ip = "vpn.example.com""#).is_err());
+    }
+}
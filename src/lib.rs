@@ -0,0 +1,3156 @@
+//! WireGuard failover: probes several uplinks and keeps the route to a
+//! WireGuard peer (or the default route) on the best working one.
+//!
+//! [`FailoverMonitor`] is the whole daemon minus the command line. Build a
+//! [`FailoverConfig`], then either call [`FailoverMonitor::run`] or drive it
+//! one cycle at a time with [`FailoverMonitor::tick`]:
+//!
+//! ```no_run
+//! # fn example(config: wg_failover::FailoverConfig) {
+//! let mut monitor = wg_failover::FailoverMonitor::new(config);
+//! let status = monitor.tick();
+//! if status.switched() {
+//!     println!("now via {:?}: {}", status.active_interface, status.explanation);
+//! }
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Write as _};
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+thread_local! {
+    static PROBE_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Runs `probe` with a fresh probe ID, which `probe_debug!` prefixes to its
+/// lines (`[probe:1a2b3c4d] ...`) so one probe's log lines can be grepped.
+pub(crate) fn with_probe_id<T>(probe: impl FnOnce() -> T) -> T {
+    let id = format!("{:08x}", RandomState::new().build_hasher().finish() as u32);
+    let previous = PROBE_ID.with(|current| current.replace(Some(id)));
+    let result = probe();
+    PROBE_ID.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+pub(crate) fn probe_tag() -> String {
+    PROBE_ID.with(|current| current.borrow().as_ref().map(|id| format!("[probe:{}] ", id)).unwrap_or_default())
+}
+
+macro_rules! probe_debug {
+    ($($arg:tt)*) => {
+        debug!("{}{}", $crate::probe_tag(), format_args!($($arg)*))
+    };
+}
+
+pub fn log_with_timestamp(msg: &str) {
+    debug!("[{}] {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), msg);
+}
+
+pub mod clock;
+pub mod config;
+pub mod error;
+#[cfg(feature = "gelf")]
+pub mod gelf;
+pub mod hooks;
+pub mod ip;
+pub mod mock;
+pub mod network;
+mod persist;
+mod report;
+pub mod wireguard;
+
+pub use clock::{Clock, SystemClock};
+pub use config::{FailoverConfig, FailoverConfigBuilder};
+pub use error::{ErrorSeverity, FailoverError, FailoverResult};
+pub use ip::{IpAddress, Ipv4Address, Ipv6Address};
+
+use config::{peer_from_wg_config, CaptureSettings, EnvOverrides, MaintenanceWindow, ProbeMethod, ProbeStrategy, TieBreak};
+use hooks::{ErrorAlert, RecoveryNotice, SwitchEvent};
+use network::{
+    add_static_arp, announce_addresses, bandwidth_probe, carrier_up, default_route_info, delete_route,
+    delete_static_arp, detect_icmp_capability, get_gateway_for_interface, get_interface_addresses,
+    compute_link_quality_score, get_route_interface, is_ipv6_target, link_admin_up, measure_latency, ping_dont_fragment, probe_wg_endpoint,
+    route_change, routes_for_destination, run_traceroute, set_link_up, test_connectivity_multiple_ips,
+    update_default_route, update_route_for_peer, wait_for_connection_drain, IcmpCapability, InterfaceAddress,
+    CommandRunner, RouteCache, RouteInfo, SystemCommandRunner, DEFAULT_ROUTE_METRIC,
+};
+use persist::PersistedState;
+use report::{format_summary, write_state_dump, MonitorSnapshot};
+
+/// A [`FailoverConfig`] together with the caches shared by everything that
+/// probes or changes routes on its behalf.
+struct AppState {
+    config: FailoverConfig,
+    runner: Arc<dyn CommandRunner>,
+    clock: Arc<dyn Clock>,
+    route_cache: RouteCache,
+    peer_cache: Mutex<PeerReachabilityCache>,
+    link_bring_ups: Mutex<HashMap<String, LinkBringUp>>,
+    stop: StopHandle,
+    pre_failover_hooks: Vec<FailoverHook>,
+    post_failover_hooks: Vec<FailoverHook>,
+    cycle_hooks: Vec<CycleHook>,
+}
+
+// Called with the old and new interface names, "" for none
+type FailoverHook = Arc<dyn Fn(&str, &str) -> FailoverResult<()> + Send + Sync>;
+type CycleHook = Arc<dyn Fn(&NetworkStatus) + Send + Sync>;
+
+impl std::ops::Deref for AppState {
+    type Target = FailoverConfig;
+
+    fn deref(&self) -> &FailoverConfig {
+        &self.config
+    }
+}
+
+impl AppState {
+    fn new(config: FailoverConfig) -> Self {
+        Self {
+            runner: Arc::new(SystemCommandRunner),
+            clock: Arc::new(SystemClock),
+            route_cache: RouteCache::new(config.route_cache_ttl),
+            peer_cache: Mutex::new(PeerReachabilityCache::new(config.peer_cache_threshold, config.peer_exclusion)),
+            link_bring_ups: Mutex::new(HashMap::new()),
+            stop: StopHandle::default(),
+            pre_failover_hooks: Vec::new(),
+            post_failover_hooks: Vec::new(),
+            cycle_hooks: Vec::new(),
+            config,
+        }
+    }
+
+    /// Runs the pre-switch script hook, then the pre-failover closures, and
+    /// returns whether the switch may go ahead.
+    fn run_pre_switch_hooks(&self, event: &SwitchEvent) -> bool {
+        if !self.switch_hooks.run_pre(event) {
+            return false;
+        }
+        let (from, to) = (event.old_iface.as_deref().unwrap_or(""), event.new_iface.as_deref().unwrap_or(""));
+        for hook in &self.pre_failover_hooks {
+            match hook(from, to) {
+                Ok(()) => {}
+                Err(e) if self.switch_hooks.pre_hook_aborts => {
+                    warn!("Pre-failover hook failed: {}, not switching to {}", e, to);
+                    return false;
+                }
+                Err(e) => warn!("Pre-failover hook failed: {}, switching anyway", e),
+            }
+        }
+        true
+    }
+
+    /// Fires the post-switch script hook and webhook, then runs the
+    /// post-failover closures.
+    fn notify_switch(&self, event: &SwitchEvent) {
+        self.switch_hooks.notify(event);
+        let (from, to) = (event.old_iface.as_deref().unwrap_or(""), event.new_iface.as_deref().unwrap_or(""));
+        for hook in &self.post_failover_hooks {
+            if let Err(e) = hook(from, to) {
+                warn!("Post-failover hook failed: {}", e);
+            }
+        }
+    }
+
+    fn runner(&self) -> &dyn CommandRunner {
+        self.runner.as_ref()
+    }
+
+    fn removes_route_when_all_failed(&self) -> bool {
+        self.failover_to_no_route && !self.route_all_traffic
+    }
+
+    /// Test IPs to probe this cycle; the peer is left out while it is
+    /// temporarily excluded for being unreachable everywhere.
+    fn active_test_ips(&self) -> Vec<String> {
+        let excluded = self.peer_cache.lock().unwrap_or_else(|e| e.into_inner()).is_excluded(self.clock.now());
+        if excluded && self.test_ips.len() > 1 {
+            self.test_ips.iter().filter(|ip| !self.is_peer(ip)).cloned().collect()
+        } else {
+            self.test_ips.clone()
+        }
+    }
+
+    /// Returns what probes for `iface` should bind to: the configured source
+    /// address if there is one, otherwise the interface name itself.
+    fn probe_source<'a>(&'a self, iface: &'a str) -> &'a str {
+        self.probe_src_addrs.get(iface).map(String::as_str).unwrap_or(iface)
+    }
+
+    /// Applies the absolute latency cutoff: a link that answers but is slower
+    /// than `max_acceptable_latency_ms` is not usable.
+    fn within_latency_cutoff(&self, iface: &str, latency_ms: f64) -> bool {
+        match self.max_acceptable_latency_ms {
+            Some(max) if latency_ms > max => {
+                warn!("Interface {} average latency {:.1}ms exceeds maximum of {:.1}ms, marking as failed", iface, latency_ms, max);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// The packet loss above which `iface` counts as failed.
+    fn max_loss_pct(&self, iface: &str) -> f64 {
+        self.interface_max_loss_pct.get(iface).copied().unwrap_or(self.max_loss_pct) as f64
+    }
+
+    fn within_loss_cutoff(&self, iface: &str, loss_pct: f64) -> bool {
+        let max = self.max_loss_pct(iface);
+        if loss_pct > max {
+            warn!("Interface {} lost {:.0}% of probe packets (more than {:.0}%), marking as failed", iface, loss_pct, max);
+            return false;
+        }
+        true
+    }
+
+    /// Applies peer and interface changes picked up from a watched env file.
+    /// Returns true if anything changed.
+    fn apply_runtime_overrides(&mut self, overrides: EnvOverrides) -> bool {
+        let mut changed = false;
+        let peer_ip = overrides.peer_ip.as_deref().and_then(|p| match IpAddress::resolve(p) {
+            Ok(peer_ip) => Some(peer_ip),
+            Err(e) => {
+                warn!("Ignoring peer {:?} from the env file: {}", p, e);
+                None
+            }
+        });
+        if let Some(peer_ip) = peer_ip.filter(|p| *p != self.peer_ip) {
+            info!("Env file changed peer from {} to {}", self.peer_ip, peer_ip);
+            let previous = self.config.peer_ip;
+            for ip in self.config.test_ips.iter_mut().filter(|ip| IpAddress::try_from(ip.as_str()).is_ok_and(|ip| ip == previous)) {
+                *ip = peer_ip.to_string();
+            }
+            self.config.peer_ip = peer_ip;
+            changed = true;
+        }
+        let interfaces: Vec<String> = overrides.interface_slots(&self.interfaces).into_iter().flatten().collect();
+        let distinct = interfaces.iter().enumerate().all(|(i, iface)| !interfaces[..i].contains(iface));
+        if interfaces.len() < 2 || !distinct {
+            warn!("Ignoring interfaces {:?} from the env file, at least two different ones are required", interfaces);
+        } else if interfaces != self.interfaces {
+            info!("Env file changed interfaces from {} to {}", self.interfaces.join(", "), interfaces.join(", "));
+            self.config.interfaces = interfaces;
+            changed = true;
+        }
+        changed
+    }
+
+    /// The address family of the peer decides which routes and gateways we manage.
+    fn peer_is_ipv6(&self) -> bool {
+        self.peer_ip.is_ipv6()
+    }
+
+    fn is_peer(&self, ip: &str) -> bool {
+        IpAddress::try_from(ip).is_ok_and(|ip| ip == self.peer_ip)
+    }
+
+    /// Whether the test IPs cover both address families. The peer's family
+    /// then decides the interface status, and with route_all_traffic the
+    /// other family's default route is managed on its own.
+    fn dual_stack(&self) -> bool {
+        let peer_ipv6 = self.peer_is_ipv6();
+        self.test_ips.iter().any(|ip| is_ipv6_target(ip) != peer_ipv6)
+            && self.test_ips.iter().any(|ip| is_ipv6_target(ip) == peer_ipv6)
+    }
+
+    fn interface_index(&self, iface: &str) -> Option<usize> {
+        self.interfaces.iter().position(|i| i == iface)
+    }
+
+    /// Returns the maintenance window (if any) that currently inhibits this
+    /// kind of switch.
+    fn inhibiting_maintenance_window(&self, is_failback: bool) -> Option<&MaintenanceWindow> {
+        let now = SystemTime::from(self.clock.utc_now());
+        self.maintenance_windows.iter().find(|w| {
+            w.contains(now) && if is_failback { w.inhibit_failback } else { w.inhibit_failover }
+        })
+    }
+
+    /// Gap between this interface's pings, shared fairly with every other
+    /// monitored interface when spread_probes is on.
+    fn probe_spacing(&self, test_ips: usize) -> Duration {
+        if self.spread_probes {
+            probe_spacing(self.check_interval, test_ips, self.interfaces.len())
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    fn probe_timeout(&self, method: ProbeMethod) -> u8 {
+        self.probe_timeouts.get(&method).copied().unwrap_or(self.ping_timeout)
+    }
+}
+
+const SMART_RECOVERY_WINDOW: Duration = Duration::from_secs(60);
+const SMART_RECOVERY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InterfaceStatus {
+    Working,
+    Failed,
+    Unknown,
+}
+
+// Good probes in a row before a recovering interface counts as up again
+const RECOVERY_PROBES: u32 = 3;
+// How often to probe while an interface is recovering, if check_interval is longer
+const RECOVERY_PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where an interface is between up and down. Advanced once per probe (after
+/// the status window), so unlike [`InterfaceStatus`] it remembers since when
+/// and, once down, why.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum InterfaceState {
+    /// Not probed yet
+    #[default]
+    Unknown,
+    /// Measurements were reset and the next probe is awaited
+    Probing,
+    Up { since: Instant },
+    Down { since: Instant, reason: String },
+    /// Answering again after being down; up after a few good probes in a row
+    Recovering { since: Instant, attempts: u32 },
+}
+
+impl InterfaceState {
+    pub fn probe_succeeded(self, now: Instant) -> Self {
+        match self {
+            InterfaceState::Unknown | InterfaceState::Probing => InterfaceState::Up { since: now },
+            InterfaceState::Up { since } => InterfaceState::Up { since },
+            InterfaceState::Down { .. } => InterfaceState::Recovering { since: now, attempts: 1 },
+            InterfaceState::Recovering { since, attempts } if attempts + 1 < RECOVERY_PROBES => {
+                InterfaceState::Recovering { since, attempts: attempts + 1 }
+            }
+            InterfaceState::Recovering { .. } => InterfaceState::Up { since: now },
+        }
+    }
+
+    pub fn probe_failed(self, now: Instant, reason: String) -> Self {
+        match self {
+            InterfaceState::Down { since, .. } => InterfaceState::Down { since, reason },
+            _ => InterfaceState::Down { since: now, reason },
+        }
+    }
+
+    /// Starts over after the interface changed under us, e.g. got a new address.
+    pub fn reset(self) -> Self {
+        match self {
+            InterfaceState::Unknown => InterfaceState::Unknown,
+            _ => InterfaceState::Probing,
+        }
+    }
+
+    /// How the interface counts when choosing one; a recovering interface
+    /// already answers, so it may be chosen.
+    pub fn status(&self) -> InterfaceStatus {
+        match self {
+            InterfaceState::Unknown | InterfaceState::Probing => InterfaceStatus::Unknown,
+            InterfaceState::Up { .. } | InterfaceState::Recovering { .. } => InterfaceStatus::Working,
+            InterfaceState::Down { .. } => InterfaceStatus::Failed,
+        }
+    }
+
+    pub fn is_recovering(&self) -> bool {
+        matches!(self, InterfaceState::Recovering { .. })
+    }
+}
+
+impl fmt::Display for InterfaceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterfaceState::Unknown => f.write_str("unknown"),
+            InterfaceState::Probing => f.write_str("probing"),
+            InterfaceState::Up { .. } => f.write_str("up"),
+            InterfaceState::Down { reason, .. } => write!(f, "down ({})", reason),
+            InterfaceState::Recovering { attempts, .. } => write!(f, "recovering ({}/{})", attempts, RECOVERY_PROBES),
+        }
+    }
+}
+
+/// Something that changed during a cycle, listed in [`NetworkStatus::events`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum FailoverEvent {
+    InterfaceStateChanged { iface: String, from: InterfaceState, to: InterfaceState },
+}
+
+// Moves `metrics` to the state `next` makes of its current one, and notes
+// the change in `events` unless it stays in the same kind of state
+fn transition(iface: &str, metrics: &mut InterfaceMetrics, events: &mut Vec<FailoverEvent>, next: impl FnOnce(InterfaceState) -> InterfaceState) {
+    let from = metrics.state.clone();
+    metrics.state = next(from.clone());
+    metrics.status = metrics.state.status();
+    if std::mem::discriminant(&from) != std::mem::discriminant(&metrics.state) {
+        info!("Interface {} is now {} (was {})", iface, metrics.state, from);
+        events.push(FailoverEvent::InterfaceStateChanged { iface: iface.to_string(), from, to: metrics.state.clone() });
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InterfaceMetrics {
+    pub status: InterfaceStatus,
+    pub state: InterfaceState,
+    pub connectivity_latency_ms: f64,
+    pub speed_latency_ms: f64,
+    pub packet_loss_pct: f64, // from the most recent probe or speed check
+    pub estimated_bandwidth_mbps: Option<f64>,
+    pub link_quality_score: Option<f64>, // 0.0-1.0 from the last speed check, see compute_link_quality_score
+    pub health_score: u8, // see compute_health_score
+    pub gateway_probe_ms: f64,
+    pub test_results: HashMap<String, bool>, // IP -> reachable
+    // Whether the IPv4 and IPv6 test IPs answer; None without test IPs of that family
+    pub working_v4: Option<bool>,
+    pub working_v6: Option<bool>,
+    addresses: Option<Vec<IpAddr>>, // stable global addresses, None until first seen
+}
+
+impl Default for InterfaceMetrics {
+    fn default() -> Self {
+        Self {
+            status: InterfaceStatus::Unknown,
+            state: InterfaceState::Unknown,
+            connectivity_latency_ms: 0.0,
+            speed_latency_ms: 0.0,
+            packet_loss_pct: 0.0,
+            estimated_bandwidth_mbps: None,
+            link_quality_score: None,
+            health_score: 0,
+            gateway_probe_ms: 0.0,
+            test_results: HashMap::new(),
+            working_v4: None,
+            working_v6: None,
+            addresses: None,
+        }
+    }
+}
+
+impl InterfaceMetrics {
+    /// Forgets everything measured so far, keeping only the known addresses
+    /// and the state, which the caller moves on.
+    fn reset(&mut self) {
+        *self = Self { addresses: self.addresses.take(), state: std::mem::take(&mut self.state), ..Self::default() };
+    }
+
+    fn working_for(&self, ipv6: bool) -> Option<bool> {
+        if ipv6 { self.working_v6 } else { self.working_v4 }
+    }
+
+    /// Records the interface's current addresses and returns true if they
+    /// differ from the previously recorded set (the first set is just stored).
+    /// IPv6 privacy addresses and non-global scopes are ignored, since they
+    /// rotate or say nothing about the uplink.
+    fn update_addresses(&mut self, addresses: &[InterfaceAddress]) -> bool {
+        let mut current: Vec<IpAddr> = addresses.iter()
+            .filter(|a| a.scope == "global" && !a.temporary)
+            .map(|a| a.addr)
+            .collect();
+        current.sort();
+        current.dedup();
+        let changed = self.addresses.as_ref().is_some_and(|known| *known != current);
+        self.addresses = Some(current);
+        changed
+    }
+}
+
+/// Probe errors of one interface. Transient and recoverable ones are only
+/// counted; a permanent or critical one is alerted on, once, until the
+/// interface probes without errors again.
+#[derive(Debug, Default)]
+struct ProbeErrors {
+    count: u64,
+    alerted: Option<String>,
+}
+
+impl ProbeErrors {
+    // Returns the severity when the error should be alerted on
+    fn record(&mut self, error: Option<&FailoverError>) -> Option<ErrorSeverity> {
+        let Some(error) = error else {
+            self.alerted = None;
+            return None;
+        };
+        match error.severity() {
+            ErrorSeverity::Transient | ErrorSeverity::Recoverable => {
+                self.count += 1;
+                None
+            }
+            severity => {
+                let message = error.to_string();
+                if self.alerted.as_ref() == Some(&message) {
+                    return None;
+                }
+                self.alerted = Some(message);
+                Some(severity)
+            }
+        }
+    }
+}
+
+/// Running min/avg/max of how long it took from detecting a failed active
+/// interface to having a working route installed on another one.
+#[derive(Debug, Default)]
+struct RecoveryStats {
+    count: u32,
+    total: Duration,
+    min: Option<Duration>,
+    max: Option<Duration>,
+}
+
+impl RecoveryStats {
+    fn record(&mut self, time_to_recover: Duration) {
+        self.count += 1;
+        self.total += time_to_recover;
+        self.min = Some(self.min.map_or(time_to_recover, |m| m.min(time_to_recover)));
+        self.max = Some(self.max.map_or(time_to_recover, |m| m.max(time_to_recover)));
+    }
+
+    fn average(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.total / self.count)
+    }
+}
+
+/// Tracks consecutive cycles in which the peer was unreachable from every
+/// probed interface. Past the threshold the peer is left out of connectivity
+/// probes for a while, so a dead peer does not cost a ping timeout per
+/// interface every cycle. A threshold of 0 disables this.
+#[derive(Debug)]
+struct PeerReachabilityCache {
+    threshold: u32,
+    exclusion: Duration,
+    consecutive_failures: u32,
+    excluded_until: Option<Instant>,
+}
+
+impl PeerReachabilityCache {
+    fn new(threshold: u32, exclusion: Duration) -> Self {
+        Self { threshold, exclusion, consecutive_failures: 0, excluded_until: None }
+    }
+
+    fn is_excluded(&self, now: Instant) -> bool {
+        self.excluded_until.is_some_and(|until| now < until)
+    }
+
+    /// Records whether the peer answered on any interface this cycle.
+    fn record_cycle(&mut self, reachable: bool, now: Instant) {
+        if reachable {
+            self.record_success();
+            return;
+        }
+        self.consecutive_failures += 1;
+        if self.threshold > 0 && self.consecutive_failures >= self.threshold && !self.is_excluded(now) {
+            info!("Temporarily excluding peer_ip from probes: unreachable for {} consecutive cycles", self.consecutive_failures);
+            self.excluded_until = Some(now + self.exclusion);
+            self.consecutive_failures = 0;
+        }
+    }
+
+    /// The peer answered some other probe (e.g. the speed check), include it again.
+    fn record_success(&mut self) {
+        if self.excluded_until.take().is_some() {
+            info!("Peer reachable again, including it in probes");
+        }
+        self.consecutive_failures = 0;
+    }
+}
+
+/// Smooths raw per-cycle probe results: an interface only counts as failed
+/// when the majority of the last `size` results failed.
+#[derive(Debug)]
+struct StatusWindow {
+    size: usize,
+    history: VecDeque<bool>,
+}
+
+impl StatusWindow {
+    fn new(size: u8) -> Self {
+        Self { size: size.max(1) as usize, history: VecDeque::new() }
+    }
+
+    /// Records a raw result and returns whether the interface counts as working.
+    fn record(&mut self, ok: bool) -> bool {
+        if self.history.len() == self.size {
+            self.history.pop_front();
+        }
+        self.history.push_back(ok);
+        let failures = self.history.iter().filter(|ok| !**ok).count();
+        failures * 2 <= self.history.len()
+    }
+
+    fn clear(&mut self) {
+        self.history.clear();
+    }
+}
+
+/// Anti-flapping for switches away from a working interface: the candidate
+/// must win `required_checks` decisions in a row, and the active interface is
+/// kept for at least `min_hold_time` after the previous switch. Switches away
+/// from a failed interface are never held back.
+#[derive(Debug)]
+struct SwitchHysteresis {
+    min_hold_time: Duration,
+    required_checks: u32,
+    last_switch: Option<Instant>,
+    better_streak: Option<(String, u32)>, // candidate and consecutive wins
+}
+
+impl SwitchHysteresis {
+    fn new(min_hold_time: Duration, required_checks: u32) -> Self {
+        Self { min_hold_time, required_checks: required_checks.max(1), last_switch: None, better_streak: None }
+    }
+
+    /// Records this cycle's decision. `candidate` is the interface preferred
+    /// over a still-working active one, if any.
+    fn observe(&mut self, candidate: Option<&str>) {
+        self.better_streak = match (candidate, self.better_streak.take()) {
+            (Some(c), Some((previous, wins))) if previous == c => Some((previous, wins + 1)),
+            (Some(c), _) => Some((c.to_string(), 1)),
+            (None, _) => None,
+        };
+    }
+
+    /// Why switching to `candidate` has to wait, or None if it may go ahead.
+    fn suppression(&self, candidate: &str, now: Instant) -> Option<String> {
+        if let Some(held) = self.last_switch.map(|t| now.saturating_duration_since(t)).filter(|h| *h < self.min_hold_time) {
+            return Some(format!("active for {}s of the {}s minimum hold time", held.as_secs(), self.min_hold_time.as_secs()));
+        }
+        let wins = self.better_streak.as_ref().filter(|(c, _)| c == candidate).map_or(0, |(_, wins)| *wins);
+        if wins < self.required_checks {
+            return Some(format!("{} better for {}/{} consecutive checks", candidate, wins, self.required_checks));
+        }
+        None
+    }
+
+    fn record_switch(&mut self, now: Instant) {
+        self.last_switch = Some(now);
+        self.better_streak = None;
+    }
+}
+
+/// Spreads `interval` randomly by up to +/-`jitter_pct` percent so a fleet of
+/// nodes started together drifts apart. Each sleep is jittered around the
+/// nominal interval, so the offset never accumulates beyond the band.
+fn jittered_interval(interval: Duration, jitter_pct: u8, random: u64) -> Duration {
+    if jitter_pct == 0 {
+        return interval;
+    }
+    let unit = (random as f64 / u64::MAX as f64) * 2.0 - 1.0; // -1.0..=1.0
+    let offset = interval.as_secs_f64() * jitter_pct as f64 / 100.0 * unit;
+    Duration::from_secs_f64((interval.as_secs_f64() + offset).max(0.0))
+}
+
+// Gap between consecutive test IP pings when probes are spread out: each of
+// `interfaces` gets an equal share of the interval, split evenly between its
+// test IPs. Pings to rate-limited targets then arrive at a steady pace instead
+// of in a burst at the top of the cycle.
+fn probe_spacing(interval: Duration, test_ips: usize, interfaces: usize) -> Duration {
+    let slots = test_ips.saturating_mul(interfaces);
+    if slots <= 1 {
+        return Duration::ZERO;
+    }
+    interval / slots as u32
+}
+
+/// Result of one connectivity check of one interface.
+#[derive(Debug)]
+struct ProbeOutcome {
+    ok: bool,
+    latency_ms: f64,
+    packet_loss_pct: f64,
+    test_results: HashMap<String, bool>,
+    gateway_ms: Option<f64>, // if the gateway was probed and answered
+    error: Option<FailoverError>, // the first probe that could not be sent, if any
+}
+
+impl ProbeOutcome {
+    // Nothing was or could be sent: every test IP counts as lost
+    fn unreachable(state: &AppState) -> Self {
+        Self {
+            ok: false,
+            latency_ms: 0.0,
+            packet_loss_pct: 100.0,
+            test_results: state.active_test_ips().into_iter().map(|ip| (ip, false)).collect(),
+            gateway_ms: None,
+            error: None,
+        }
+    }
+}
+
+/// Runs the multi-IP connectivity check for one interface with its configured
+/// probe source, timeout and namespace, and applies the latency and packet
+/// loss cutoffs. With `probe_gateway_first`, the local gateway is pinged first
+/// and the interface fails straight away if it does not answer.
+fn probe_connectivity(state: &AppState, iface: &str) -> ProbeOutcome {
+    with_probe_id(|| {
+        let result = probe_connectivity_inner(state, iface);
+        probe_debug!("Probe of {} completed: working={}, latency={:.1}ms, loss={:.1}%", iface, result.ok, result.latency_ms, result.packet_loss_pct);
+        result
+    })
+}
+
+/// Last automatic bring-up of an interface, kept until it has stayed up for
+/// a whole flap cooldown.
+#[derive(Debug, Clone, Copy)]
+struct LinkBringUp {
+    at: Instant,
+    flaps: u32, // bring-ups before this one that did not last
+}
+
+/// With auto_bring_up_interfaces, brings `iface` back up if it is
+/// administratively down, but not again within link_flap_cooldown of the
+/// previous attempt. The interface answers probes from the next cycle on.
+fn bring_up_if_down(state: &AppState, iface: &str) {
+    match link_admin_up(state.runner(), iface) {
+        Ok(true) => return,
+        Ok(false) => {}
+        Err(e) => {
+            probe_debug!("Could not read link flags of {}: {}", iface, e);
+            return;
+        }
+    }
+    let now = state.clock.now();
+    let mut bring_ups = state.link_bring_ups.lock().unwrap_or_else(|e| e.into_inner());
+    let flaps = match bring_ups.get(iface) {
+        Some(last) => {
+            let since = now.saturating_duration_since(last.at);
+            if since < state.link_flap_cooldown {
+                let remaining = (state.link_flap_cooldown - since).as_secs_f64().ceil();
+                warn!("Skipping bring-up for {}: flap cooldown active ({}s remaining)", iface, remaining);
+                return;
+            }
+            last.flaps + 1
+        }
+        None => 0,
+    };
+    bring_ups.insert(iface.to_string(), LinkBringUp { at: now, flaps });
+    match set_link_up(state.runner(), iface) {
+        Ok(()) if flaps > 0 => warn!("Brought {} back up, it went down {} times after earlier bring-ups", iface, flaps),
+        Ok(()) => info!("Brought {} back up", iface),
+        Err(e) => warn!("Could not bring {} up: {}", iface, e),
+    }
+}
+
+// Forgets the flaps of an interface that stayed up for a full cooldown
+fn note_link_up(state: &AppState, iface: &str) {
+    let now = state.clock.now();
+    let mut bring_ups = state.link_bring_ups.lock().unwrap_or_else(|e| e.into_inner());
+    if bring_ups.get(iface).is_some_and(|last| now.saturating_duration_since(last.at) >= state.link_flap_cooldown) {
+        bring_ups.remove(iface);
+    }
+}
+
+fn probe_connectivity_inner(state: &AppState, iface: &str) -> ProbeOutcome {
+    // An unplugged cable fails every ping anyway; don't wait for the timeouts
+    match carrier_up(state.runner(), iface) {
+        Ok(false) => {
+            if state.auto_bring_up_interfaces {
+                bring_up_if_down(state, iface);
+            }
+            probe_debug!("No carrier on {}, skipping probe", iface);
+            return ProbeOutcome::unreachable(state);
+        }
+        Ok(true) => note_link_up(state, iface),
+        Err(e) => probe_debug!("Could not read link state of {}: {}", iface, e),
+    }
+
+    let mut gateway_ms = None;
+    let test_ips = state.active_test_ips();
+    let dual_stack = state.dual_stack();
+    if state.probe_gateway_first {
+        if let Some(gateway) = state.route_cache.gateway_for(state.runner(), iface, state.peer_is_ipv6()) {
+            let (reachable, latency, _) = measure_latency(
+                state.runner(),
+                state.probe_source(iface),
+                &gateway,
+                1,
+                state.probe_timeout(ProbeMethod::Ping),
+                state.connectivity_netns.as_deref(),
+            );
+            // The other family may still work behind its own gateway
+            if !reachable && !dual_stack {
+                probe_debug!("Gateway unreachable for {}, skipping full probe", iface);
+                return ProbeOutcome::unreachable(state);
+            }
+            gateway_ms = reachable.then_some(latency);
+        }
+    }
+
+    let spacing = state.probe_spacing(test_ips.len());
+    let (ok, latency, loss, results, error) = test_connectivity_multiple_ips(
+        state.runner(),
+        &*state.clock,
+        state.probe_source(iface),
+        &test_ips,
+        state.probe_timeout(ProbeMethod::Ping),
+        state.connectivity_netns.as_deref(),
+        spacing,
+    );
+    let ok = if dual_stack { family_working(&results, state.peer_is_ipv6()).unwrap_or(ok) } else { ok };
+    let ok = ok && state.within_latency_cutoff(iface, latency) && state.within_loss_cutoff(iface, loss);
+    ProbeOutcome { ok, latency_ms: latency, packet_loss_pct: loss, test_results: results, gateway_ms, error }
+}
+
+/// Whether the test IPs of one address family answered, by the same
+/// at-least-half rule as the whole probe. None if none were probed.
+fn family_working(results: &HashMap<String, bool>, ipv6: bool) -> Option<bool> {
+    let family: Vec<bool> = results.iter().filter(|(ip, _)| is_ipv6_target(ip) == ipv6).map(|(_, ok)| *ok).collect();
+    let reached = family.iter().filter(|ok| **ok).count();
+    (!family.is_empty()).then_some(reached > 0 && reached * 2 >= family.len())
+}
+
+/// How an interface is referred to in logs and explanations, by its place in
+/// the priority order.
+pub fn interface_role(index: usize) -> String {
+    match index {
+        0 => "Primary".to_string(),
+        1 => "Secondary".to_string(),
+        n => format!("Priority {}", n + 1),
+    }
+}
+
+/// Picks the interface to route through and explains why, in plain words.
+/// Returns `None` when no interface should be selected (keep the current route).
+fn select_interface<'a>(state: &'a AppState, metrics: &[InterfaceMetrics]) -> (Option<&'a String>, String) {
+    let (index, explanation) = choose_interface(
+        &state.interfaces,
+        metrics,
+        state.speed_threshold,
+        state.removes_route_when_all_failed(),
+        state.preferred_interface.as_deref().and_then(|iface| state.interfaces.iter().position(|i| i == iface)),
+    );
+    (index.map(|i| &state.interfaces[i]), explanation)
+}
+
+/// The decision behind `select_interface`: the most preferred working
+/// interface wins unless a less preferred one is significantly faster.
+/// A working `preferred` interface takes that place instead, while failover
+/// among the rest still follows list order.
+fn choose_interface(
+    interfaces: &[String],
+    metrics: &[InterfaceMetrics],
+    speed_threshold: u8,
+    removes_route: bool,
+    preferred: Option<usize>,
+) -> (Option<usize>, String) {
+    let everyone = if metrics.len() == 2 { "Both interfaces".to_string() } else { format!("All {} interfaces", metrics.len()) };
+    let Some(best) = metrics.iter().position(|m| m.status == InterfaceStatus::Working) else {
+        return if metrics.iter().any(|m| m.status == InterfaceStatus::Unknown) {
+            (None, "Interface status unknown. Decision: no change.".to_string())
+        } else if removes_route {
+            (None, format!("{} failed. Decision: remove the peer route.", everyone))
+        } else {
+            (None, format!("{} failed. Decision: keep the current route.", everyone))
+        };
+    };
+    let best = match preferred.filter(|&p| metrics.get(p).is_some_and(|m| m.status == InterfaceStatus::Working)) {
+        Some(preferred) => preferred,
+        None if metrics[..best].iter().any(|m| m.status == InterfaceStatus::Unknown) => {
+            return (None, "Interface status unknown. Decision: no change.".to_string());
+        }
+        None => best,
+    };
+    let role = |i: usize| {
+        if Some(i) == preferred { format!("Preferred {}", interface_role(i).to_lowercase()) } else { interface_role(i) }
+    };
+
+    let working: Vec<usize> = (0..metrics.len()).filter(|&i| metrics[i].status == InterfaceStatus::Working).collect();
+    if working.len() == 1 {
+        let statuses: Vec<String> = interfaces.iter().zip(metrics).enumerate()
+            .map(|(i, (iface, m))| {
+                let role = if i == 0 { interface_role(i) } else { interface_role(i).to_lowercase() };
+                let status = if m.status == InterfaceStatus::Working { "working" } else { "failed" };
+                format!("{} {} {}", role, iface, status)
+            })
+            .collect();
+        return (Some(best), format!("{}. Decision: use {}.", statuses.join(", "), role(best)));
+    }
+
+    // Several work. Default is the most preferred; switch only if another is
+    // significantly faster. "Faster" here uses latency as proxy, lower is better.
+    let working_summary = if working.len() == metrics.len() {
+        format!("{} working.", everyone)
+    } else {
+        format!("{} of {} interfaces working.", working.len(), metrics.len())
+    };
+    let best_lat = metrics[best].speed_latency_ms;
+    let fastest = working.iter().copied()
+        .filter(|&i| i != best && metrics[i].speed_latency_ms > 0.0)
+        .min_by(|&a, &b| score_interface(&metrics[a]).total_cmp(&score_interface(&metrics[b])));
+    let Some(fastest) = fastest.filter(|_| best_lat > 0.0) else {
+        return (
+            Some(best),
+            format!("{} No speed data available yet. Decision: stick with {}.", working_summary, role(best)),
+        );
+    };
+
+    // The challenger must be < best * (1 - threshold/100)
+    let fastest_lat = metrics[fastest].speed_latency_ms;
+    let (best_score, fastest_score) = (score_interface(&metrics[best]), score_interface(&metrics[fastest]));
+    let threshold_factor = 1.0 - (speed_threshold as f64 / 100.0);
+    let difference_pct = (best_score - fastest_score) / best_score * 100.0;
+    let quality = |i: usize| metrics[i].link_quality_score.map(|q| format!(" quality={:.2}", q)).unwrap_or_default();
+    let comparison = if difference_pct >= 0.0 {
+        format!("{} lower by {:.1}%", role(fastest), difference_pct)
+    } else {
+        format!("{} higher by {:.1}%", role(fastest), -difference_pct)
+    };
+    let summary = format!(
+        "{} {} {} latency={:.1}ms{}, {} {} latency={:.1}ms{}. {} (threshold {}%).",
+        working_summary, role(best), interfaces[best], best_lat, quality(best),
+        role(fastest), interfaces[fastest], fastest_lat, quality(fastest), comparison, speed_threshold
+    );
+
+    if fastest_score < best_score * threshold_factor {
+        return (Some(fastest), format!("{} Decision: switch to {}.", summary, role(fastest)));
+    }
+    // Just as fast either way: a clearly healthier link is the better bet
+    let healthier = working.iter().copied()
+        .filter(|&i| i != best && metrics[i].speed_latency_ms > 0.0)
+        .filter(|&i| (score_interface(&metrics[i]) - best_score).abs() <= best_score * (1.0 - threshold_factor))
+        .max_by_key(|&i| (metrics[i].health_score, std::cmp::Reverse(i)))
+        .filter(|&i| metrics[i].health_score >= metrics[best].health_score.saturating_add(HEALTH_SCORE_MARGIN));
+    match healthier {
+        Some(i) => (Some(i), format!("{} {} {} is healthier ({} against {}). Decision: switch to {}.",
+            summary, role(i), interfaces[i], metrics[i].health_score, metrics[best].health_score, role(i))),
+        None => (Some(best), format!("{} Decision: stick with {}.", summary, role(best))),
+    }
+}
+
+// Latency at which the latency part of the health score reaches 0, unless
+// max_acceptable_latency_ms is set
+const HEALTH_LATENCY_ZERO_MS: f64 = 500.0;
+// Bandwidth estimate that earns the full bandwidth part of the health score
+const HEALTH_BANDWIDTH_FULL_MBPS: f64 = 100.0;
+// How much healthier an interface must be to win over a just as fast one
+const HEALTH_SCORE_MARGIN: u8 = 10;
+
+/// Rates an interface from 0 (failed) to 100 (perfect). Its connectivity
+/// latency, packet loss, link quality and bandwidth estimate each map to
+/// 0-100 and are averaged with the configured [`ScoringWeights`]; parts that
+/// were not measured are left out.
+///
+/// [`ScoringWeights`]: crate::config::ScoringWeights
+pub fn compute_health_score(metrics: &InterfaceMetrics, config: &FailoverConfig) -> u8 {
+    if metrics.status != InterfaceStatus::Working {
+        return 0;
+    }
+    let weights = &config.scoring_weights;
+    let latency_zero = config.max_acceptable_latency_ms.unwrap_or(HEALTH_LATENCY_ZERO_MS);
+    let parts = [
+        (weights.latency, Some(1.0 - metrics.connectivity_latency_ms / latency_zero)),
+        (weights.loss, Some(1.0 - metrics.packet_loss_pct / 100.0)),
+        (weights.link_quality, metrics.link_quality_score),
+        (weights.bandwidth, metrics.estimated_bandwidth_mbps.map(|mbps| mbps / HEALTH_BANDWIDTH_FULL_MBPS)),
+    ];
+    let (weighted, total) = parts.iter()
+        .filter_map(|(weight, part)| part.map(|part| (weight * part.clamp(0.0, 1.0), weight)))
+        .fold((0.0, 0.0), |(sum, total), (part, weight)| (sum + part, total + weight));
+    if total <= 0.0 {
+        return 100;
+    }
+    (weighted / total * 100.0).round() as u8
+}
+
+fn update_health_scores(state: &AppState, metrics: &mut [InterfaceMetrics]) {
+    for m in metrics {
+        m.health_score = compute_health_score(m, &state.config);
+    }
+}
+
+/// What the speed comparison ranks interfaces by, lower is better: the
+/// latency to the peer, counted up to twice as slow as the link quality
+/// drops from 1.0 to 0.0. Without a quality score it is the latency.
+fn score_interface(metrics: &InterfaceMetrics) -> f64 {
+    metrics.speed_latency_ms / metrics.link_quality_score.map_or(1.0, |q| 0.5 + 0.5 * q)
+}
+
+/// Picks among the working interfaces whose speed latency is within
+/// `epsilon_ms` of the `chosen` one's. `turn` is the cycle number for
+/// round-robin, and a random number for weighted, where each tied interface
+/// gets a share inverse to its latency. Without speed data for `chosen`,
+/// nothing is tied.
+fn break_tie(metrics: &[InterfaceMetrics], chosen: usize, tie_break: TieBreak, epsilon_ms: f64, turn: u64) -> usize {
+    let chosen_lat = metrics[chosen].speed_latency_ms;
+    let tied: Vec<usize> = (0..metrics.len())
+        .filter(|&i| metrics[i].status == InterfaceStatus::Working && metrics[i].speed_latency_ms > 0.0)
+        .filter(|&i| (metrics[i].speed_latency_ms - chosen_lat).abs() <= epsilon_ms)
+        .collect();
+    if chosen_lat <= 0.0 || tied.len() < 2 {
+        return chosen;
+    }
+    match tie_break {
+        TieBreak::RoundRobin => tied[(turn % tied.len() as u64) as usize],
+        TieBreak::Weighted => {
+            let weights: Vec<f64> = tied.iter().map(|&i| 1.0 / metrics[i].speed_latency_ms).collect();
+            let mut point = turn as f64 / u64::MAX as f64 * weights.iter().sum::<f64>();
+            for (&i, weight) in tied.iter().zip(&weights) {
+                if point < *weight {
+                    return i;
+                }
+                point -= weight;
+            }
+            *tied.last().expect("at least two tied")
+        }
+    }
+}
+
+fn explain_decision(state: &AppState) -> Result<String> {
+    let mut out = String::new();
+    writeln!(out, "Explaining interface decision for peer {}", state.peer_ip)?;
+    writeln!(out, "Test IPs: {}", state.test_ips.join(", "))?;
+    writeln!(out)?;
+
+    let mut all_metrics = Vec::new();
+    for (index, iface) in state.interfaces.iter().enumerate() {
+        let role = interface_role(index);
+        let gateway = get_gateway_for_interface(state.runner(), iface, state.peer_is_ipv6());
+        let (ok, latency, loss, results, error) = test_connectivity_multiple_ips(
+            state.runner(),
+            &*state.clock,
+            state.probe_source(iface),
+            &state.test_ips,
+            state.probe_timeout(ProbeMethod::Ping),
+            state.connectivity_netns.as_deref(),
+            Duration::ZERO,
+        );
+        let reachable = results.values().filter(|r| **r).count();
+
+        writeln!(out, "{} {} (gateway: {})", role, iface, gateway.as_deref().unwrap_or("none"))?;
+        for ip in &state.test_ips {
+            let status = if results.get(ip).copied().unwrap_or(false) { "reachable" } else { "unreachable" };
+            writeln!(out, "  {:<20} {}", ip, status)?;
+        }
+        writeln!(out, "  {}/{} test IPs reachable (at least 50% required), average latency {:.1}ms, {:.0}% packet loss",
+            reachable, state.test_ips.len(), latency, loss)?;
+        if let Some(e) = &error {
+            writeln!(out, "  probe error ({}): {}", e.severity(), e)?;
+        }
+
+        let within_cutoff = state.within_latency_cutoff(iface, latency);
+        if let (true, false, Some(max)) = (ok, within_cutoff, state.max_acceptable_latency_ms) {
+            writeln!(out, "  Average latency exceeds the {:.1}ms maximum", max)?;
+        }
+        let within_loss = state.within_loss_cutoff(iface, loss);
+        if ok && !within_loss {
+            writeln!(out, "  Packet loss exceeds {:.0}%", state.max_loss_pct(iface))?;
+        }
+        let status = if ok && within_cutoff && within_loss { InterfaceStatus::Working } else { InterfaceStatus::Failed };
+        writeln!(out, "  Status: {:?}", status)?;
+        writeln!(out)?;
+
+        all_metrics.push(InterfaceMetrics {
+            status,
+            connectivity_latency_ms: latency,
+            packet_loss_pct: loss,
+            test_results: results,
+            ..Default::default()
+        });
+    }
+
+    if working_count(&all_metrics) > 1 {
+        writeln!(out, "Several interfaces working, measuring latency to peer {} for speed comparison", state.peer_ip)?;
+        measure_peer_latency(state, &mut all_metrics);
+        writeln!(out)?;
+    }
+
+    update_health_scores(state, &mut all_metrics);
+    let (_, explanation) = select_interface(state, &all_metrics);
+    writeln!(out, "{}", explanation)?;
+    Ok(out)
+}
+
+fn working_count(metrics: &[InterfaceMetrics]) -> usize {
+    metrics.iter().filter(|m| m.status == InterfaceStatus::Working).count()
+}
+
+// Heavier latency measurement to the peer used for the speed comparison,
+// taken on every working interface. An interface losing most of these pings
+// is degraded, unless every interface does: then the peer itself is down.
+fn measure_peer_latency(state: &AppState, metrics: &mut [InterfaceMetrics]) {
+    let mut measured = Vec::new();
+    for (index, (iface, m)) in state.interfaces.iter().zip(metrics.iter_mut()).enumerate() {
+        if m.status == InterfaceStatus::Working {
+            let (_, avg, loss) = measure_latency(state.runner(), state.probe_source(iface), &state.peer_ip.to_string(), 5, 5, state.speed_netns.as_deref());
+            m.speed_latency_ms = avg;
+            m.packet_loss_pct = loss;
+            m.link_quality_score = match compute_link_quality_score(state.runner(), iface) {
+                Ok(score) => Some(score),
+                Err(e) => {
+                    debug!("No link quality score for {}: {}", iface, e);
+                    None
+                }
+            };
+            measured.push(index);
+        }
+    }
+    if measured.iter().any(|&i| metrics[i].packet_loss_pct <= state.max_loss_pct(&state.interfaces[i])) {
+        for i in measured {
+            if !state.within_loss_cutoff(&state.interfaces[i], metrics[i].packet_loss_pct) {
+                metrics[i].status = InterfaceStatus::Failed;
+            }
+        }
+    }
+}
+
+/// One full probe of every interface, including the speed comparison when
+/// several work, as a single main loop cycle would do it.
+fn probe_all_interfaces(state: &AppState) -> Vec<InterfaceMetrics> {
+    let mut all_metrics = Vec::new();
+    for iface in &state.interfaces {
+        let outcome = probe_connectivity(state, iface);
+        all_metrics.push(InterfaceMetrics {
+            status: if outcome.ok { InterfaceStatus::Working } else { InterfaceStatus::Failed },
+            connectivity_latency_ms: outcome.latency_ms,
+            packet_loss_pct: outcome.packet_loss_pct,
+            gateway_probe_ms: outcome.gateway_ms.unwrap_or(0.0),
+            test_results: outcome.test_results,
+            ..Default::default()
+        });
+    }
+    if working_count(&all_metrics) > 1 {
+        measure_peer_latency(state, &mut all_metrics);
+    }
+    update_health_scores(state, &mut all_metrics);
+    all_metrics
+}
+
+/// Answers "is the route wrong, or is the decision wrong?": probes every interface
+/// once, then compares the route the daemon would install with the routing table.
+fn audit_routes(state: &AppState) -> Result<String> {
+    let mut out = String::new();
+    let metrics = probe_all_interfaces(state);
+    let (desired, explanation) = select_interface(state, &metrics);
+    let peer_route = get_route_interface(state.runner(), &state.peer_ip.to_string());
+    let default_info = default_route_info(state.runner(), state.peer_is_ipv6())?;
+    let default_route = default_info.as_ref().map(|r| r.dev.clone());
+
+    let statuses: Vec<String> = state.interfaces.iter().zip(&metrics).enumerate()
+        .map(|(i, (iface, m))| {
+            let role = if i == 0 { interface_role(i) } else { interface_role(i).to_lowercase() };
+            format!("{} {}: {:?}", role, iface, m.status)
+        })
+        .collect();
+    writeln!(out, "{}", statuses.join(", "))?;
+    writeln!(out, "{}", explanation)?;
+    writeln!(out)?;
+    writeln!(out, "{:<16} {:<12} {:<12}", "ROUTE", "CURRENT", "DESIRED")?;
+    let (managed, current) = if state.route_all_traffic {
+        writeln!(out, "{:<16} {:<12} {:<12}", "default", default_route.as_deref().unwrap_or("none"), desired.map_or("(unchanged)", |d| d.as_str()))?;
+        writeln!(out, "{:<16} {:<12} {:<12}", format!("peer {}", state.peer_ip), peer_route.as_deref().unwrap_or("none"), "-")?;
+        ("default route", default_route)
+    } else {
+        writeln!(out, "{:<16} {:<12} {:<12}", "default", default_route.as_deref().unwrap_or("none"), "-")?;
+        writeln!(out, "{:<16} {:<12} {:<12}", format!("peer {}", state.peer_ip), peer_route.as_deref().unwrap_or("none"), desired.map_or("(unchanged)", |d| d.as_str()))?;
+        ("peer route", peer_route)
+    };
+    if let Some(info) = &default_info {
+        writeln!(out, "Default route: dev {}, gateway {}, metric {}, table {}",
+            info.dev,
+            info.gateway.as_deref().unwrap_or("none"),
+            info.metric.map_or("none".to_string(), |m| m.to_string()),
+            info.table.as_deref().unwrap_or("main"))?;
+    }
+    writeln!(out)?;
+
+    match (desired, current) {
+        (None, _) => writeln!(out, "No interface would be selected; the daemon would leave the {} as is.", managed)?,
+        (Some(desired), Some(current)) if *desired == current => writeln!(out, "OK: {} matches the daemon's decision ({}).", managed, desired)?,
+        (Some(desired), Some(current)) => writeln!(out, "MISMATCH: {} is via {}, the daemon would route via {}.", managed, current, desired)?,
+        (Some(desired), None) => writeln!(out, "MISMATCH: no {} found, the daemon would route via {}.", managed, desired)?,
+    }
+    Ok(out)
+}
+
+/// A tcpdump process writing a ring of pcap files (`-C`/`-W`), restarted on
+/// the active interface after each switch unless a fixed interface is set.
+/// The process is stopped when this is dropped.
+struct RollingCapture {
+    settings: CaptureSettings,
+    running: Option<(String, Child)>, // interface, tcpdump
+}
+
+impl RollingCapture {
+    fn new(settings: CaptureSettings) -> Self {
+        Self { settings, running: None }
+    }
+
+    fn file_path(&self, iface: &str) -> PathBuf {
+        self.settings.dir.join(format!("capture-{}.pcap", iface))
+    }
+
+    /// Base name of the running ring; tcpdump appends the file number
+    fn current_file(&self) -> Option<PathBuf> {
+        self.running.as_ref().map(|(iface, _)| self.file_path(iface))
+    }
+
+    /// Makes sure tcpdump runs on the right interface, (re)starting it if the
+    /// active interface changed or the previous process exited.
+    fn follow(&mut self, runner: &dyn CommandRunner, active: Option<&str>) {
+        let Some(target) = self.settings.interface.clone().or_else(|| active.map(str::to_string)) else {
+            return;
+        };
+        if let Some((iface, child)) = &mut self.running {
+            match child.try_wait() {
+                Ok(None) if *iface == target => return,
+                Ok(None) => info!("Active interface is now {}, moving capture from {}", target, iface),
+                Ok(Some(status)) => warn!("tcpdump on {} exited ({}), restarting", iface, status),
+                Err(e) => warn!("Could not check tcpdump on {}: {}", iface, e),
+            }
+        }
+        self.stop();
+
+        let path = self.file_path(&target);
+        // Command: tcpdump -i <iface> -n -U -w <dir>/capture-<iface>.pcap -C <size_mb> -W <count>
+        let (path_arg, size, count) = (path.to_string_lossy(), self.settings.max_file_size_mb.to_string(), self.settings.retention_count.to_string());
+        let spawned = runner.spawn("tcpdump", &["-i", &target, "-n", "-U", "-w", &path_arg, "-C", &size, "-W", &count]);
+        match spawned {
+            Ok(child) => {
+                info!("Capturing {} to {:?} (rotating {} files)", target, path, self.settings.retention_count);
+                self.running = Some((target, child));
+            }
+            Err(e) => error!("Failed to start tcpdump on {}: {}", target, e),
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some((iface, mut child)) = self.running.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+            debug!("Stopped capture on {}", iface);
+        }
+    }
+}
+
+impl Drop for RollingCapture {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Runs a best-effort traceroute in the background when an interface goes down,
+/// at most once per `traceroute_min_interval` per interface.
+fn maybe_traceroute(state: &AppState, iface: &str, last_runs: &mut HashMap<String, Instant>) {
+    if !state.traceroute_on_failure {
+        return;
+    }
+    let now = state.clock.now();
+    if let Some(ago) = last_runs.get(iface).map(|last| now.saturating_duration_since(*last)) {
+        if ago < state.traceroute_min_interval {
+            debug!("Skipping traceroute for {}: last run {:?} ago", iface, ago);
+            return;
+        }
+    }
+    last_runs.insert(iface.to_string(), now);
+
+    let iface = iface.to_string();
+    let target = state.peer_ip.to_string();
+    let max_hops = state.traceroute_max_hops;
+    let runner = Arc::clone(&state.runner);
+    thread::spawn(move || match run_traceroute(runner.as_ref(), &iface, &target, max_hops) {
+        Ok(hops) => {
+            info!("Traceroute to {} via {} after failure:", target, iface);
+            for hop in hops {
+                info!("  {}", hop);
+            }
+        }
+        Err(e) => warn!("Traceroute to {} via {} failed: {}", target, iface, e),
+    });
+}
+
+// Install the route for the WireGuard peer (or the default route) via the given interface
+fn install_route(state: &AppState, iface: &str, gateway: Option<&String>) -> Result<()> {
+    state.route_cache.invalidate();
+    if state.route_all_traffic {
+        log_with_timestamp(&format!("Routing ALL traffic via {}", iface));
+        let wanted = RouteInfo {
+            dev: iface.to_string(),
+            gateway: gateway.cloned(),
+            metric: Some(DEFAULT_ROUTE_METRIC),
+            table: None,
+        };
+        if default_route_info(state.runner(), state.peer_is_ipv6()).ok().flatten().as_ref() == Some(&wanted) {
+            log_with_timestamp("Default route already in place, not replacing it");
+        } else {
+            match update_default_route(state.runner(), iface, gateway, state.peer_is_ipv6()) {
+                Ok(_) => log_with_timestamp("Default route updated successfully."),
+                Err(e) => {
+                    error!("Failed to update default route: {}", e);
+                    log_with_timestamp(&format!("Default route update failed with error: {}", e));
+                    return Err(e);
+                }
+            }
+        }
+        install_route_exclusions(state, iface, gateway);
+        Ok(())
+    } else {
+        log_with_timestamp(&format!("Routing WireGuard Peer {} via {}", state.peer_ip, iface));
+        if state.pre_failover_flush {
+            log_with_timestamp(&format!("Flushing existing route for peer {}", state.peer_ip));
+            if let Err(e) = delete_route(state.runner(), &state.peer_ip.to_string(), None) {
+                warn!("Failed to flush old peer route: {}", e);
+            }
+        }
+        match update_route_for_peer(state.runner(), &state.peer_ip.to_string(), iface, gateway) {
+            Ok(_) => {
+                log_with_timestamp("Peer route updated successfully.");
+                Ok(())
+            },
+            Err(e) => {
+                error!("Failed to update peer route: {}", e);
+                log_with_timestamp(&format!("Peer route update failed with error: {}", e));
+                Err(e)
+            }
+        }
+    }
+}
+
+// Route-all mode: keep the excluded prefixes (LAN, WireGuard endpoints) on explicit
+// routes so they never follow the default into the tunnel. Entries without an
+// interface follow the selected interface.
+fn install_route_exclusions(state: &AppState, iface: &str, gateway: Option<&String>) {
+    for exclusion in &state.route_exclusions {
+        let (dev, gw) = match &exclusion.interface {
+            Some(dev) => (dev.as_str(), exclusion.gateway.clone().or_else(|| state.route_cache.gateway_for(state.runner(), dev, is_ipv6_target(&exclusion.prefix)))),
+            None => (iface, exclusion.gateway.clone().or_else(|| gateway.cloned())),
+        };
+        match update_route_for_peer(state.runner(), &exclusion.prefix, dev, gw.as_ref()) {
+            Ok(_) => log_with_timestamp(&format!("Excluded prefix {} routed via {}", exclusion.prefix, dev)),
+            Err(e) => warn!("Failed to route excluded prefix {} via {}: {}", exclusion.prefix, dev, e),
+        }
+    }
+}
+
+// Check that the confirmation target answers over the interface we just switched to
+fn confirm_switch(state: &AppState, iface: &str) -> std::result::Result<(), String> {
+    let timeout = state.probe_timeout(ProbeMethod::Ping);
+    if let Some(target) = &state.post_switch_confirm_target {
+        let (reachable, _, _) = measure_latency(state.runner(), state.probe_source(iface), target, 3, timeout, state.connectivity_netns.as_deref());
+        if !reachable {
+            return Err(format!("confirmation target {} unreachable", target));
+        }
+    }
+    // Small pings can work while full-size packets are black-holed on a link with a smaller MTU
+    if let Some(mtu) = state.post_switch_mtu_check {
+        if !ping_dont_fragment(state.runner(), state.probe_source(iface), &state.peer_ip.to_string(), mtu, timeout, state.connectivity_netns.as_deref()) {
+            return Err(format!("{}-byte packets with DF set do not reach peer {}, link is degraded", mtu, state.peer_ip));
+        }
+    }
+    Ok(())
+}
+
+fn verify_recovery(state: &AppState, iface: &str) -> bool {
+    let total_checks = (SMART_RECOVERY_WINDOW.as_secs() / SMART_RECOVERY_CHECK_INTERVAL.as_secs()) as u32;
+    let required = ((total_checks as f64 * state.smart_recovery_success_rate).ceil() as u32).min(total_checks);
+    let allowed_failures = total_checks - required;
+    let mut passed = 0;
+    let mut failed = 0;
+
+    for check in 1..=total_checks {
+        if probe_connectivity(state, iface).ok {
+            passed += 1;
+        } else {
+            failed += 1;
+        }
+        info!("Primary recovery verification in progress ({}/{} checks passed)", passed, total_checks);
+
+        if failed > allowed_failures {
+            return false;
+        }
+        if check < total_checks {
+            state.stop.sleep(&*state.clock, SMART_RECOVERY_CHECK_INTERVAL, None);
+            if state.stop.stop_requested() {
+                info!("Stop requested, abandoning recovery verification of {}", iface);
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+// First non-empty, non-comment line of the control file, if any
+fn read_desired_interface(path: &Path) -> Result<Option<String>> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    Ok(content.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string))
+}
+
+/// Route-applier mode: no probing at all. An external health monitor writes
+/// the interface to use into the control file, and the route is installed
+/// (and confirmed, and reasserted if overwritten) exactly as in normal mode.
+fn run_external_control(state: &AppState, path: &Path) -> Result<()> {
+    info!("External control mode: reading the desired interface from {:?}, probing disabled", path);
+    let mut active: Option<String> = None;
+    let mut last_error: Option<String> = None;
+    while !state.stop.stop_requested() {
+        match read_desired_interface(path) {
+            Ok(Some(desired)) if state.interface_index(&desired).is_some() => {
+                last_error = None;
+                let routed_via = if state.route_all_traffic {
+                    default_route_info(state.runner(), state.peer_is_ipv6()).ok().flatten().map(|r| r.dev)
+                } else {
+                    get_route_interface(state.runner(), &state.peer_ip.to_string())
+                };
+                if active.as_ref() != Some(&desired) || routed_via.as_ref() != Some(&desired) {
+                    info!("Control file requests {}, installing route", desired);
+                    let event = SwitchEvent {
+                        old_iface: active.clone(),
+                        new_iface: Some(desired.clone()),
+                        reason: format!("Requested by control file {}.", path.display()),
+                        peer: state.peer_ip.to_string(),
+                    };
+                    let gateway = state.route_cache.gateway_for(state.runner(), &desired, state.peer_is_ipv6());
+                    let switching = active.as_ref() != Some(&desired);
+                    if (!switching || state.run_pre_switch_hooks(&event)) && install_route(state, &desired, gateway.as_ref()).is_ok() {
+                        if let Err(reason) = confirm_switch(state, &desired) {
+                            warn!("Switch to {} not confirmed: {}", desired, reason);
+                        }
+                        if switching {
+                            state.notify_switch(&event);
+                        }
+                        active = Some(desired);
+                    }
+                }
+            }
+            Ok(Some(unknown)) => {
+                let message = format!("Control file names unknown interface {:?}, expected one of {}", unknown, state.interfaces.join(", "));
+                if last_error.as_ref() != Some(&message) {
+                    warn!("{}", message);
+                    last_error = Some(message);
+                }
+            }
+            Ok(None) => log_with_timestamp("Control file is empty, leaving routes as they are"),
+            Err(e) => {
+                let message = format!("{:#}, leaving routes as they are", e);
+                if last_error.as_ref() != Some(&message) {
+                    warn!("{}", message);
+                    last_error = Some(message);
+                }
+            }
+        }
+        state.stop.sleep(&*state.clock, state.check_interval, None);
+    }
+    Ok(())
+}
+
+/// --once-then-watch: make the first decision synchronously, so that by the
+/// time startup returns (or the daemon detaches) the route is already set.
+fn commit_initial_route(state: &AppState) -> Option<String> {
+    let metrics = probe_all_interfaces(state);
+    let (target, explanation) = select_interface(state, &metrics);
+    info!("Initial decision: {}", explanation);
+    let target = target?;
+    let gateway = state.route_cache.gateway_for(state.runner(), target, state.peer_is_ipv6());
+    install_route(state, target, gateway.as_ref()).ok()?;
+    if let Err(reason) = confirm_switch(state, target) {
+        warn!("Initial route via {} not confirmed: {}", target, reason);
+    }
+    Some(target.clone())
+}
+
+// The interface the managed route (peer or default) goes through right now
+fn routed_interface(state: &AppState) -> Option<String> {
+    if state.route_all_traffic {
+        default_route_info(state.runner(), state.peer_is_ipv6()).ok().flatten().map(|r| r.dev)
+    } else {
+        get_route_interface(state.runner(), &state.peer_ip.to_string())
+    }
+}
+
+fn save_state(state: &AppState, interface: Option<String>, last_switch: Option<chrono::DateTime<chrono::Utc>>) {
+    if let Some(path) = &state.state_file {
+        PersistedState { interface, last_switch }.save(path);
+    }
+}
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn request_state_dump(_signal: libc::c_int) {
+    DUMP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// SIGTERM/SIGINT end the current cycle and let main() clean up after itself
+pub fn install_shutdown_handler() {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        unsafe { libc::signal(signal, request_shutdown as *const () as libc::sighandler_t) };
+    }
+    // SAFETY: as above
+    unsafe { libc::signal(libc::SIGUSR2, request_state_dump as *const () as libc::sighandler_t) };
+}
+
+/// Returns true once per SIGUSR2 received
+fn take_dump_request() -> bool {
+    DUMP_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Classic double-fork daemonization for init systems without a service
+/// manager: detaches from the terminal, changes to `/` and points the standard
+/// streams at /dev/null. Only the grandchild returns. Must run before any
+/// thread is spawned.
+pub fn daemonize(pid_file: Option<&Path>) -> FailoverResult<()> {
+    // Resolve before changing to / below
+    let pid_file = pid_file.map(std::path::absolute).transpose().map_err(|e| FailoverError::io("resolving the PID file path", e))?;
+
+    // SAFETY: the process is still single-threaded here
+    match unsafe { libc::fork() } {
+        -1 => return Err(FailoverError::io("fork", std::io::Error::last_os_error())),
+        0 => {}
+        _ => std::process::exit(0),
+    }
+    // SAFETY: plain syscall; the child is not a process group leader after fork
+    if unsafe { libc::setsid() } == -1 {
+        return Err(FailoverError::io("setsid", std::io::Error::last_os_error()));
+    }
+    // Second fork: the daemon can never reacquire a controlling terminal
+    match unsafe { libc::fork() } {
+        -1 => return Err(FailoverError::io("fork", std::io::Error::last_os_error())),
+        0 => {}
+        _ => std::process::exit(0),
+    }
+
+    if let Some(path) = &pid_file {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .map_err(|e| FailoverError::io(&format!("writing PID file {}", path.display()), e))?;
+    }
+    std::env::set_current_dir("/").map_err(|e| FailoverError::io("chdir /", e))?;
+    let devnull = std::fs::OpenOptions::new().read(true).write(true).open("/dev/null")
+        .map_err(|e| FailoverError::io("opening /dev/null", e))?;
+    for fd in 0..=2 {
+        // SAFETY: both descriptors are valid; dup2 replaces stdin/stdout/stderr
+        if unsafe { libc::dup2(devnull.as_raw_fd(), fd) } == -1 {
+            return Err(FailoverError::io("dup2", std::io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/// Pins the whole process to `cpus`, e.g. to keep it off the cores that
+/// handle packet forwarding on a small router.
+#[cfg(target_os = "linux")]
+pub fn set_cpu_affinity(cpus: &[usize]) -> FailoverResult<()> {
+    // SAFETY: cpu_set_t is plain data; CPU_ZERO/CPU_SET only write inside it
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(FailoverError::command("sched_setaffinity", format!("CPU {} is out of range", cpu)));
+        }
+        // SAFETY: `cpu` was checked against CPU_SETSIZE, so the bit is inside `set`
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    // SAFETY: `set` is a valid cpu_set_t of the size passed
+    let rc = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if rc != 0 {
+        return Err(FailoverError::io("sched_setaffinity", std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_cpu_affinity(_cpus: &[usize]) -> FailoverResult<()> {
+    Err(FailoverError::UnsupportedOs("CPU affinity".to_string()))
+}
+
+/// Sets the process's nice level. Lowering priority needs no privileges;
+/// negative values need CAP_SYS_NICE.
+pub fn set_nice_level(level: i8) -> FailoverResult<()> {
+    // SAFETY: plain syscall on the current process
+    let rc = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, level as libc::c_int) };
+    if rc != 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(match err.kind() {
+            std::io::ErrorKind::PermissionDenied => FailoverError::InsufficientPermissions(
+                format!("nice level {} needs CAP_SYS_NICE", level)),
+            _ => FailoverError::io("setpriority", err),
+        });
+    }
+    Ok(())
+}
+
+/// Stops a [`FailoverMonitor`] from another thread. Each monitor has its own,
+/// so stopping one leaves the others in the process running; SIGTERM and
+/// SIGINT (see [`install_shutdown_handler`]) still stop them all.
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle {
+    inner: Arc<StopState>,
+}
+
+#[derive(Debug, Default)]
+struct StopState {
+    stop: AtomicBool,
+    running: Mutex<bool>,
+    // Signalled when run() returns
+    changed: Condvar,
+}
+
+// Signals cannot wake a sleep, so sleeps look for them (and for stop()) this often
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl StopHandle {
+    /// Asks the monitor to stop and waits until `run` has restored the routes
+    /// and returned. Returns at once if it is not running. A stopped monitor
+    /// stays stopped; must not be called from the thread running it.
+    pub fn stop(&self) {
+        let running = self.inner.running.lock().unwrap_or_else(|e| e.into_inner());
+        self.inner.stop.store(true, Ordering::SeqCst);
+        drop(self.inner.changed.wait_while(running, |running| *running).unwrap_or_else(|e| e.into_inner()));
+    }
+
+    /// True while `run` is looping (or restoring routes on its way out).
+    pub fn is_running(&self) -> bool {
+        *self.inner.running.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn stop_requested(&self) -> bool {
+        self.inner.stop.load(Ordering::SeqCst) || shutdown_requested()
+    }
+
+    fn set_running(&self, running: bool) {
+        *self.inner.running.lock().unwrap_or_else(|e| e.into_inner()) = running;
+        self.inner.changed.notify_all();
+    }
+
+    // Sleeps on `clock` until `duration` has passed or a stop is requested,
+    // calling `on_dump` as soon as a state dump is requested. Without
+    // `on_dump` the request is left for the main loop's next sleep. Stops and
+    // signals are noticed within STOP_POLL_INTERVAL.
+    fn sleep(&self, clock: &dyn Clock, duration: Duration, mut on_dump: Option<&mut dyn FnMut()>) {
+        let deadline = clock.now() + duration;
+        while !self.stop_requested() {
+            if let Some(on_dump) = on_dump.as_mut().filter(|_| take_dump_request()) {
+                on_dump();
+            }
+            let remaining = deadline.saturating_duration_since(clock.now());
+            if remaining.is_zero() {
+                break;
+            }
+            clock.sleep(remaining.min(STOP_POLL_INTERVAL));
+        }
+    }
+}
+
+// Clears the running flag however run() exits, so stop() never waits forever
+struct RunningGuard<'a>(&'a StopHandle);
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set_running(false);
+    }
+}
+
+fn remove_static_arp_entries(state: &AppState, entries: &[(String, String)]) {
+    for (ip, iface) in entries {
+        match delete_static_arp(state.runner(), ip, iface) {
+            Ok(()) => info!("Removed static ARP entry for {} on {}", ip, iface),
+            Err(e) => warn!("Failed to remove static ARP entry for {}: {}", ip, e),
+        }
+    }
+}
+
+/// The routes for one destination we manage, as found before we changed anything.
+#[derive(Debug)]
+struct RouteSnapshot {
+    dest: String,
+    ipv6: bool,
+    routes: Vec<RouteInfo>,
+}
+
+// Destinations install_route may touch with the current settings
+fn managed_destinations(state: &AppState) -> Vec<(String, bool)> {
+    if state.route_all_traffic {
+        let mut dests = vec![("default".to_string(), state.peer_is_ipv6())];
+        if state.dual_stack() {
+            dests.push(("default".to_string(), !state.peer_is_ipv6()));
+        }
+        dests.extend(state.route_exclusions.iter().map(|e| (e.prefix.clone(), is_ipv6_target(&e.prefix))));
+        dests
+    } else {
+        vec![(state.peer_ip.to_string(), state.peer_is_ipv6())]
+    }
+}
+
+fn snapshot_routes(state: &AppState) -> Vec<RouteSnapshot> {
+    managed_destinations(state).into_iter()
+        .filter_map(|(dest, ipv6)| match routes_for_destination(state.runner(), &dest, ipv6) {
+            Ok(routes) => Some(RouteSnapshot { dest, ipv6, routes }),
+            Err(e) => {
+                warn!("Could not record the routes for {}, they will not be restored on exit: {:#}", dest, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Puts the managed routes back the way they were at startup: routes we added
+/// are removed, routes we replaced are installed again. Destinations that only
+/// became managed later (a new peer from the env file) had no routes before.
+fn restore_routes(state: &AppState, mut snapshots: Vec<RouteSnapshot>) {
+    for (dest, ipv6) in managed_destinations(state) {
+        if !snapshots.iter().any(|s| s.dest == dest && s.ipv6 == ipv6) {
+            snapshots.push(RouteSnapshot { dest, ipv6, routes: Vec::new() });
+        }
+    }
+    for snapshot in &snapshots {
+        let current = match routes_for_destination(state.runner(), &snapshot.dest, snapshot.ipv6) {
+            Ok(current) => current,
+            Err(e) => {
+                warn!("Could not restore the routes for {}: {:#}", snapshot.dest, e);
+                continue;
+            }
+        };
+        for route in current.iter().filter(|r| !snapshot.routes.contains(r)) {
+            match route_change(state.runner(), "del", &snapshot.dest, snapshot.ipv6, route) {
+                Ok(()) => info!("Removed route {} via {}", snapshot.dest, route.dev),
+                Err(e) => warn!("Failed to remove route {} via {}: {:#}", snapshot.dest, route.dev, e),
+            }
+        }
+        for route in snapshot.routes.iter().filter(|r| !current.contains(r)) {
+            match route_change(state.runner(), "replace", &snapshot.dest, snapshot.ipv6, route) {
+                Ok(()) => info!("Restored route {} via {}", snapshot.dest, route.dev),
+                Err(e) => warn!("Failed to restore route {} via {}: {:#}", snapshot.dest, route.dev, e),
+            }
+        }
+    }
+}
+
+/// What one monitoring cycle saw and did.
+#[derive(Debug, Clone)]
+pub struct NetworkStatus {
+    pub cycle: u64,
+    /// Active interface before the cycle
+    pub previous_interface: Option<String>,
+    /// Active interface after the cycle; None while no route is installed
+    pub active_interface: Option<String>,
+    /// A more preferred interface that passes its probes again but is not
+    /// failed back to yet, held back by hysteresis or smart recovery
+    pub recovering_interface: Option<String>,
+    /// Latest measurements of every monitored interface, most preferred first
+    pub interfaces: Vec<(String, InterfaceMetrics)>,
+    /// Why the selected interface was chosen
+    pub explanation: String,
+    /// Interface state changes, in the order they happened
+    pub events: Vec<FailoverEvent>,
+}
+
+impl NetworkStatus {
+    pub fn switched(&self) -> bool {
+        self.previous_interface != self.active_interface
+    }
+
+    pub fn all_failed(&self) -> bool {
+        self.interfaces.iter().all(|(_, m)| m.status == InterfaceStatus::Failed)
+    }
+
+    /// One line with the active interface and every interface's state, with
+    /// ANSI colors if `color` is set; what `--summary` prints each cycle.
+    pub fn summary_line(&self, color: bool) -> String {
+        format_summary(
+            self.cycle,
+            self.active_interface.as_deref(),
+            self.recovering_interface.as_deref(),
+            &self.interfaces.iter().map(|(iface, m)| (iface.as_str(), m)).collect::<Vec<_>>(),
+            self.switched().then_some(self.previous_interface.as_deref()),
+            color,
+        )
+    }
+}
+
+/// The failover daemon without the command line: owns the settings and all
+/// state carried from one cycle to the next. [`tick`](Self::tick) runs a
+/// single probe-and-decide cycle; [`run`](Self::run) loops until shutdown
+/// and then puts the original routes back.
+pub struct FailoverMonitor {
+    state: AppState,
+    cycle: u64,
+    // All per-interface state is indexed like config.interfaces
+    metrics: Vec<InterfaceMetrics>,
+    windows: Vec<StatusWindow>,
+    // Set while an interface is (or was) down and we have not yet failed back to it
+    outages: Vec<bool>,
+    current_active_interface: Option<String>,
+    // A more preferred interface that works again but is not failed back to yet
+    recovering_interface: Option<String>,
+    // With dual-stack test IPs and route_all_traffic, where the default route
+    // of the family the peer does not use goes
+    other_family_interface: Option<String>,
+    last_speed_check: Instant,
+    // When the active interface was first seen failing, until traffic is on a working one again
+    outage_started: Option<Instant>,
+    recovery_stats: RecoveryStats,
+    probe_errors: Vec<ProbeErrors>,
+    last_traceroutes: HashMap<String, Instant>,
+    hysteresis: SwitchHysteresis,
+    capture: Option<RollingCapture>,
+    env_file_modified: Option<SystemTime>,
+    wg_config_modified: Option<SystemTime>,
+    // Taken before the first route change, so shutdown can put everything back
+    original_routes: Option<Vec<RouteSnapshot>>,
+    static_arp_entries: Vec<(String, String)>, // neighbour IP, interface
+    initial_route_committed: bool,
+    // Wall-clock time of the last switch, for the state file
+    last_switch_at: Option<chrono::DateTime<chrono::Utc>>,
+    // current_active_interface came from the state file and its route has not been checked yet
+    restored_unchecked: bool,
+}
+
+impl FailoverMonitor {
+    /// Sets up the monitor without touching the system; nothing is probed or
+    /// changed until the first [`tick`](Self::tick) or [`run`](Self::run).
+    pub fn new(config: FailoverConfig) -> Self {
+        let modified = |path: &Option<PathBuf>| path.as_ref()
+            .and_then(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok());
+        let state = AppState::new(config);
+        // Carry on with the interface chosen before a restart, if it is still configured
+        let restored = state.state_file.as_deref()
+            .and_then(PersistedState::load)
+            .filter(|saved| match saved.interface.as_deref() {
+                Some(iface) if state.interface_index(iface).is_some() => true,
+                Some(iface) => {
+                    info!("Ignoring saved interface {}, it is no longer configured", iface);
+                    false
+                }
+                None => false,
+            })
+            .unwrap_or_default();
+        if let Some(iface) = &restored.interface {
+            info!("Resuming with saved interface {} (last switch {:?})", iface, restored.last_switch.map(|t| t.to_rfc3339()));
+        }
+        let mut monitor = Self {
+            cycle: 0,
+            metrics: vec![InterfaceMetrics::default(); state.interfaces.len()],
+            windows: state.interfaces.iter().map(|_| StatusWindow::new(state.status_window)).collect(),
+            outages: vec![false; state.interfaces.len()],
+            restored_unchecked: restored.interface.is_some(),
+            current_active_interface: restored.interface,
+            recovering_interface: None,
+            other_family_interface: None,
+            last_speed_check: state.clock.now(),
+            outage_started: None,
+            recovery_stats: RecoveryStats::default(),
+            probe_errors: state.interfaces.iter().map(|_| ProbeErrors::default()).collect(),
+            last_traceroutes: HashMap::new(),
+            hysteresis: SwitchHysteresis::new(state.min_hold_time, state.consecutive_better_checks),
+            capture: state.capture.clone().map(RollingCapture::new),
+            env_file_modified: modified(&state.env_file),
+            wg_config_modified: modified(&state.wg_config_file),
+            original_routes: None,
+            static_arp_entries: Vec::new(),
+            initial_route_committed: false,
+            last_switch_at: restored.last_switch,
+            state,
+        };
+        monitor.start_timers();
+        monitor
+    }
+
+    // Instants only mean something to the clock that produced them, so these
+    // start over whenever the clock is replaced
+    fn start_timers(&mut self) {
+        let clock = &self.state.clock;
+        let now = clock.now();
+        // Force a speed check in the first cycle
+        self.last_speed_check = now.checked_sub(self.state.speed_check_interval).unwrap_or(now);
+        self.hysteresis.last_switch = self.last_switch_at.and_then(|t| {
+            let ago = (clock.utc_now() - t).to_std().ok()?; // None if the clock went backwards
+            now.checked_sub(ago)
+        });
+    }
+
+    /// Runs ip, ping and traceroute through `runner` instead of spawning them
+    /// directly, e.g. to drive the monitor against canned output.
+    pub fn with_command_runner(mut self, runner: impl CommandRunner + 'static) -> Self {
+        self.state.runner = Arc::new(runner);
+        self
+    }
+
+    /// Reads the time from `clock` instead of the system clocks, e.g. a
+    /// [`MockClock`](crate::mock::MockClock) that tests move forward. Set it
+    /// before the first [`tick`](Self::tick).
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.state.clock = Arc::new(clock);
+        self.start_timers();
+        self
+    }
+
+    /// Calls `hook` with the old and new interface names (`""` for none)
+    /// before each switch, after the pre-switch script hook. An error aborts
+    /// the switch unless `pre_hook_aborts` is off. Hooks run on the monitor's
+    /// thread in the order they were added.
+    pub fn with_pre_failover_hook(mut self, hook: impl Fn(&str, &str) -> FailoverResult<()> + Send + Sync + 'static) -> Self {
+        self.state.pre_failover_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Calls `hook` with the old and new interface names after each change of
+    /// the active interface, once the post-switch script hook and webhook have
+    /// been started. Errors are logged.
+    pub fn with_post_failover_hook(mut self, hook: impl Fn(&str, &str) -> FailoverResult<()> + Send + Sync + 'static) -> Self {
+        self.state.post_failover_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Calls `hook` with the outcome of every cycle, e.g. to print
+    /// [`NetworkStatus::summary_line`]. Runs on the monitor's thread at the
+    /// end of the cycle.
+    pub fn with_cycle_hook(mut self, hook: impl Fn(&NetworkStatus) + Send + Sync + 'static) -> Self {
+        self.state.cycle_hooks.push(Arc::new(hook));
+        self
+    }
+
+    pub fn config(&self) -> &FailoverConfig {
+        &self.state.config
+    }
+
+    /// A handle for stopping [`run`](Self::run) from another thread.
+    pub fn stop_handle(&self) -> StopHandle {
+        self.state.stop.clone()
+    }
+
+    /// Asks `run` to stop and waits until it has; see [`StopHandle::stop`].
+    pub fn stop(&self) {
+        self.state.stop.stop();
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.stop.is_running()
+    }
+
+    /// The interface the managed route currently goes through, if any.
+    pub fn active_interface(&self) -> Option<&str> {
+        self.current_active_interface.as_deref()
+    }
+
+    /// Probes every interface once and returns a report of why the daemon
+    /// would pick the interface it picks.
+    pub fn explain_decision(&self) -> Result<String> {
+        explain_decision(&self.state)
+    }
+
+    /// Probes every interface once and returns a report comparing the route
+    /// the daemon would install with the routing table.
+    pub fn audit_routes(&self) -> Result<String> {
+        audit_routes(&self.state)
+    }
+
+    /// For --once-then-watch: makes the first decision synchronously, so the
+    /// route is already set when this returns. Returns the chosen interface.
+    pub fn commit_initial_route(&mut self) -> Option<String> {
+        self.prepare();
+        self.wait_for_startup_delay();
+        let committed = commit_initial_route(&self.state);
+        if committed.is_some() && committed != self.current_active_interface {
+            self.last_switch_at = Some(self.state.clock.utc_now());
+        }
+        self.current_active_interface = committed;
+        self.initial_route_committed = true;
+        self.restored_unchecked = false;
+        info!("Initial route committed via {:?}", self.current_active_interface);
+        save_state(&self.state, self.current_active_interface.clone(), self.last_switch_at);
+        self.current_active_interface.clone()
+    }
+
+    // Pins the static ARP entries and records the routes we are about to
+    // manage; does nothing the second time
+    fn prepare(&mut self) {
+        if self.original_routes.is_some() {
+            return;
+        }
+        // Pin gateway MACs on links where ARP to the gateway is unreliable (some LTE modems)
+        for (ip, mac) in &self.state.static_arp {
+            let Some(iface) = get_route_interface(self.state.runner(), ip) else {
+                warn!("No route to static_arp neighbour {}, not installing its entry", ip);
+                continue;
+            };
+            match add_static_arp(self.state.runner(), ip, mac, &iface) {
+                Ok(()) => {
+                    info!("Static ARP entry {} -> {} installed on {}", ip, mac, iface);
+                    self.static_arp_entries.push((ip.clone(), iface));
+                }
+                Err(e) => warn!("Failed to install static ARP entry for {}: {}", ip, e),
+            }
+        }
+        let original_routes = snapshot_routes(&self.state);
+        log_with_timestamp(&format!("Routes before startup: {:?}", original_routes));
+        self.original_routes = Some(original_routes);
+    }
+
+    fn wait_for_startup_delay(&self) {
+        if !self.state.startup_delay.is_zero() {
+            info!("Waiting {}s for interfaces to initialize...", self.state.startup_delay.as_secs());
+            self.state.stop.sleep(&*self.state.clock, self.state.startup_delay, None);
+        }
+    }
+
+    /// Monitors until [`stop`](StopHandle::stop), SIGTERM or SIGINT (see
+    /// [`install_shutdown_handler`]), then restores the routes found at startup and removes the static ARP
+    /// entries. With an external control file, applies the interface named
+    /// there instead of probing.
+    pub fn run(mut self) -> Result<()> {
+        let stop = self.state.stop.clone();
+        stop.set_running(true);
+        let _running = RunningGuard(&stop);
+        self.prepare();
+        match detect_icmp_capability(self.state.runner()) {
+            IcmpCapability::RawSocket => log_with_timestamp("ICMP capability: raw sockets"),
+            IcmpCapability::SuidPing(path) => log_with_timestamp(&format!("ICMP capability: setuid ping at {:?}", path)),
+            IcmpCapability::UnprivilegedIcmp => {
+                log_with_timestamp("ICMP capability: unprivileged ICMP sockets");
+                warn!("Binding pings to an interface without CAP_NET_RAW needs Linux 5.7 or newer");
+            }
+            IcmpCapability::None => error!(
+                "No way to send ICMP echo requests: not root, no CAP_NET_RAW, ping is not setuid and \
+                 unprivileged ICMP is disabled (net.ipv4.ping_group_range). Every probe will fail."),
+        }
+
+        if let Some(path) = self.state.external_control_file.clone() {
+            let result = run_external_control(&self.state, &path);
+            self.shutdown();
+            return result;
+        }
+
+        log_with_timestamp("Starting WireGuard Failover (Multiple IP Test Mode)");
+        info!("Peer: {}", self.state.peer_ip);
+        info!("Interfaces by priority: {}", self.state.interfaces.join(", "));
+        info!("Test IPs: {:?}", self.state.test_ips);
+        info!("Route all traffic: {}", self.state.route_all_traffic);
+        info!("Intervals - Check: {:?}, Speed: {:?}", self.state.check_interval, self.state.speed_check_interval);
+
+        if let Some(capture) = &mut self.capture {
+            capture.follow(self.state.runner(), self.current_active_interface.as_deref());
+        }
+        if !self.initial_route_committed {
+            self.wait_for_startup_delay();
+        }
+        log_with_timestamp("Initialization complete, entering main loop");
+
+        while !stop.stop_requested() {
+            let cycle_started = self.state.clock.now();
+            self.tick();
+
+            let mut sleep_for = jittered_interval(self.state.check_interval, self.state.interval_jitter_pct, RandomState::new().build_hasher().finish());
+            if self.state.spread_probes {
+                // The spread-out probes already used part of the interval
+                sleep_for = sleep_for.saturating_sub(self.state.clock.now().saturating_duration_since(cycle_started));
+            }
+            if self.metrics.iter().any(|m| m.state.is_recovering()) {
+                sleep_for = sleep_for.min(RECOVERY_PROBE_INTERVAL);
+            }
+            log_with_timestamp(&format!("Sleeping for {:?} before next iteration", sleep_for));
+            stop.sleep(&*self.state.clock, sleep_for, Some(&mut || {
+                write_state_dump(&self.snapshot().to_json(), self.state.state_dump_file.as_deref());
+            }));
+            log_with_timestamp("Awake from sleep, starting next loop iteration");
+        }
+
+        self.shutdown();
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        info!("Shutting down");
+        if let Some(original_routes) = self.original_routes.take() {
+            restore_routes(&self.state, original_routes);
+        }
+        remove_static_arp_entries(&self.state, &self.static_arp_entries);
+        self.static_arp_entries.clear();
+    }
+
+    fn snapshot(&self) -> MonitorSnapshot<'_> {
+        MonitorSnapshot {
+            state: &self.state,
+            cycle: self.cycle,
+            current_active_interface: self.current_active_interface.as_deref(),
+            recovering_interface: self.recovering_interface.as_deref(),
+            metrics: &self.metrics,
+            windows: &self.windows,
+            outages: &self.outages,
+            outage_started: self.outage_started,
+            last_speed_check: self.last_speed_check,
+            last_traceroutes: &self.last_traceroutes,
+            recovery_stats: &self.recovery_stats,
+            capture_file: self.capture.as_ref().and_then(RollingCapture::current_file),
+        }
+    }
+
+    // Applies peer and interface changes from the watched env file and
+    // WireGuard config; new settings invalidate the measurements
+    fn reload_watched_files(&mut self) {
+        if let Some(env_file) = self.state.env_file.clone() {
+            let modified = std::fs::metadata(&env_file).and_then(|m| m.modified()).ok();
+            if modified.is_some() && modified != self.env_file_modified {
+                self.env_file_modified = modified;
+                match EnvOverrides::from_env_file(&self.state.env_prefix, &env_file) {
+                    Ok(overrides) => {
+                        let previous = self.state.interfaces.clone();
+                        if self.state.apply_runtime_overrides(overrides) {
+                            self.follow_interface_list(&previous);
+                            self.metrics.iter_mut().for_each(InterfaceMetrics::reset);
+                        }
+                    }
+                    Err(e) => warn!("Ignoring changed env file {:?}: {}", env_file, e),
+                }
+            }
+        }
+        if let Some(wg_config) = self.state.wg_config_file.clone() {
+            let modified = std::fs::metadata(&wg_config).and_then(|m| m.modified()).ok();
+            if modified.is_some() && modified != self.wg_config_modified {
+                self.wg_config_modified = modified;
+                match peer_from_wg_config(&wg_config) {
+                    Ok(endpoint) => {
+                        let overrides = EnvOverrides { peer_ip: Some(endpoint.ip().to_string()), ..Default::default() };
+                        if self.state.apply_runtime_overrides(overrides) {
+                            self.metrics.iter_mut().for_each(InterfaceMetrics::reset);
+                        }
+                    }
+                    Err(e) => warn!("Ignoring changed WireGuard config {:?}: {}", wg_config, e),
+                }
+            }
+        }
+    }
+
+    // Rearranges the per-interface state to match a changed interface list,
+    // keeping what is known about interfaces that are still in it
+    fn follow_interface_list(&mut self, previous: &[String]) {
+        fn remap<T>(values: &mut Vec<T>, previous: &[String], current: &[String], fresh: impl Fn() -> T) {
+            let mut old: Vec<Option<T>> = values.drain(..).map(Some).collect();
+            values.extend(current.iter().map(|iface| {
+                previous.iter().position(|p| p == iface).and_then(|i| old[i].take()).unwrap_or_else(&fresh)
+            }));
+        }
+        let current = self.state.interfaces.clone();
+        let status_window = self.state.status_window;
+        remap(&mut self.metrics, previous, &current, InterfaceMetrics::default);
+        remap(&mut self.windows, previous, &current, || StatusWindow::new(status_window));
+        remap(&mut self.outages, previous, &current, || false);
+        remap(&mut self.probe_errors, previous, &current, ProbeErrors::default);
+    }
+
+    /// Runs one cycle: probes the interfaces, runs the speed check when it is
+    /// due, picks an interface and moves the route to it if needed.
+    pub fn tick(&mut self) -> NetworkStatus {
+        self.cycle += 1;
+        let cycle = self.cycle;
+        let active_at_start = self.current_active_interface.clone();
+        log_with_timestamp("Starting main loop iteration");
+        self.reload_watched_files();
+
+        let Self {
+            state,
+            metrics,
+            windows,
+            outages,
+            current_active_interface,
+            recovering_interface,
+            other_family_interface,
+            last_speed_check,
+            outage_started,
+            recovery_stats,
+            probe_errors,
+            last_traceroutes,
+            hysteresis,
+            capture,
+            last_switch_at,
+            restored_unchecked,
+            ..
+        } = self;
+        let state = &*state;
+        let now = state.clock.now();
+        log_with_timestamp(&format!("Current time instant: {:?}", now));
+        let mut events = Vec::new();
+        
+        // A new address (DHCP lease, swapped cable) means the measurements
+        // and the cached gateway describe a link that no longer exists
+        for ((iface, m), window) in state.interfaces.iter().zip(metrics.iter_mut()).zip(windows.iter_mut()) {
+            match get_interface_addresses(state.runner(), iface) {
+                Ok(addresses) => {
+                    if m.update_addresses(&addresses) {
+                        info!("Interface {} address changed, resetting metrics", iface);
+                        m.reset();
+                        transition(iface, m, &mut events, InterfaceState::reset);
+                        window.clear();
+                        state.route_cache.invalidate();
+                    }
+                }
+                Err(e) => debug!("Could not read addresses of {}: {}", iface, e),
+            }
+        }
+
+        // ----------------------------------------
+        // 1. Identify Gateways (Dynamic, in case of network changes)
+        // ----------------------------------------
+        log_with_timestamp("Identifying gateways for interfaces");
+        let gateways: Vec<Option<String>> = state.interfaces.iter().map(|iface| state.route_cache.gateway_for(state.runner(), iface, state.peer_is_ipv6())).collect();
+        let (cache_hits, cache_misses) = state.route_cache.stats();
+        log_with_timestamp(&format!("Route cache: {} hits, {} misses", cache_hits, cache_misses));
+        for (index, gateway) in gateways.iter().enumerate() {
+            log_with_timestamp(&format!("{} gateway: {:?}", interface_role(index), gateway));
+        }
+
+        // ----------------------------------------
+        // 2. Connectivity Check (Frequent) - Multiple IPs
+        // ----------------------------------------
+        log_with_timestamp("Starting connectivity checks with multiple IPs");
+        let active = current_active_interface.clone();
+        let active_index = active.as_deref().and_then(|a| state.interface_index(a));
+        let standby_due = cycle.is_multiple_of(state.standby_probe_cycles.max(1) as u64);
+        let probe_all = state.probe_strategy == ProbeStrategy::All || active_index.is_none() || standby_due;
+        log_with_timestamp(&format!("Probe strategy {:?}, active {:?}, probing all: {}", state.probe_strategy, active, probe_all));
+
+        let mut results: Vec<Option<_>> = state.interfaces.iter().map(|_| None).collect();
+        for (index, iface) in state.interfaces.iter().enumerate() {
+            if probe_all || active_index == Some(index) || metrics[index].state.is_recovering() {
+                log_with_timestamp(&format!("Checking connectivity via {} interface: {}", interface_role(index).to_lowercase(), iface));
+                results[index] = Some(probe_connectivity(state, iface));
+            }
+        }
+        // Active-first: the active link is in trouble, so check the standbys now rather than waiting
+        if let Some(active_index) = active_index.filter(|&i| !probe_all && results[i].as_ref().is_some_and(|r| !r.ok)) {
+            let standby: Vec<&str> = state.interfaces.iter().enumerate()
+                .filter(|&(i, _)| i != active_index)
+                .map(|(_, iface)| iface.as_str())
+                .collect();
+            info!("Active interface {} failed, probing standby {}", state.interfaces[active_index], standby.join(", "));
+            for (index, iface) in state.interfaces.iter().enumerate() {
+                if results[index].is_none() {
+                    results[index] = Some(probe_connectivity(state, iface));
+                }
+            }
+        }
+
+        log_with_timestamp("Updating metrics based on connectivity results");
+        // Only cycles in which the peer was actually probed count towards excluding it
+        let peer_results: Vec<bool> = results.iter()
+            .flatten()
+            .filter_map(|outcome| outcome.test_results.iter().find(|(ip, _)| state.is_peer(ip)).map(|(_, ok)| *ok))
+            .collect();
+        if !peer_results.is_empty() {
+            state.peer_cache.lock().unwrap_or_else(|e| e.into_inner())
+                .record_cycle(peer_results.contains(&true), now);
+        }
+
+        for (index, result) in results.into_iter().enumerate() {
+            let iface = &state.interfaces[index];
+            let role = interface_role(index);
+            let m = &mut metrics[index];
+            let Some(outcome) = result else {
+                log_with_timestamp(&format!("Skipped probing standby {} {}, keeping status {:?}", role.to_lowercase(), iface, m.status));
+                continue;
+            };
+            info!("{} {}: success={}, average latency={:.1}ms, packet loss={:.0}%",
+                  role, iface, outcome.ok, outcome.latency_ms, outcome.packet_loss_pct);
+            // Errors that clear up by themselves are counted; the others need someone to act
+            match (&outcome.error, probe_errors[index].record(outcome.error.as_ref())) {
+                (Some(e), Some(severity)) => {
+                    error!("Probing {} failed with a {} error: {}", iface, severity, e);
+                    state.switch_hooks.alert(&ErrorAlert {
+                        iface: iface.clone(),
+                        severity,
+                        message: e.to_string(),
+                        peer: state.peer_ip.to_string(),
+                    });
+                }
+                (Some(e), None) if e.is_recoverable() => {
+                    warn!("Probing {} hit a {} error ({} so far): {}", iface, e.severity(), probe_errors[index].count, e);
+                }
+                _ => {}
+            }
+            let ok = windows[index].record(outcome.ok);
+            if !ok {
+                outages[index] = true;
+            }
+            if m.status == InterfaceStatus::Working && !ok {
+                maybe_traceroute(state, iface, last_traceroutes);
+            }
+            if ok {
+                transition(iface, m, &mut events, |s| s.probe_succeeded(now));
+            } else {
+                let reason = match &outcome.error {
+                    Some(e) => e.to_string(),
+                    None if outcome.ok => "still failing most recent probes".to_string(),
+                    None => format!("{:.0}% packet loss", outcome.packet_loss_pct),
+                };
+                transition(iface, m, &mut events, |s| s.probe_failed(now, reason));
+            }
+            m.connectivity_latency_ms = outcome.latency_ms;
+            m.packet_loss_pct = outcome.packet_loss_pct;
+            m.working_v4 = family_working(&outcome.test_results, false);
+            m.working_v6 = family_working(&outcome.test_results, true);
+            m.test_results = outcome.test_results;
+            m.gateway_probe_ms = outcome.gateway_ms.unwrap_or(0.0);
+            log_with_timestamp(&format!("{} metrics updated: status={:?}, latency={:.1}ms, loss={:.0}%, gateway={:.1}ms", role, m.status, m.connectivity_latency_ms, m.packet_loss_pct, m.gateway_probe_ms));
+        }
+
+        // Log detailed test results
+        for ip in &state.test_ips {
+            let reachable: Vec<String> = state.interfaces.iter().zip(metrics.iter())
+                .map(|(iface, m)| format!("{}={}", iface, m.test_results.get(ip).unwrap_or(&false)))
+                .collect();
+            debug!("IP {}: {}", ip, reachable.join(", "));
+        }
+
+        // ----------------------------------------
+        // 3. Speed Check (Periodic)
+        // ----------------------------------------
+        // Only if several are working, we might want to run a heavier test to decide best path.
+        log_with_timestamp("Checking if speed test is due");
+        let time_since_last_speed_check = now.duration_since(*last_speed_check);
+        log_with_timestamp(&format!("Time since last speed check: {:?}, required interval: {:?}", time_since_last_speed_check, state.speed_check_interval));
+        
+        if now.duration_since(*last_speed_check) >= state.speed_check_interval {
+            log_with_timestamp("Speed check interval reached, performing speed/quality check...");
+            if working_count(metrics) > 1 {
+                log_with_timestamp("Several interfaces working, running detailed latency measurements");
+                // Run heavier ping to peer IP for speed comparison
+                measure_peer_latency(state, metrics);
+                let measured: Vec<String> = state.interfaces.iter().zip(metrics.iter())
+                    .filter(|(_, m)| m.status == InterfaceStatus::Working)
+                    .map(|(iface, m)| format!("{}: {:.1}ms", iface, m.speed_latency_ms))
+                    .collect();
+                if metrics.iter().any(|m| m.status == InterfaceStatus::Working && m.speed_latency_ms > 0.0) {
+                    state.peer_cache.lock().unwrap_or_else(|e| e.into_inner()).record_success();
+                }
+                
+                info!("Speed/Latency Result - {}", measured.join(", "));
+                log_with_timestamp("Speed metrics updated successfully");
+            } else {
+                log_with_timestamp("Skipping speed test because fewer than two interfaces are working");
+            }
+            if state.bandwidth_check_enabled {
+                for (iface, metrics) in state.interfaces.iter().zip(metrics.iter_mut()) {
+                    if metrics.status != InterfaceStatus::Working {
+                        continue;
+                    }
+                    match bandwidth_probe(state.runner(), state.probe_source(iface), &state.peer_ip.to_string(), 5, state.speed_netns.as_deref()) {
+                        Ok(estimate) => {
+                            info!("Bandwidth estimate - {}: {:.2} Mbps ({:.1}ms)", iface, estimate.throughput_mbps, estimate.latency_ms);
+                            metrics.estimated_bandwidth_mbps = Some(estimate.throughput_mbps);
+                        }
+                        Err(e) => {
+                            warn!("Bandwidth probe via {} failed: {}", iface, e);
+                            metrics.estimated_bandwidth_mbps = None;
+                        }
+                    }
+                }
+            }
+            *last_speed_check = now;
+            log_with_timestamp(&format!("Last speed check time updated to: {:?}", last_speed_check));
+        } else {
+            log_with_timestamp("Speed check not due yet, skipping");
+        }
+
+        // The last bandwidth estimate holds until the next speed check
+        if let Some(min) = state.min_bandwidth_mbps.filter(|_| state.bandwidth_check_enabled) {
+            for (iface, metrics) in state.interfaces.iter().zip(metrics.iter_mut()) {
+                if let Some(mbps) = metrics.estimated_bandwidth_mbps.filter(|mbps| *mbps < min) {
+                    if metrics.status == InterfaceStatus::Working {
+                        warn!("Interface {} degraded: estimated bandwidth {:.2} Mbps below minimum {:.2} Mbps", iface, mbps, min);
+                        metrics.status = InterfaceStatus::Failed;
+                    }
+                }
+            }
+        }
+
+        // ----------------------------------------
+        // 4. Decision Logic
+        // ----------------------------------------
+        log_with_timestamp("Starting decision logic for interface selection");
+        update_health_scores(state, metrics);
+        let (mut target_name, mut explanation) = select_interface(state, metrics);
+        if let (Some(tie_break), Some(chosen)) = (state.tie_break, target_name.and_then(|name| state.interface_index(name))) {
+            let turn = match tie_break {
+                TieBreak::RoundRobin => cycle,
+                TieBreak::Weighted => RandomState::new().build_hasher().finish(),
+            };
+            let pick = break_tie(metrics, chosen, tie_break, state.tie_break_epsilon_ms, turn);
+            if pick != chosen {
+                explanation.push_str(&format!(" {} is as fast (within {}ms), {:?} tie-break: use {}.",
+                    state.interfaces[pick], state.tie_break_epsilon_ms, tie_break, interface_role(pick)));
+                target_name = Some(&state.interfaces[pick]);
+            }
+        }
+        log_with_timestamp(&format!("Decision: {}", explanation));
+        let all_failed = metrics.iter().all(|m| m.status == InterfaceStatus::Failed);
+        let target_index = target_name.and_then(|name| state.interface_index(name));
+        if all_failed {
+            warn!("All interfaces failed connectivity check.");
+        } else if target_index.is_some_and(|t| metrics[..t].iter().any(|m| m.status == InterfaceStatus::Working)) {
+            // A less preferred interface won on speed
+            info!("{}", explanation);
+        }
+        let target_interface = target_index.map(|i| (&state.interfaces[i], &gateways[i]));
+        log_with_timestamp(&format!("Decision result: target_interface = {:?}", target_interface));
+
+        // ----------------------------------------
+        // 5. Apply Route Change
+        // ----------------------------------------
+        log_with_timestamp("Evaluating route changes");
+        let status_of = |iface: &String| {
+            state.interface_index(iface).map_or(InterfaceStatus::Unknown, |i| metrics[i].status.clone())
+        };
+        // The route via an interface restored from the state file may be gone (a
+        // clean shutdown puts the startup routes back) and the interface may have
+        // failed since; keep it only if it works, without touching a route in place
+        if *restored_unchecked && cycle > state.startup_probes as u64 {
+            *restored_unchecked = false;
+            if let Some(restored) = current_active_interface.clone() {
+                if status_of(&restored) != InterfaceStatus::Working {
+                    info!("Saved interface {} is not working, choosing afresh", restored);
+                    *current_active_interface = None;
+                } else if routed_interface(state).as_deref() == Some(restored.as_str()) {
+                    info!("Route via saved interface {} still in place", restored);
+                } else {
+                    let gateway = state.interface_index(&restored).and_then(|i| gateways[i].as_ref());
+                    if install_route(state, &restored, gateway).is_err() {
+                        *current_active_interface = None;
+                    }
+                }
+            }
+        }
+        let previous_active = current_active_interface.clone();
+        if previous_active.as_ref().map(status_of) == Some(InterfaceStatus::Failed) && outage_started.is_none() {
+            debug!("Active interface {:?} failed, starting recovery timer", previous_active);
+            *outage_started = Some(now);
+        }
+
+        // Why a failback to a working interface is held back, if it is
+        let mut failback_held: Option<(String, String)> = None;
+        if cycle <= state.startup_probes as u64 {
+            info!("Startup probe cycle {}/{} complete, not changing routes yet", cycle, state.startup_probes);
+        } else if let Some((target_iface, target_gw)) = target_interface {
+            log_with_timestamp(&format!("Target interface selected: {}, gateway: {:?}", target_iface, target_gw));
+            let mut should_update = match &current_active_interface {
+                Some(current) => {
+                    let update_needed = current != target_iface;
+                    log_with_timestamp(&format!("Current active interface: {}, update needed: {}", current, update_needed));
+                    update_needed // Changed interface
+                },
+                None => {
+                    log_with_timestamp("No current active interface (first run), update needed");
+                    true // First run
+                },
+            };
+
+            if !should_update && current_active_interface.is_some() {
+                // Make sure nobody (e.g. NetworkManager) replaced the route we installed
+                if let Some(actual) = routed_interface(state).filter(|actual| actual != target_iface) {
+                    if state.reassert_routes {
+                        warn!("Route was overwritten by another process (now via {}, expected {}), reasserting", actual, target_iface);
+                        should_update = true;
+                    } else {
+                        warn!("Route was overwritten by another process (now via {}, expected {})", actual, target_iface);
+                    }
+                }
+            }
+
+            // Moving back to a more preferred interface than the current one
+            let current_index = current_active_interface.as_deref().and_then(|c| state.interface_index(c));
+            let target_index = state.interface_index(target_iface);
+            let is_failback = matches!((current_index, target_index), (Some(c), Some(t)) if t < c);
+            let is_switch = current_active_interface.as_ref().is_some_and(|current| current != target_iface);
+            // Only leaving a still-working interface is a matter of preference
+            let discretionary = is_switch && current_active_interface.as_ref().map(status_of) == Some(InterfaceStatus::Working);
+            hysteresis.observe(discretionary.then_some(target_iface.as_str()));
+            if should_update && discretionary {
+                if let Some(reason) = hysteresis.suppression(target_iface, now) {
+                    info!("Switch from {} to {} suppressed by hysteresis: {}",
+                          current_active_interface.as_deref().unwrap_or("?"), target_iface, reason);
+                    should_update = false;
+                    if is_failback {
+                        failback_held = Some((target_iface.clone(), reason));
+                    }
+                }
+            }
+            if should_update && is_switch {
+                if let Some(window) = state.inhibiting_maintenance_window(is_failback) {
+                    info!("Failover inhibited: in maintenance window until {}",
+                          chrono::DateTime::<chrono::Local>::from(window.end).format("%Y-%m-%d %H:%M:%S"));
+                    should_update = false;
+                }
+            }
+
+            if should_update && is_failback && target_index.is_some_and(|t| outages[t]) && state.smart_recovery {
+                info!("{} recovered, verifying stability before failback", target_iface);
+                if verify_recovery(state, target_iface) {
+                    info!("Recovery verified, failing back to {}", target_iface);
+                } else {
+                    warn!("Recovery of {} not stable, staying on {}", target_iface, current_active_interface.as_deref().unwrap_or("?"));
+                    should_update = false;
+                    failback_held = Some((target_iface.clone(), "recovery not yet verified".to_string()));
+                }
+            }
+
+            if let (true, true, Some(max_wait), Some(current)) = (should_update, is_switch, state.connection_drain_wait, &current_active_interface) {
+                match wait_for_connection_drain(state.runner(), &*state.clock, current, max_wait) {
+                    Ok(0) => log_with_timestamp(&format!("No active connections via {}", current)),
+                    Ok(remaining) => warn!("Switching away from {} with {} connections still active, they will be cut", current, remaining),
+                    Err(e) => warn!("Could not count active connections via {}: {}", current, e),
+                }
+            }
+
+            if should_update {
+                // Try the selected interface first; with a confirmation target, fall back
+                // to the other working interfaces, by priority, if the target is unreachable
+                // after the switch
+                let confirm_switches = state.post_switch_confirm_target.is_some() || state.post_switch_mtu_check.is_some();
+                let mut candidates = vec![(target_iface, target_gw)];
+                if confirm_switches || state.wg_endpoint_check.is_some() {
+                    candidates.extend(state.interfaces.iter().zip(&gateways)
+                        .filter(|(iface, _)| *iface != target_iface && status_of(iface) == InterfaceStatus::Working));
+                }
+                let candidate_count = candidates.len();
+                for (attempt, (iface, gw)) in candidates.into_iter().enumerate() {
+                    if let Some((endpoint, timeout)) = state.wg_endpoint_check {
+                        match probe_wg_endpoint(state.runner(), iface, endpoint, timeout) {
+                            Ok(true) => log_with_timestamp(&format!("WireGuard endpoint {} reachable via {}", endpoint, iface)),
+                            Ok(false) => {
+                                warn!("WireGuard endpoint {} not reachable via {}, not switching to it", endpoint, iface);
+                                continue;
+                            }
+                            Err(e) => {
+                                warn!("WireGuard endpoint check via {} failed: {}, not switching to it", iface, e);
+                                continue;
+                            }
+                        }
+                    }
+                    let event = SwitchEvent {
+                        old_iface: current_active_interface.clone(),
+                        new_iface: Some(iface.clone()),
+                        reason: explanation.clone(),
+                        peer: state.peer_ip.to_string(),
+                    };
+                    let switching = current_active_interface.as_ref() != Some(iface);
+                    if (switching && !state.run_pre_switch_hooks(&event)) || install_route(state, iface, gw.as_ref()).is_err() {
+                        break;
+                    }
+                    *current_active_interface = Some(iface.clone());
+                    if !confirm_switches {
+                        break;
+                    }
+                    match confirm_switch(state, iface) {
+                        Ok(()) => {
+                            log_with_timestamp(&format!("Switch to {} confirmed", iface));
+                            break;
+                        }
+                        Err(reason) if attempt + 1 < candidate_count => {
+                            warn!("Switch to {} not confirmed: {}, trying next candidate", iface, reason);
+                        }
+                        Err(reason) => {
+                            warn!("Switch to {} not confirmed: {}, no candidates left", iface, reason);
+                        }
+                    }
+                }
+                if state.send_gratuitous_arp {
+                    if let Some(iface) = current_active_interface.as_ref().filter(|i| active_at_start.as_ref() != Some(*i)) {
+                        announce_addresses(state.runner(), iface);
+                    }
+                }
+            } else {
+                log_with_timestamp("No route change needed, interface unchanged");
+            }
+        } else if all_failed
+            && state.removes_route_when_all_failed()
+            && cycle > state.startup_probes as u64
+        {
+            // Fail fast instead of sending WireGuard traffic down a dead path; the
+            // route is installed again as on first run once an interface recovers
+            if current_active_interface.is_some() {
+                warn!("All interfaces failed, removing route for peer {}", state.peer_ip);
+                match delete_route(state.runner(), &state.peer_ip.to_string(), None) {
+                    Ok(()) => *current_active_interface = None,
+                    Err(e) => error!("Failed to remove peer route: {}", e),
+                }
+            }
+        } else {
+            log_with_timestamp("No target interface selected, skipping route update");
+        }
+
+        // The other family follows the active interface while it works there,
+        // and otherwise goes to the most preferred interface where it does
+        if state.route_all_traffic && state.dual_stack() && cycle > state.startup_probes as u64 {
+            let ipv6 = !state.peer_is_ipv6();
+            let works = |i: &usize| metrics[*i].working_for(ipv6) == Some(true);
+            let target = current_active_interface.as_deref().and_then(|c| state.interface_index(c)).filter(works)
+                .or_else(|| (0..metrics.len()).find(works))
+                .map(|i| &state.interfaces[i])
+                .filter(|target| other_family_interface.as_ref() != Some(*target));
+            if let Some(target) = target {
+                let (family, peer_family) = if ipv6 { ("IPv6", "IPv4") } else { ("IPv4", "IPv6") };
+                let gateway = state.route_cache.gateway_for(state.runner(), target, ipv6);
+                match update_default_route(state.runner(), target, gateway.as_ref(), ipv6) {
+                    Ok(()) => {
+                        match current_active_interface.as_ref().filter(|active| *active != target) {
+                            Some(active) => info!("{} default route via {}, {} stays on {}", family, target, peer_family, active),
+                            None => info!("{} default route via {}", family, target),
+                        }
+                        *other_family_interface = Some(target.clone());
+                    }
+                    Err(e) => warn!("Failed to move the {} default route to {}: {}", family, target, e),
+                }
+            }
+        }
+
+        if let Some(index) = current_active_interface.as_deref().and_then(|c| state.interface_index(c)) {
+            if metrics[index].status == InterfaceStatus::Working {
+                outages[index] = false;
+            }
+        }
+
+        if let Some(started) = *outage_started {
+            if current_active_interface.as_ref().map(status_of) == Some(InterfaceStatus::Working) {
+                if *current_active_interface != previous_active {
+                    let time_to_recover = state.clock.now().saturating_duration_since(started);
+                    recovery_stats.record(time_to_recover);
+                    info!("Recovered via {} in {:.1}s (min {:.1}s, avg {:.1}s, max {:.1}s over {} failovers)",
+                          current_active_interface.as_deref().unwrap_or("?"),
+                          time_to_recover.as_secs_f64(),
+                          recovery_stats.min.unwrap_or_default().as_secs_f64(),
+                          recovery_stats.average().unwrap_or_default().as_secs_f64(),
+                          recovery_stats.max.unwrap_or_default().as_secs_f64(),
+                          recovery_stats.count);
+                } else {
+                    debug!("Active interface recovered on its own, no failover needed");
+                }
+                *outage_started = None;
+            }
+        }
+
+        if *current_active_interface != previous_active {
+            if current_active_interface.is_some() {
+                hysteresis.record_switch(now);
+                *last_switch_at = Some(state.clock.utc_now());
+            }
+            save_state(state, current_active_interface.clone(), *last_switch_at);
+            let mut reason = explanation.clone();
+            if let Some(selected) = target_name.filter(|t| current_active_interface.as_deref().is_some_and(|c| c != *t)) {
+                reason.push_str(&format!(" Switch to {} not confirmed.", selected));
+            }
+            state.notify_switch(&SwitchEvent {
+                old_iface: previous_active.clone(),
+                new_iface: current_active_interface.clone(),
+                reason,
+                peer: state.peer_ip.to_string(),
+            });
+        }
+
+        if failback_held.as_ref().map(|(iface, _)| iface) != recovering_interface.as_ref() {
+            if let Some((iface, reason)) = &failback_held {
+                info!("{} is recovering ({}), staying on {} for now", iface, reason, current_active_interface.as_deref().unwrap_or("?"));
+                state.switch_hooks.notify_recovering(&RecoveryNotice {
+                    iface: iface.clone(),
+                    active_iface: current_active_interface.clone(),
+                    reason: reason.clone(),
+                    peer: state.peer_ip.to_string(),
+                });
+            }
+            *recovering_interface = failback_held.map(|(iface, _)| iface);
+        }
+
+        if let Some(capture) = capture {
+            capture.follow(state.runner(), current_active_interface.as_deref());
+        }
+
+        let status = NetworkStatus {
+            cycle,
+            previous_interface: active_at_start,
+            active_interface: current_active_interface.clone(),
+            recovering_interface: recovering_interface.clone(),
+            interfaces: state.interfaces.iter().cloned().zip(metrics.iter().cloned()).collect(),
+            explanation,
+            events,
+        };
+        for hook in &state.cycle_hooks {
+            hook(&status);
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_window_fails_on_majority_of_recent_results() {
+        let mut window = StatusWindow::new(3);
+        assert!(window.record(true));
+        assert!(window.record(false)); // 1 of 2 failed
+        assert!(!window.record(false)); // 2 of 3 failed
+        assert!(!window.record(true)); // false, false, true
+        assert!(window.record(true)); // false, true, true
+
+        let mut single = StatusWindow::new(1);
+        assert!(!single.record(false));
+        assert!(single.record(true));
+    }
+
+    #[test]
+    fn interface_state_recovers_after_consecutive_good_probes() {
+        let t0 = Instant::now();
+        let later = t0 + Duration::from_secs(10);
+        let mut metrics = InterfaceMetrics::default();
+        let mut events = Vec::new();
+
+        transition("eth0", &mut metrics, &mut events, |s| s.probe_succeeded(t0));
+        assert_eq!(metrics.state, InterfaceState::Up { since: t0 });
+        transition("eth0", &mut metrics, &mut events, |s| s.probe_failed(t0, "timeout".into()));
+        transition("eth0", &mut metrics, &mut events, |s| s.probe_failed(later, "100% packet loss".into()));
+        assert_eq!(metrics.state, InterfaceState::Down { since: t0, reason: "100% packet loss".into() });
+        assert_eq!(metrics.status, InterfaceStatus::Failed);
+
+        for attempts in 1..RECOVERY_PROBES {
+            transition("eth0", &mut metrics, &mut events, |s| s.probe_succeeded(later));
+            assert_eq!(metrics.state, InterfaceState::Recovering { since: later, attempts });
+            assert_eq!(metrics.status, InterfaceStatus::Working);
+        }
+        transition("eth0", &mut metrics, &mut events, |s| s.probe_succeeded(later));
+        assert_eq!(metrics.state.to_string(), "up");
+        metrics.reset();
+        transition("eth0", &mut metrics, &mut events, InterfaceState::reset);
+        assert_eq!(metrics.status, InterfaceStatus::Unknown);
+
+        // One event per change of state, not per probe
+        let changes: Vec<String> = events.iter()
+            .map(|FailoverEvent::InterfaceStateChanged { from, to, .. }| format!("{} -> {}", from, to))
+            .collect();
+        assert_eq!(changes, [
+            "unknown -> up",
+            "up -> down (timeout)",
+            "down (100% packet loss) -> recovering (1/3)",
+            "recovering (2/3) -> up",
+            "up -> probing",
+        ]);
+    }
+
+    #[test]
+    fn bring_up_waits_out_the_flap_cooldown() {
+        use std::os::unix::process::ExitStatusExt;
+        let mut runner = crate::network::MockCommandRunner::new();
+        runner.expect_read_file()
+            .withf(|path| path == Path::new("/sys/class/net/eth0/flags"))
+            .returning(|_| Ok("0x1002\n".to_string()));
+        runner.expect_run()
+            .withf(|program, args| program == "ip" && args == ["link", "set", "dev", "eth0", "up"])
+            .times(2)
+            .returning(|_, _| Ok(std::process::Output { status: std::process::ExitStatus::from_raw(0), stdout: Vec::new(), stderr: Vec::new() }));
+        let clock = crate::mock::MockClock::new();
+        let config = FailoverConfigBuilder::new().peer_ip("203.0.113.1").interfaces(["eth0", "wlan0"]).build().unwrap();
+        let mut state = AppState::new(config);
+        state.runner = Arc::new(runner);
+        state.clock = Arc::new(clock.clone());
+
+        bring_up_if_down(&state, "eth0");
+        clock.advance(Duration::from_secs(10));
+        bring_up_if_down(&state, "eth0"); // down again within the 30s cooldown
+        clock.advance(Duration::from_secs(20));
+        bring_up_if_down(&state, "eth0");
+        assert_eq!(state.link_bring_ups.lock().unwrap()["eth0"].flaps, 1);
+
+        clock.advance(Duration::from_secs(30));
+        note_link_up(&state, "eth0");
+        assert!(state.link_bring_ups.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn jittered_interval_stays_within_band() {
+        let interval = Duration::from_secs(30);
+        assert_eq!(jittered_interval(interval, 0, u64::MAX), interval);
+        assert_eq!(jittered_interval(interval, 10, 0), Duration::from_secs(27));
+        assert_eq!(jittered_interval(interval, 10, u64::MAX), Duration::from_secs(33));
+        for random in [1, u64::MAX / 3, u64::MAX / 2, u64::MAX - 1] {
+            let jittered = jittered_interval(interval, 10, random);
+            assert!(jittered >= Duration::from_secs(27) && jittered <= Duration::from_secs(33));
+        }
+    }
+
+    #[test]
+    fn probe_spacing_divides_interval_between_all_pings() {
+        let interval = Duration::from_secs(30);
+        assert_eq!(probe_spacing(interval, 3, 2), Duration::from_secs(5));
+        assert_eq!(probe_spacing(interval, 1, 1), Duration::ZERO);
+        assert_eq!(probe_spacing(interval, 0, 2), Duration::ZERO);
+
+        // Every monitored interface gets its share, not just two of them
+        let mut config = FailoverConfigBuilder::new()
+            .peer_ip("203.0.113.1")
+            .interfaces(["eth0", "wlan0", "wwan0"])
+            .test_ips(["8.8.8.8", "1.1.1.1"])
+            .check_interval(interval)
+            .build()
+            .unwrap();
+        assert_eq!(AppState::new(config.clone()).probe_spacing(2), Duration::ZERO);
+        config.spread_probes = true;
+        assert_eq!(AppState::new(config).probe_spacing(2), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn most_preferred_working_interface_wins_unless_much_slower() {
+        let interfaces: Vec<String> = ["eth0", "wlan0", "wwan0"].iter().map(|s| s.to_string()).collect();
+        let metrics = |statuses: [(InterfaceStatus, f64); 3]| -> Vec<InterfaceMetrics> {
+            statuses.into_iter()
+                .map(|(status, speed_latency_ms)| InterfaceMetrics { status, speed_latency_ms, ..Default::default() })
+                .collect()
+        };
+        use InterfaceStatus::{Failed, Unknown, Working};
+
+        let (choice, explanation) = choose_interface(&interfaces, &metrics([(Failed, 0.0), (Failed, 0.0), (Working, 0.0)]), 20, false, None);
+        assert_eq!(choice, Some(2));
+        assert_eq!(explanation, "Primary eth0 failed, secondary wlan0 failed, priority 3 wwan0 working. Decision: use Priority 3.");
+
+        // Only the fastest challenger is compared against the preferred interface
+        let (choice, _) = choose_interface(&interfaces, &metrics([(Working, 50.0), (Working, 45.0), (Working, 20.0)]), 20, false, None);
+        assert_eq!(choice, Some(2));
+        let (choice, _) = choose_interface(&interfaces, &metrics([(Failed, 0.0), (Working, 50.0), (Working, 45.0)]), 20, false, None);
+        assert_eq!(choice, Some(1));
+
+        let (choice, _) = choose_interface(&interfaces, &metrics([(Unknown, 0.0), (Working, 50.0), (Failed, 0.0)]), 20, false, None);
+        assert_eq!(choice, None);
+        let (choice, explanation) = choose_interface(&interfaces, &metrics([(Failed, 0.0), (Failed, 0.0), (Failed, 0.0)]), 20, true, None);
+        assert_eq!(choice, None);
+        assert_eq!(explanation, "All 3 interfaces failed. Decision: remove the peer route.");
+
+        // A working preferred interface is the one challengers must beat...
+        let (choice, explanation) = choose_interface(&interfaces, &metrics([(Working, 50.0), (Working, 45.0), (Working, 48.0)]), 20, false, Some(1));
+        assert_eq!(choice, Some(1));
+        assert_eq!(
+            explanation,
+            "All 3 interfaces working. Preferred secondary wlan0 latency=45.0ms, Priority 3 wwan0 latency=48.0ms. \
+             Priority 3 higher by 6.7% (threshold 20%). Decision: stick with Preferred secondary."
+        );
+        let (choice, _) = choose_interface(&interfaces, &metrics([(Working, 20.0), (Working, 45.0), (Working, 48.0)]), 20, false, Some(1));
+        assert_eq!(choice, Some(0));
+        let (choice, _) = choose_interface(&interfaces, &metrics([(Unknown, 0.0), (Working, 45.0), (Failed, 0.0)]), 20, false, Some(1));
+        assert_eq!(choice, Some(1));
+        // ...but when it fails, failover walks the list in order
+        let (choice, _) = choose_interface(&interfaces, &metrics([(Failed, 0.0), (Working, 45.0), (Working, 45.0)]), 20, false, Some(0));
+        assert_eq!(choice, Some(1));
+
+        // A poor link counts as slower than it measured
+        let mut measured = metrics([(Working, 50.0), (Working, 35.0), (Failed, 0.0)]);
+        assert_eq!(choose_interface(&interfaces, &measured, 20, false, None).0, Some(1));
+        measured[1].link_quality_score = Some(0.5);
+        let (choice, explanation) = choose_interface(&interfaces, &measured, 20, false, None);
+        assert_eq!(choice, Some(0));
+        assert!(explanation.contains("wlan0 latency=35.0ms quality=0.50"), "{}", explanation);
+    }
+
+    #[test]
+    fn health_score_weighs_what_was_measured() {
+        let mut config = FailoverConfigBuilder::new().peer_ip("203.0.113.1").interfaces(["eth0", "wlan0"]).build().unwrap();
+        let mut m = InterfaceMetrics { status: InterfaceStatus::Working, connectivity_latency_ms: 50.0, ..Default::default() };
+        // latency 0.9 (weight 0.4), loss 1.0 (weight 0.3)
+        assert_eq!(compute_health_score(&m, &config), 94);
+        m.link_quality_score = Some(0.5);
+        m.estimated_bandwidth_mbps = Some(200.0);
+        assert_eq!(compute_health_score(&m, &config), 86);
+        config.max_acceptable_latency_ms = Some(50.0);
+        assert_eq!(compute_health_score(&m, &config), 50);
+        m.status = InterfaceStatus::Failed;
+        assert_eq!(compute_health_score(&m, &config), 0);
+
+        // Within the 20% speed threshold, the clearly healthier link wins
+        let interfaces = vec!["eth0".to_string(), "wlan0".to_string()];
+        let mut metrics = vec![
+            InterfaceMetrics { status: InterfaceStatus::Working, speed_latency_ms: 40.0, health_score: 70, ..Default::default() },
+            InterfaceMetrics { status: InterfaceStatus::Working, speed_latency_ms: 45.0, health_score: 85, ..Default::default() },
+        ];
+        let (choice, explanation) = choose_interface(&interfaces, &metrics, 20, false, None);
+        assert_eq!(choice, Some(1));
+        assert!(explanation.ends_with("Secondary wlan0 is healthier (85 against 70). Decision: switch to Secondary."), "{}", explanation);
+        metrics[1].health_score = 75;
+        assert_eq!(choose_interface(&interfaces, &metrics, 20, false, None).0, Some(0));
+        metrics[1].health_score = 100;
+        metrics[1].speed_latency_ms = 60.0;
+        assert_eq!(choose_interface(&interfaces, &metrics, 20, false, None).0, Some(0));
+    }
+
+    #[test]
+    fn ties_are_broken_only_within_epsilon() {
+        let metrics: Vec<InterfaceMetrics> = [(InterfaceStatus::Working, 20.0), (InterfaceStatus::Working, 20.5), (InterfaceStatus::Working, 30.0)]
+            .into_iter()
+            .map(|(status, speed_latency_ms)| InterfaceMetrics { status, speed_latency_ms, ..Default::default() })
+            .collect();
+        let picks: Vec<usize> = (0..4).map(|cycle| break_tie(&metrics, 0, TieBreak::RoundRobin, 1.0, cycle)).collect();
+        assert_eq!(picks, [0, 1, 0, 1]);
+        assert_eq!(break_tie(&metrics, 0, TieBreak::RoundRobin, 0.1, 1), 0);
+        assert_eq!(break_tie(&metrics, 0, TieBreak::RoundRobin, 10.0, 2), 2);
+
+        // 1/20 against 1/20.5: just over half the range goes to the faster one
+        assert_eq!(break_tie(&metrics, 0, TieBreak::Weighted, 1.0, 0), 0);
+        assert_eq!(break_tie(&metrics, 0, TieBreak::Weighted, 1.0, u64::MAX / 2), 0);
+        assert_eq!(break_tie(&metrics, 0, TieBreak::Weighted, 1.0, u64::MAX / 100 * 55), 1);
+        assert_eq!(break_tie(&metrics, 0, TieBreak::Weighted, 1.0, u64::MAX), 1);
+
+        let unmeasured = vec![InterfaceMetrics { status: InterfaceStatus::Working, ..Default::default() }; 2];
+        assert_eq!(break_tie(&unmeasured, 0, TieBreak::RoundRobin, 1.0, 1), 0);
+    }
+
+    #[test]
+    fn hysteresis_holds_back_switches_until_both_conditions_pass() {
+        let start = Instant::now();
+        let mut hysteresis = SwitchHysteresis::new(Duration::from_secs(60), 3);
+
+        hysteresis.observe(Some("wlan0"));
+        hysteresis.observe(Some("wlan0"));
+        assert!(hysteresis.suppression("wlan0", start).is_some());
+        hysteresis.observe(Some("wlan0"));
+        assert_eq!(hysteresis.suppression("wlan0", start), None);
+
+        // A cycle without a better candidate starts the count again
+        hysteresis.observe(None);
+        hysteresis.observe(Some("wlan0"));
+        assert!(hysteresis.suppression("wlan0", start).is_some());
+
+        hysteresis.record_switch(start);
+        for _ in 0..3 {
+            hysteresis.observe(Some("eth0"));
+        }
+        assert!(hysteresis.suppression("eth0", start + Duration::from_secs(30)).is_some());
+        assert_eq!(hysteresis.suppression("eth0", start + Duration::from_secs(60)), None);
+    }
+
+    #[test]
+    fn peer_reachability_cache_excludes_after_threshold() {
+        let start = Instant::now();
+        let mut cache = PeerReachabilityCache::new(3, Duration::from_secs(60));
+        cache.record_cycle(false, start);
+        cache.record_cycle(false, start);
+        assert!(!cache.is_excluded(start));
+        cache.record_cycle(false, start);
+        assert!(cache.is_excluded(start + Duration::from_secs(59)));
+        assert!(!cache.is_excluded(start + Duration::from_secs(60)));
+
+        cache.record_cycle(false, start);
+        cache.record_cycle(false, start);
+        cache.record_cycle(false, start);
+        assert!(cache.is_excluded(start));
+        cache.record_success();
+        assert!(!cache.is_excluded(start));
+
+        let mut disabled = PeerReachabilityCache::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            disabled.record_cycle(false, start);
+        }
+        assert!(!disabled.is_excluded(start));
+    }
+
+    #[test]
+    fn stop_ends_run_and_waits_for_it() {
+        let clock = crate::mock::MockClock::new();
+        let network = crate::mock::SimulatedNetwork::default().with_clock(clock.clone());
+        let config = FailoverConfigBuilder::new()
+            .peer_ip("203.0.113.1")
+            .interfaces(["sim0", "sim1"])
+            .test_ips(["203.0.113.1"])
+            .check_interval(Duration::from_secs(3600))
+            .build()
+            .unwrap();
+        let (cycles, cycle_done) = std::sync::mpsc::channel();
+        let monitor = FailoverMonitor::new(config).with_command_runner(network).with_clock(clock.clone())
+            .with_cycle_hook(move |status| {
+                let _ = cycles.send(status.cycle);
+            });
+        let stop = monitor.stop_handle();
+        assert!(!stop.is_running());
+
+        let run = thread::spawn(move || monitor.run());
+        // Sleeps return at once on the mock clock, so it keeps cycling until stopped
+        assert_eq!(cycle_done.recv().unwrap(), 1);
+        assert_eq!(cycle_done.recv().unwrap(), 2);
+        assert!(stop.is_running());
+
+        stop.stop();
+        assert!(!stop.is_running());
+        assert!(run.join().unwrap().is_ok());
+        // run() dropped the hook on its way out, so this ends instead of waiting for more cycles
+        assert!(cycle_done.iter().all(|cycle| cycle > 2));
+    }
+
+    #[test]
+    fn sleeps_pass_on_the_monitor_clock() {
+        let clock = crate::mock::MockClock::new();
+        let start = clock.now();
+        let stop = StopHandle::default();
+        stop.sleep(&clock, Duration::from_secs(3600), None);
+        assert_eq!(clock.now() - start, Duration::from_secs(3600));
+
+        stop.inner.stop.store(true, Ordering::SeqCst);
+        stop.sleep(&clock, Duration::from_secs(3600), None);
+        assert_eq!(clock.now() - start, Duration::from_secs(3600), "a stopped monitor does not sleep");
+    }
+
+    #[test]
+    fn failover_hooks_run_after_the_script_hooks_and_can_veto() {
+        let config = FailoverConfigBuilder::new().peer_ip("203.0.113.1").interfaces(["eth0", "wlan0"]).build().unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (pre_calls, post_calls) = (calls.clone(), calls.clone());
+        let monitor = FailoverMonitor::new(config)
+            .with_pre_failover_hook(move |from, to| {
+                pre_calls.lock().unwrap().push(format!("pre {}->{}", from, to));
+                if to == "wlan0" {
+                    return Err(FailoverError::command("vpn-ready", "not yet"));
+                }
+                Ok(())
+            })
+            .with_post_failover_hook(move |from, to| {
+                post_calls.lock().unwrap().push(format!("post {}->{}", from, to));
+                Err(FailoverError::command("notify", "ignored"))
+            });
+        let event = |to: &str| SwitchEvent {
+            old_iface: None,
+            new_iface: Some(to.to_string()),
+            reason: String::new(),
+            peer: "203.0.113.1".to_string(),
+        };
+
+        assert!(monitor.state.run_pre_switch_hooks(&event("eth0")));
+        assert!(!monitor.state.run_pre_switch_hooks(&event("wlan0")));
+        monitor.state.notify_switch(&event("eth0"));
+        assert_eq!(*calls.lock().unwrap(), ["pre ->eth0", "pre ->wlan0", "post ->eth0"]);
+    }
+
+    #[test]
+    fn probe_errors_count_recoverable_and_alert_once_on_permanent() {
+        let mut errors = ProbeErrors::default();
+        let timed_out = FailoverError::io("ping", std::io::Error::from(std::io::ErrorKind::TimedOut));
+        let missing = FailoverError::InterfaceNotFound("wwan0".to_string());
+
+        assert_eq!(errors.record(Some(&timed_out)), None);
+        assert_eq!(errors.record(Some(&FailoverError::command("ping", "exit status 2"))), None);
+        assert_eq!(errors.count, 2);
+
+        assert_eq!(errors.record(Some(&missing)), Some(ErrorSeverity::Permanent));
+        assert_eq!(errors.record(Some(&missing)), None);
+        assert_eq!(errors.record(None), None);
+        assert_eq!(errors.record(Some(&missing)), Some(ErrorSeverity::Permanent));
+        assert_eq!(
+            errors.record(Some(&FailoverError::InsufficientPermissions("ping: socket: Operation not permitted".to_string()))),
+            Some(ErrorSeverity::Critical)
+        );
+        assert_eq!(errors.count, 2);
+    }
+
+    #[test]
+    fn loss_cutoff_uses_the_interface_override_or_the_default() {
+        let mut config = FailoverConfigBuilder::new().peer_ip("203.0.113.1").interfaces(["eth0", "wwan0"]).build().unwrap();
+        config.max_loss_pct = 30;
+        config.interface_max_loss_pct.insert("wwan0".to_string(), 70);
+        let state = AppState::new(config);
+
+        assert!(state.within_loss_cutoff("eth0", 30.0));
+        assert!(!state.within_loss_cutoff("eth0", 50.0));
+        assert!(state.within_loss_cutoff("wwan0", 50.0));
+        assert!(!state.within_loss_cutoff("wwan0", 75.0));
+    }
+
+    #[test]
+    fn hold_time_follows_the_injected_clock() {
+        let clock = crate::mock::MockClock::new();
+        let network = crate::mock::SimulatedNetwork::from_json(r#"{
+            "sim0": {"latency_ms": 10, "outages": [{"at_secs": 0, "duration_secs": 5}]},
+            "sim1": {"latency_ms": 30}
+        }"#).unwrap().with_clock(clock.clone());
+        let mut config = FailoverConfigBuilder::new()
+            .peer_ip("203.0.113.1")
+            .interfaces(["sim0", "sim1"])
+            .test_ips(["203.0.113.1"])
+            .build()
+            .unwrap();
+        config.startup_probes = 0;
+        config.min_hold_time = Duration::from_secs(60);
+        let summaries = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&summaries);
+        let mut monitor = FailoverMonitor::new(config).with_command_runner(network).with_clock(clock.clone())
+            .with_cycle_hook(move |status| seen.lock().unwrap().push(status.summary_line(false)));
+
+        // sim0 starts out down, so sim1 takes the route
+        assert_eq!(monitor.tick().active_interface.as_deref(), Some("sim1"));
+        assert!(summaries.lock().unwrap()[0].starts_with("cycle 1 | active=sim1 | "));
+        clock.advance(Duration::from_secs(30));
+        let status = monitor.tick();
+        assert_eq!(status.interfaces[0].1.status, InterfaceStatus::Working);
+        assert_eq!(status.active_interface.as_deref(), Some("sim1"), "held for 30s of 60s");
+        assert_eq!(status.recovering_interface.as_deref(), Some("sim0"));
+        assert!(status.summary_line(false).contains("sim0: RECOVERING"));
+        clock.advance(Duration::from_secs(30));
+        let status = monitor.tick();
+        assert_eq!(status.active_interface.as_deref(), Some("sim0"));
+        assert_eq!(status.recovering_interface, None);
+        assert_eq!(summaries.lock().unwrap().len(), 3);
+        assert!(summaries.lock().unwrap()[2].ends_with("SWITCH sim1 -> sim0"));
+    }
+
+    #[test]
+    fn each_address_family_gets_a_working_interface() {
+        let network = Arc::new(crate::mock::SimulatedNetwork::from_json(r#"{
+            "sim0": {"latency_ms": 10, "ipv6_broken": true},
+            "sim1": {"latency_ms": 30}
+        }"#).unwrap());
+        let mut config = FailoverConfigBuilder::new()
+            .peer_ip("203.0.113.1")
+            .interfaces(["sim0", "sim1"])
+            .test_ips(["203.0.113.1", "2001:db8::1"])
+            .route_all_traffic(true)
+            .build()
+            .unwrap();
+        config.startup_probes = 0;
+        let mut monitor = FailoverMonitor::new(config).with_command_runner(Arc::clone(&network));
+
+        let status = monitor.tick();
+        // Half the test IPs answer on sim0, but only the peer's family counts
+        assert_eq!(status.active_interface.as_deref(), Some("sim0"));
+        assert_eq!((status.interfaces[0].1.working_v4, status.interfaces[0].1.working_v6), (Some(true), Some(false)));
+        assert_eq!((status.interfaces[1].1.working_v4, status.interfaces[1].1.working_v6), (Some(true), Some(true)));
+        let default_via = |ipv6| crate::network::default_route_info(network.as_ref(), ipv6).unwrap().map(|r| r.dev);
+        assert_eq!(default_via(false).as_deref(), Some("sim0"));
+        assert_eq!(default_via(true).as_deref(), Some("sim1"));
+
+        assert_eq!(family_working(&HashMap::from([("198.51.100.1".to_string(), false)]), true), None);
+    }
+
+    #[test]
+    fn env_file_changes_apply_to_every_interface() {
+        let config = FailoverConfigBuilder::new()
+            .peer_ip("203.0.113.1")
+            .interfaces(["eth0", "wlan0", "wwan0"])
+            .build()
+            .unwrap();
+        let mut monitor = FailoverMonitor::new(config);
+        monitor.outages[2] = true;
+        let mut apply = |vars: &[(&str, &str)]| {
+            let previous = monitor.state.interfaces.clone();
+            let changed = monitor.state.apply_runtime_overrides(EnvOverrides::from_vars("WG", vars.iter().copied()).unwrap());
+            monitor.follow_interface_list(&previous);
+            (changed, monitor.state.interfaces.clone(), monitor.outages.clone())
+        };
+
+        // Per-interface state moves with the interface
+        assert_eq!(apply(&[("WG_INTERFACES", "wwan0, eth0, lte0, wlan0")]),
+                   (true, vec!["wwan0".into(), "eth0".into(), "lte0".into(), "wlan0".into()], vec![true, false, false, false]));
+        assert_eq!(apply(&[("WG_SECONDARY", "wlan0")]).1, ["wwan0", "wlan0", "eth0", "lte0"]);
+        assert!(!apply(&[("WG_SECONDARY", "wlan0")]).0);
+        assert!(!apply(&[("WG_INTERFACES", "eth0, eth0")]).0);
+        assert_eq!(monitor.metrics.len(), 4);
+    }
+}
@@ -1,12 +1,32 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use log::{debug, error, info, warn};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
+use clap_complete::Shell;
+use log::{debug, info, warn};
 use serde::Deserialize;
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::process::Command;
-use std::thread;
-use std::time::{Duration, Instant};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use wg_failover::config::{
+    apply_peer_quorum_setting, check_interface_count, config_floats_eq, interface_slots, parse_list, parse_probe_timeouts,
+    peer_from_wg_config, CaptureSettings, EnvOverrides, MaintenanceWindow, MaintenanceWindowConfig, ProbeStrategy, RouteExclusion, ScoringWeights, TieBreak, DEFAULT_MAX_INTERFACES,
+    DEFAULT_LINK_FLAP_COOLDOWN_SECS, DEFAULT_MAX_LOSS_PCT, DEFAULT_PING_TIMEOUT, DEFAULT_STATE_FILE, DEFAULT_TEST_IPS,
+    DEFAULT_TIE_BREAK_EPSILON_MS,
+};
+use wg_failover::hooks::{validate_hook_command, HookTiming, SwitchHooks, WebhookUrl, DEFAULT_HOOK_TIMEOUT_SECS};
+use wg_failover::mock::SimulatedNetwork;
+use wg_failover::network::{
+    get_interface_addresses, get_route_interface, is_excluded_interface, is_valid_mac, list_links,
+    list_physical_interfaces, normalize_interface_name, run_ping_stats, strip_ip_brackets, PingStats,
+    SystemCommandRunner, IP_ICMP_HEADER_BYTES,
+};
+#[cfg(feature = "gelf")]
+use wg_failover::gelf;
+use wg_failover::{
+    daemonize, install_shutdown_handler, interface_role, log_with_timestamp, set_cpu_affinity, set_nice_level, FailoverConfig, FailoverMonitor,
+    IpAddress,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -16,7 +36,7 @@ struct Args {
     peer_ip: Option<String>,
 
     /// Path to configuration file
-    #[arg(short = 'c', long = "config")]
+    #[arg(short = 'c', long = "config", value_hint = ValueHint::FilePath)]
     config: Option<PathBuf>,
 
     /// Primary network interface (e.g., eth0)
@@ -27,6 +47,10 @@ struct Args {
     #[arg(short = 's', long = "secondary")]
     secondary: Option<String>,
 
+    /// All interfaces, most preferred first (comma-separated); replaces the config file's list
+    #[arg(long = "interfaces")]
+    interfaces: Option<String>,
+
     /// Connectivity check interval in seconds
     #[arg(short = 't', long = "interval")]
     interval: Option<u64>,
@@ -39,6 +63,10 @@ struct Args {
     #[arg(long = "speed-threshold")]
     speed_threshold: Option<u8>,
 
+    /// Maximum average latency in milliseconds for an interface to count as working
+    #[arg(long = "max-latency")]
+    max_acceptable_latency_ms: Option<f64>,
+
     /// Test IPs for connectivity checks (comma-separated)
     #[arg(long = "test-ips")]
     test_ips: Option<String>,
@@ -46,363 +74,772 @@ struct Args {
     /// Route all traffic through selected interface (not just WireGuard peer)
     #[arg(long = "route-all-traffic")]
     route_all_traffic: bool,
+
+    /// Prefix for environment variable overrides (e.g. WG_VPN0 reads WG_VPN0_PEER_IP)
+    #[arg(long = "env-prefix", default_value = "WG_FAILOVER")]
+    env_prefix: String,
+
+    /// KEY=VALUE file with <PREFIX>_* overrides; peer and interface changes are picked up at runtime
+    #[arg(long = "env-file", value_hint = ValueHint::FilePath)]
+    env_file: Option<PathBuf>,
+
+    /// Detach into the background (double fork) instead of running in the foreground
+    #[arg(long = "daemon")]
+    daemon: bool,
+
+    /// Write the daemon's PID to this file (with --daemon)
+    #[arg(long = "pid-file", requires = "daemon", value_hint = ValueHint::FilePath)]
+    pid_file: Option<PathBuf>,
+
+    /// Probe and install the first route before detaching or entering the watch loop
+    #[arg(long = "once-then-watch")]
+    once_then_watch: bool,
+
+    /// Resolve configured interface names (altnames, aliases, case, whitespace) to kernel names
+    #[arg(long = "normalize-interface-names")]
+    normalize_interface_names: bool,
+
+    /// Print one summary line per cycle to stdout, independent of the log level
+    #[arg(long = "summary")]
+    summary: bool,
+
+    /// Shell command run when the active interface changes (gets WG_OLD_IFACE, WG_NEW_IFACE, WG_REASON, WG_PEER)
+    #[arg(long = "on-switch")]
+    on_switch: Option<String>,
+
+    /// http:// URL that receives a JSON POST when the active interface changes
+    #[arg(long = "on-switch-webhook", value_hint = ValueHint::Url)]
+    on_switch_webhook: Option<String>,
+
+    /// Colored symbols in the summary line: auto (when stdout is a terminal), always or never
+    #[arg(long = "color", value_enum, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Probe and route over simulated interfaces sim0, sim1 and sim2 instead of the real network
+    #[arg(long = "simulate-network")]
+    simulate_network: bool,
+
+    /// JSON file with per-interface latency_ms, loss and outages for --simulate-network
+    #[arg(long = "simulation-config", requires = "simulate_network", value_hint = ValueHint::FilePath)]
+    simulation_config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Compare ping results to a target across interfaces (no route changes)
+    PingTest {
+        /// Target IP address or hostname to ping
+        #[arg(long = "target")]
+        target: String,
+
+        /// Number of pings to send per interface
+        #[arg(long = "count", default_value_t = 10)]
+        count: u8,
+
+        /// Ping timeout in seconds
+        #[arg(long = "timeout", default_value_t = 2)]
+        timeout: u8,
+
+        /// Interfaces to test (comma-separated), instead of those from the config
+        #[arg(long = "interface")]
+        interface: Option<String>,
+    },
+    /// Run one probe cycle and explain which interface would be chosen and why (no route changes)
+    ExplainDecision,
+    /// Compare the current routing table to the route the daemon would install (no route changes)
+    Audit,
+    /// Interactively pick interfaces and the peer, and write a config file
+    Init {
+        /// Overwrite an existing config file
+        #[arg(long = "force")]
+        force: bool,
+    },
+    /// Print a shell completion script
+    #[command(after_help = "\
+Installation:
+  bash:        wg-failover completion bash --output /etc/bash_completion.d/wg-failover
+  zsh:         wg-failover completion zsh --output \"${fpath[1]}/_wg-failover\"
+  fish:        wg-failover completion fish --output ~/.config/fish/completions/wg-failover.fish
+  PowerShell:  wg-failover completion powershell >> $PROFILE
+Start a new shell afterwards. In bash, zsh and fish, interface names are completed
+from /sys/class/net.")]
+    Completion {
+        /// Shell to generate the script for
+        #[arg(value_enum)]
+        shell: Shell,
+
+        /// Write the script to this file instead of stdout
+        #[arg(long = "output", value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    fn enabled(self) -> bool {
+        use std::io::IsTerminal;
+        match self {
+            ColorMode::Auto => std::io::stdout().is_terminal(),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 struct Config {
     peer: Option<PeerConfig>,
-    interfaces: Option<InterfaceConfig>,
+    wireguard: Option<WireguardConfig>,
+    interfaces: Option<InterfacesSection>,
     monitoring: Option<MonitoringConfig>,
     test_ips: Option<Vec<String>>,
     route_all_traffic: Option<bool>,
+    exclude_interfaces: Option<Vec<String>>, // glob patterns, e.g. "docker*"
+    preferred_interface: Option<String>, // wins ties and speed comparisons, not failover order
+    pre_failover_flush: Option<bool>,
+    probe_namespaces: Option<ProbeNamespaceConfig>,
+    probe_timeouts: Option<HashMap<String, u8>>, // probe method -> timeout seconds
+    reassert_routes: Option<bool>,
+    maintenance_windows: Option<Vec<MaintenanceWindowConfig>>,
+    post_switch_confirm_target: Option<String>,
+    post_switch_mtu_check: Option<u16>,
+    connection_drain_wait_secs: Option<u64>,
+    pre_failover_wg_check: Option<bool>,
+    wg_probe_timeout_secs: Option<u64>,
+    cpu_affinity: Option<Vec<usize>>,
+    nice_level: Option<i8>,
+    route_exclusions: Option<Vec<RouteExclusion>>,
+    gelf_endpoint: Option<String>, // "host:port" of a Graylog GELF UDP input
+    failover_to_no_route: Option<bool>,
+    external_control_file: Option<PathBuf>,
+    static_arp: Option<HashMap<String, String>>, // neighbour IP -> MAC address
+    include_peer_in_quorum: Option<bool>,
+    state_dump_file: Option<PathBuf>, // SIGUSR2 dump target instead of the log
+    persist_state: Option<bool>,
+    state_file: Option<PathBuf>, // last active interface, read at startup
+    send_gratuitous_arp: Option<bool>,
+    rolling_capture: Option<bool>,
+    capture_interface: Option<String>, // fixed interface; default follows the active one
+    capture_dir: Option<PathBuf>,
+    capture_max_file_size_mb: Option<u64>,
+    capture_retention_count: Option<usize>,
+    on_switch: Option<String>, // shell command, run via `sh -c`
+    on_switch_webhook: Option<String>, // http:// URL for a JSON POST
+    hook_timing: Option<HookTiming>,
+    hook_timeout_secs: Option<u64>,
+    pre_hook_aborts_switch: Option<bool>,
+    strict_hook_validation: Option<bool>, // fail startup instead of warning
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 struct PeerConfig {
     ip: Option<String>,
+    endpoint: Option<String>, // WireGuard endpoint, "ip:port"
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct WireguardConfig {
+    interface: Option<String>,
+    config_file: Option<PathBuf>, // default /etc/wireguard/<interface>.conf
+    peer_from_config: Option<bool>,
+}
+
+/// `[interfaces]` is either the classic table naming a primary and a
+/// secondary, or a list of `[[interfaces]]` tables with any number of them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+enum InterfacesSection {
+    List(Vec<InterfaceEntry>),
+    Table(InterfaceConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 struct InterfaceConfig {
     primary: Option<String>,
     secondary: Option<String>,
+    probe_src_addr: Option<HashMap<String, String>>, // iface -> source address
+    max_loss_pct: Option<HashMap<String, u8>>, // iface -> percent
+    exclude_interfaces: Option<Vec<String>>, // deprecated, read along with the top-level key
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct InterfaceEntry {
+    name: String,
+    priority: Option<u32>, // lower is preferred; unset entries keep file order after the others
+    probe_src_addr: Option<String>,
+    max_loss_pct: Option<u8>,
+}
+
+impl InterfacesSection {
+    /// Interface names by preference. The table form always yields two
+    /// slots, so a missing primary or secondary can still be filled in by
+    /// the --primary/--secondary shortcuts.
+    fn slots(&self) -> Vec<Option<String>> {
+        match self {
+            InterfacesSection::Table(table) => vec![table.primary.clone(), table.secondary.clone()],
+            InterfacesSection::List(entries) => {
+                let mut entries: Vec<&InterfaceEntry> = entries.iter().collect();
+                entries.sort_by_key(|e| e.priority.unwrap_or(u32::MAX));
+                entries.into_iter().map(|e| Some(e.name.clone())).collect()
+            }
+        }
+    }
+
+    fn max_loss_pcts(&self) -> HashMap<String, u8> {
+        match self {
+            InterfacesSection::Table(table) => table.max_loss_pct.clone().unwrap_or_default(),
+            InterfacesSection::List(entries) => entries.iter()
+                .filter_map(|e| Some((e.name.clone(), e.max_loss_pct?)))
+                .collect(),
+        }
+    }
+
+    fn probe_src_addrs(&self) -> HashMap<String, String> {
+        match self {
+            InterfacesSection::Table(table) => table.probe_src_addr.clone().unwrap_or_default(),
+            InterfacesSection::List(entries) => entries.iter()
+                .filter_map(|e| Some((e.name.clone(), e.probe_src_addr.clone()?)))
+                .collect(),
+        }
+    }
+}
+
+/// The interfaces before --primary and --secondary are applied: --interfaces
+/// (or `<PREFIX>_INTERFACES`) if given, otherwise the config file's.
+fn configured_slots(args: &Args, section: Option<&InterfacesSection>) -> Vec<Option<String>> {
+    match &args.interfaces {
+        Some(list) => parse_list(list).into_iter().map(Some).collect(),
+        None => section.map(InterfacesSection::slots).unwrap_or_default(),
+    }
 }
 
-#[derive(Debug, Deserialize)]
+impl Config {
+    // Settings that are applied before the monitor is built
+    fn validate(&self) -> Result<()> {
+        if let Some(level) = self.nice_level.filter(|level| !(-20..=19).contains(level)) {
+            return Err(anyhow::anyhow!("nice_level must be between -20 and 19, got {}", level));
+        }
+        Ok(())
+    }
+
+    /// The top-level exclude_interfaces patterns, plus any still given in the
+    /// [interfaces] table where they used to live.
+    fn exclude_interfaces(&self) -> Vec<String> {
+        let mut patterns = self.exclude_interfaces.clone().unwrap_or_default();
+        if let Some(InterfacesSection::Table(InterfaceConfig { exclude_interfaces: Some(legacy), .. })) = &self.interfaces {
+            warn!("exclude_interfaces in [interfaces] is deprecated, move it to the top level of the config file");
+            patterns.extend(legacy.iter().cloned());
+        }
+        patterns
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct ProbeNamespaceConfig {
+    connectivity: Option<String>,
+    speed: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 struct MonitoringConfig {
     interval: Option<u64>,
     speedtest_interval: Option<u64>,
     speed_threshold: Option<u8>,
+    max_acceptable_latency_ms: Option<f64>,
+    max_loss_pct: Option<u8>,
+    ping_timeout: Option<u8>,
+    smart_recovery: Option<bool>,
+    smart_recovery_success_rate: Option<f64>,
+    traceroute_on_failure: Option<bool>,
+    traceroute_max_hops: Option<u8>,
+    traceroute_min_interval: Option<u64>,
+    startup_delay_secs: Option<u64>,
+    startup_probes: Option<u8>,
+    probe_strategy: Option<ProbeStrategy>,
+    standby_probe_cycles: Option<u32>,
+    bandwidth_check_enabled: Option<bool>,
+    min_bandwidth_mbps: Option<f64>,
+    probe_gateway_first: Option<bool>,
+    status_window: Option<u8>,
+    route_cache_ttl_secs: Option<u64>,
+    interval_jitter_pct: Option<u8>,
+    spread_probes: Option<bool>,
+    auto_bring_up_interfaces: Option<bool>,
+    link_flap_cooldown_secs: Option<u64>,
+    tie_break: Option<TieBreak>,
+    tie_break_epsilon_ms: Option<f64>,
+    scoring_weights: Option<ScoringWeights>,
+    peer_cache_threshold: Option<u32>,
+    peer_exclusion_secs: Option<u64>,
+    min_hold_time: Option<u64>,
+    consecutive_better_checks: Option<u32>,
+    max_interfaces: Option<usize>,
 }
 
-struct AppState {
-    peer_ip: String,
-    primary_iface: String,
-    secondary_iface: String,
-    test_ips: Vec<String>,
-    check_interval: Duration,
-    speed_check_interval: Duration,
-    speed_threshold: u8,
-    route_all_traffic: bool,
-}
-
-#[derive(Debug, Clone, PartialEq)]
-enum InterfaceStatus {
-    Working,
-    Failed,
-    Unknown,
+impl PartialEq for MonitoringConfig {
+    fn eq(&self, other: &Self) -> bool {
+        // Destructured, so a new field cannot be left out by accident
+        let Self { interval, speedtest_interval, speed_threshold, max_acceptable_latency_ms, max_loss_pct, ping_timeout,
+            smart_recovery, smart_recovery_success_rate, traceroute_on_failure, traceroute_max_hops,
+            traceroute_min_interval, startup_delay_secs, startup_probes, probe_strategy, standby_probe_cycles,
+            bandwidth_check_enabled, min_bandwidth_mbps, probe_gateway_first, status_window, route_cache_ttl_secs,
+            interval_jitter_pct, spread_probes, auto_bring_up_interfaces, link_flap_cooldown_secs, tie_break, tie_break_epsilon_ms,
+            scoring_weights, peer_cache_threshold, peer_exclusion_secs, min_hold_time,
+            consecutive_better_checks, max_interfaces,
+        } = self;
+        *interval == other.interval
+            && *speedtest_interval == other.speedtest_interval
+            && *speed_threshold == other.speed_threshold
+            && config_floats_eq(*max_acceptable_latency_ms, other.max_acceptable_latency_ms)
+            && *max_loss_pct == other.max_loss_pct
+            && *ping_timeout == other.ping_timeout
+            && *smart_recovery == other.smart_recovery
+            && config_floats_eq(*smart_recovery_success_rate, other.smart_recovery_success_rate)
+            && *traceroute_on_failure == other.traceroute_on_failure
+            && *traceroute_max_hops == other.traceroute_max_hops
+            && *traceroute_min_interval == other.traceroute_min_interval
+            && *startup_delay_secs == other.startup_delay_secs
+            && *startup_probes == other.startup_probes
+            && *probe_strategy == other.probe_strategy
+            && *standby_probe_cycles == other.standby_probe_cycles
+            && *bandwidth_check_enabled == other.bandwidth_check_enabled
+            && config_floats_eq(*min_bandwidth_mbps, other.min_bandwidth_mbps)
+            && *probe_gateway_first == other.probe_gateway_first
+            && *status_window == other.status_window
+            && *route_cache_ttl_secs == other.route_cache_ttl_secs
+            && *interval_jitter_pct == other.interval_jitter_pct
+            && *spread_probes == other.spread_probes
+            && *auto_bring_up_interfaces == other.auto_bring_up_interfaces
+            && *link_flap_cooldown_secs == other.link_flap_cooldown_secs
+            && *tie_break == other.tie_break
+            && config_floats_eq(*tie_break_epsilon_ms, other.tie_break_epsilon_ms)
+            && *scoring_weights == other.scoring_weights
+            && *peer_cache_threshold == other.peer_cache_threshold
+            && *peer_exclusion_secs == other.peer_exclusion_secs
+            && *min_hold_time == other.min_hold_time
+            && *consecutive_better_checks == other.consecutive_better_checks
+            && *max_interfaces == other.max_interfaces
+    }
 }
 
-#[derive(Debug, Clone)]
-struct InterfaceMetrics {
-    status: InterfaceStatus,
-    connectivity_latency_ms: f64,
-    speed_latency_ms: f64,
-    test_results: HashMap<String, bool>, // IP -> reachable
+fn prompt(question: &str, default: Option<&str>) -> Result<String> {
+    use std::io::Write;
+    loop {
+        match default {
+            Some(default) => print!("{} [{}]: ", question, default),
+            None => print!("{}: ", question),
+        }
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            return Err(anyhow::anyhow!("Input closed before setup finished"));
+        }
+        match (line.trim(), default) {
+            ("", Some(default)) => return Ok(default.to_string()),
+            ("", None) => println!("A value is required."),
+            (answer, _) => return Ok(answer.to_string()),
+        }
+    }
 }
 
-impl Default for InterfaceMetrics {
-    fn default() -> Self {
-        Self {
-            status: InterfaceStatus::Unknown,
-            connectivity_latency_ms: 0.0,
-            speed_latency_ms: 0.0,
-            test_results: HashMap::new(),
+// Accepts an interface either by name or by its number in the listing
+fn prompt_interface(question: &str, interfaces: &[String], default: Option<&str>) -> Result<String> {
+    loop {
+        let answer = prompt(question, default)?;
+        let chosen = answer.parse::<usize>().ok()
+            .and_then(|n| interfaces.get(n.wrapping_sub(1)).cloned())
+            .unwrap_or(answer);
+        if interfaces.is_empty() || interfaces.contains(&chosen) {
+            return Ok(chosen);
         }
+        println!("{} is not one of the detected interfaces.", chosen);
     }
 }
 
-fn log_with_timestamp(msg: &str) {
-    debug!("[{}] {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), msg);
+fn render_init_config(primary: &str, secondary: &str, peer_ip: &str, test_ips: &[String]) -> String {
+    let test_ips: Vec<String> = test_ips.iter().map(|ip| format!("    \"{}\",", ip)).collect();
+    format!(
+        "# Written by `wg-failover init`. See the example config.toml for all options.\n\
+         \n\
+         test_ips = [\n{}\n]\n\
+         route_all_traffic = false\n\
+         \n\
+         [peer]\n\
+         ip = \"{}\"\n\
+         \n\
+         [interfaces]\n\
+         primary = \"{}\"\n\
+         secondary = \"{}\"\n\
+         \n\
+         [monitoring]\n\
+         interval = 30\n",
+        test_ips.join("\n"), peer_ip, primary, secondary
+    )
 }
 
-fn get_gateway_for_interface(iface: &str) -> Option<String> {
-    debug!("Getting gateway for interface: {}", iface);
-    
-    // Try to get default gateway for this interface
-    let output = Command::new("ip")
-        .args(["route", "show", "dev", iface])
-        .output();
-
-    match output {
-        Ok(out) if out.status.success() => {
-            let stdout = String::from_utf8_lossy(&out.stdout);
-            debug!("Route output for {}: {}", iface, stdout);
-            
-            // Look for default route via gateway
-            for line in stdout.lines() {
-                if line.starts_with("default via ") {
-                    let parts: Vec<&str> = line.split_whitespace().collect();
-                    if parts.len() >= 3 {
-                        debug!("Found default gateway for {}: {}", iface, parts[2]);
-                        return Some(parts[2].to_string());
-                    }
-                }
-            }
-            
-            // If no default route, look for any route with a gateway
-            for line in stdout.lines() {
-                if line.contains(" via ") {
-                    let parts: Vec<&str> = line.split_whitespace().collect();
-                    for (i, part) in parts.iter().enumerate() {
-                        if *part == "via" && i + 1 < parts.len() {
-                            debug!("Found gateway for {}: {}", iface, parts[i + 1]);
-                            return Some(parts[i + 1].to_string());
-                        }
-                    }
-                }
-            }
-            
-            debug!("No gateway found for interface {}", iface);
-            None
+fn run_init(config_path: &Path, force: bool) -> Result<()> {
+    if config_path.exists() && !force {
+        return Err(anyhow::anyhow!("{:?} already exists (pass --force to overwrite)", config_path));
+    }
+
+    let interfaces = list_physical_interfaces(&SystemCommandRunner, &[]).unwrap_or_else(|e| {
+        warn!("Could not list interfaces: {}", e);
+        Vec::new()
+    });
+    if interfaces.is_empty() {
+        println!("No interfaces detected; enter the interface names by hand.");
+    } else {
+        println!("Detected interfaces:");
+        for (i, iface) in interfaces.iter().enumerate() {
+            let addresses: Vec<String> = get_interface_addresses(&SystemCommandRunner, iface)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|a| a.scope == "global")
+                .map(|a| format!("{}/{}", a.addr, a.prefix_len))
+                .collect();
+            let wireless = Path::new("/sys/class/net").join(iface).join("wireless").exists();
+            println!(
+                "  {}) {:<12} {}{}",
+                i + 1,
+                iface,
+                if addresses.is_empty() { "no address".to_string() } else { addresses.join(", ") },
+                if wireless { " (wireless)" } else { "" }
+            );
+        }
+    }
+    println!();
+
+    let primary = prompt_interface("Primary interface", &interfaces, interfaces.first().map(String::as_str))?;
+    let secondary_default = interfaces.iter().find(|i| **i != primary).map(String::as_str);
+    let secondary = loop {
+        let secondary = prompt_interface("Secondary interface", &interfaces, secondary_default)?;
+        if secondary != primary {
+            break secondary;
         }
-        Ok(out) => {
-            let stderr = String::from_utf8_lossy(&out.stderr);
-            debug!("Failed to get routes for {}: {}", iface, stderr);
-            None
+        println!("The secondary interface must differ from the primary.");
+    };
+    let peer_ip = loop {
+        let peer_ip = prompt("WireGuard peer IP address", None)?;
+        if peer_ip.parse::<IpAddr>().is_ok() {
+            break peer_ip;
         }
-        Err(e) => {
-            debug!("Failed to execute ip command for {}: {}", iface, e);
-            None
+        println!("{} is not an IP address.", peer_ip);
+    };
+    let mut test_ips = loop {
+        let answer = prompt("Test IPs (comma-separated)", Some(&DEFAULT_TEST_IPS.join(",")))?;
+        let ips: Vec<String> = answer.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect();
+        match ips.iter().find(|ip| ip.parse::<IpAddr>().is_err()) {
+            Some(bad) => println!("{} is not an IP address.", bad),
+            None if ips.is_empty() => println!("At least one test IP is required."),
+            None => break ips,
         }
+    };
+
+    if !test_ips.contains(&peer_ip) {
+        test_ips.push(peer_ip.clone());
     }
+    let content = render_init_config(&primary, &secondary, &peer_ip, &test_ips);
+    toml::from_str::<Config>(&content).context("Generated config does not parse")?;
+
+    if let Some(dir) = config_path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    }
+    std::fs::write(config_path, content).with_context(|| format!("Failed to write {:?}", config_path))?;
+    println!("Wrote {:?}. Start monitoring with: wg-failover -c {:?}", config_path, config_path);
+    Ok(())
 }
 
-fn measure_latency(iface: &str, target: &str, count: u8, timeout: u8) -> (bool, f64) {
-    debug!("measure_latency called: iface={}, target={}, count={}, timeout={}", iface, target, count, timeout);
-    
-    let cmd_str = format!("ping -I {} -c {} -W {} {}", iface, count, timeout, target);
-    debug!("Executing command: {}", cmd_str);
-    
-    let output = Command::new("ping")
-        .args([
-            "-I", iface,
-            "-c", &count.to_string(),
-            "-W", &timeout.to_string(),
-            target,
-        ])
-        .output();
-
-    match output {
-        Ok(out) if out.status.success() => {
-            debug!("Ping command succeeded with status: {}", out.status);
-            let stdout = String::from_utf8_lossy(&out.stdout);
-            let stderr = String::from_utf8_lossy(&out.stderr);
-            debug!("Ping stdout: {}", stdout);
-            if !stderr.is_empty() {
-                debug!("Ping stderr: {}", stderr);
+// Options whose values are interface names, completed from /sys/class/net
+const INTERFACE_ARGS: [&str; 4] = ["primary", "secondary", "interfaces", "interface"];
+
+/// clap_complete's script for `shell`, with interface names completed from
+/// /sys/class/net for the options in INTERFACE_ARGS. clap_complete only
+/// generates static scripts, so that part is patched in for bash, zsh and
+/// fish; other shells complete those options without suggestions.
+fn completion_script(shell: Shell, cmd: &mut clap::Command) -> String {
+    let bin = cmd.get_name().to_string();
+    let mut buf = Vec::new();
+    clap_complete::generate(shell, cmd, &bin, &mut buf);
+    let mut script = String::from_utf8(buf).expect("completion scripts are UTF-8");
+
+    let interface_args: Vec<&clap::Arg> = std::iter::once(&*cmd)
+        .chain(cmd.get_subcommands())
+        .flat_map(|c| c.get_arguments())
+        .filter(|arg| INTERFACE_ARGS.contains(&arg.get_id().as_str()))
+        .collect();
+    match shell {
+        Shell::Bash => {
+            // Answer interface options first, hand everything else to the generated function
+            let generated = script.lines().next().and_then(|l| l.strip_suffix("() {")).unwrap_or_default().to_string();
+            let flags: Vec<String> = interface_args.iter()
+                .flat_map(|arg| arg.get_long().map(|l| format!("--{}", l)).into_iter().chain(arg.get_short().map(|s| format!("-{}", s))))
+                .collect();
+            let function = format!("_{}_interfaces", bin.replace('-', "_"));
+            script.push_str(&format!(
+                r#"
+{function}() {{
+    case "$3" in
+        {flags}) COMPREPLY=($(compgen -W "$(ls /sys/class/net 2>/dev/null)" -- "$2")); return 0 ;;
+    esac
+    {generated} "$@"
+}}
+complete -F {function} -o bashdefault -o default {bin}
+"#,
+                flags = flags.join("|"),
+            ));
+        }
+        Shell::Zsh => {
+            for arg in &interface_args {
+                let value_name = arg.get_value_names().and_then(|names| names.first()).map_or_else(
+                    || arg.get_id().as_str().to_uppercase(),
+                    |name| name.to_string(),
+                );
+                script = script.replace(&format!(":{}:_default'", value_name), &format!(":{}:_net_interfaces'", value_name));
             }
-            
-            // Parse rtt min/avg/max/mdev = 1.1/2.2/3.3/0.4 ms
-            debug!("Parsing ping output for latency statistics");
-            for line in stdout.lines() {
-                debug!("Processing line: {}", line);
-                if line.contains("min/avg/max") {
-                    debug!("Found latency statistics line: {}", line);
-                    if let Some(stats) = line.split('=').nth(1) {
-                        let parts: Vec<&str> = stats.split('/').collect();
-                        debug!("Parsed statistics parts: {:?}", parts);
-                        if parts.len() >= 2 {
-                            if let Ok(avg) = parts[1].trim().parse::<f64>() {
-                                debug!("Successfully parsed average latency: {} ms", avg);
-                                return (true, avg);
-                            } else {
-                                debug!("Failed to parse average latency from: {}", parts[1].trim());
-                            }
-                        } else {
-                            debug!("Insufficient statistics parts, expected at least 2");
-                        }
-                    } else {
-                        debug!("No statistics found after '=' in line");
-                    }
+        }
+        Shell::Fish => {
+            for arg in &interface_args {
+                let mut line = format!("complete -c {}", bin);
+                if let Some(long) = arg.get_long() {
+                    line.push_str(&format!(" -l {}", long));
+                }
+                if let Some(short) = arg.get_short() {
+                    line.push_str(&format!(" -s {}", short));
                 }
+                script.push_str(&format!("{} -x -a '(ls /sys/class/net)'\n", line));
             }
-            debug!("Ping succeeded but could not parse latency statistics");
-            (true, 0.0) // Success but failed to parse latency?
-        }
-        Ok(out) => {
-            debug!("Ping command failed with status: {}", out.status);
-            let stderr = String::from_utf8_lossy(&out.stderr);
-            debug!("Ping stderr: {}", stderr);
-            (false, 0.0)
-        }
-        Err(e) => {
-            debug!("Failed to execute ping command: {}", e);
-            (false, 0.0)
         }
+        _ => {}
     }
+    script
 }
 
-fn test_connectivity_multiple_ips(iface: &str, test_ips: &[String]) -> (bool, f64, HashMap<String, bool>) {
-    debug!("Testing connectivity for interface {} to {} IPs", iface, test_ips.len());
-    
-    let mut successful_tests = 0;
-    let mut total_latency = 0.0;
-    let mut test_results = HashMap::new();
-    
-    for ip in test_ips {
-        debug!("Testing connectivity to {} via {}", ip, iface);
-        let (success, latency) = measure_latency(iface, ip, 1, 2);
-        test_results.insert(ip.clone(), success);
-        
-        if success {
-            successful_tests += 1;
-            total_latency += latency;
-            debug!("Successfully reached {} via {} with latency {:.1}ms", ip, iface, latency);
+fn run_ping_test(interfaces: &[String], target: &str, count: u8, timeout: u8) -> Result<()> {
+    println!("Ping test to {} (count={}, timeout={}s)", target, count, timeout);
+    println!();
+
+    let results: Vec<(&String, Option<PingStats>)> = interfaces.iter()
+        .map(|iface| (iface, run_ping_stats(&SystemCommandRunner, iface, target, count, timeout)))
+        .collect();
+
+    // Winner is the reachable interface with the lowest average latency
+    let winner = results.iter()
+        .filter_map(|(iface, stats)| stats.as_ref().filter(|s| s.loss_pct < 100.0).map(|s| (*iface, s.avg_ms)))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(iface, _)| iface.clone());
+
+    println!("{:<16} {:>10} {:>10} {:>10} {:>10} {:>8}", "Interface", "min", "avg", "max", "mdev", "loss");
+    for (iface, stats) in &results {
+        let row = match stats {
+            Some(s) if s.loss_pct < 100.0 => format!(
+                "{:<16} {:>8.1}ms {:>8.1}ms {:>8.1}ms {:>8.1}ms {:>7.0}%",
+                iface, s.min_ms, s.avg_ms, s.max_ms, s.mdev_ms, s.loss_pct
+            ),
+            _ => format!("{:<16} {:>10} {:>10} {:>10} {:>10} {:>7}%", iface, "-", "-", "-", "-", 100),
+        };
+        if winner.as_ref() == Some(*iface) {
+            println!("\x1b[1m{}\x1b[0m", row);
         } else {
-            debug!("Failed to reach {} via {}", ip, iface);
+            println!("{}", row);
         }
     }
-    
-    let avg_latency = if successful_tests > 0 {
-        total_latency / successful_tests as f64
-    } else {
-        0.0
-    };
-    
-    // Consider interface working if at least 50% of tests succeed
-    let interface_working = successful_tests > 0 && (successful_tests as f32 / test_ips.len() as f32) >= 0.5;
-    
-    debug!("Interface {}: {} successful tests out of {}, average latency: {:.1}ms, working: {}", 
-           iface, successful_tests, test_ips.len(), avg_latency, interface_working);
-    
-    (interface_working, avg_latency, test_results)
-}
+    println!();
 
-fn update_route_for_peer(peer_ip: &str, iface: &str, gateway: Option<&String>) -> Result<()> {
-    debug!("update_route_for_peer called: peer_ip={}, iface={}, gateway={:?}", peer_ip, iface, gateway);
-    
-    // Command: ip route replace <peer_ip> [via <gateway>] dev <iface>
-    let mut cmd = Command::new("ip");
-    cmd.arg("route").arg("replace").arg(peer_ip);
-    
-    if let Some(gw) = gateway {
-        debug!("Adding gateway to route: via {}", gw);
-        cmd.arg("via").arg(gw);
-    } else {
-        debug!("No gateway specified for route");
+    match (&winner, get_route_interface(&SystemCommandRunner, target)) {
+        (Some(best), Some(active)) if *best == active => {
+            println!("Current route to {} goes via {} (the better interface)", target, active);
+        }
+        (Some(best), Some(active)) => {
+            println!("Current route to {} goes via {}, but {} has lower latency", target, active, best);
+        }
+        (None, Some(active)) => {
+            println!("Current route to {} goes via {}; no interface reached the target", target, active);
+        }
+        (_, None) => println!("Could not determine the current route to {}", target),
     }
-    
-    cmd.arg("dev").arg(iface);
-    cmd.arg("metric").arg("100");
-    
-    let cmd_str = format!("{:?}", cmd);
-    debug!("Executing route command: {}", cmd_str);
 
-    let output = cmd.output().context("Failed to execute ip route command")?;
-    
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        debug!("Route command failed with status: {}", output.status);
-        debug!("Route command stderr: {}", stderr);
-        debug!("Route command stdout: {}", stdout);
-        return Err(anyhow::anyhow!("ip route failed: {}", stderr));
-    }
-    
-    debug!("Route command succeeded with status: {}", output.status);
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    if !stdout.is_empty() {
-        debug!("Route command stdout: {}", stdout);
-    }
-    let stderr = String::from_utf8_lossy(&output.stderr);
-    if !stderr.is_empty() {
-        debug!("Route command stderr: {}", stderr);
-    }
-    
-    debug!("Updated route for {} via {} (gw: {:?})", peer_ip, iface, gateway);
     Ok(())
 }
 
-fn update_default_route(iface: &str, gateway: Option<&String>) -> Result<()> {
-    debug!("update_default_route called: iface={}, gateway={:?}", iface, gateway);
-    
-    // Command: ip route replace default [via <gateway>] dev <iface>
-    let mut cmd = Command::new("ip");
-    cmd.arg("route").arg("replace").arg("default");
-    
-    if let Some(gw) = gateway {
-        debug!("Adding gateway to default route: via {}", gw);
-        cmd.arg("via").arg(gw);
+fn load_config_file(config_path: &Path) -> Result<Option<Config>> {
+    if config_path.exists() {
+        log_with_timestamp(&format!("Configuration file exists, reading from {:?}", config_path));
+        let content = std::fs::read_to_string(config_path)
+            .context(format!("Failed to read config file {:?}", config_path))?;
+        log_with_timestamp("Configuration file read successfully, parsing TOML");
+        let config: Config = toml::from_str(&content).context("Failed to parse TOML")?;
+        debug!("Parsed config: {:?}", config);
+        config.validate().with_context(|| format!("Invalid config file {:?}", config_path))?;
+        Ok(Some(config))
     } else {
-        debug!("No gateway specified for default route");
+        log_with_timestamp("Configuration file does not exist, using command line arguments only");
+        Ok(None)
     }
-    
-    cmd.arg("dev").arg(iface);
-    cmd.arg("metric").arg("100");
-    
-    let cmd_str = format!("{:?}", cmd);
-    debug!("Executing default route command: {}", cmd_str);
+}
 
-    let output = cmd.output().context("Failed to execute ip route command")?;
-    
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        debug!("Default route command failed with status: {}", output.status);
-        debug!("Default route command stderr: {}", stderr);
-        debug!("Default route command stdout: {}", stdout);
-        return Err(anyhow::anyhow!("ip route default failed: {}", stderr));
-    }
-    
-    debug!("Default route command succeeded with status: {}", output.status);
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    if !stdout.is_empty() {
-        debug!("Default route command stdout: {}", stdout);
-    }
-    let stderr = String::from_utf8_lossy(&output.stderr);
-    if !stderr.is_empty() {
-        debug!("Default route command stderr: {}", stderr);
-    }
-    
-    debug!("Updated default route via {} (gw: {:?})", iface, gateway);
-    Ok(())
+/// Fills in any argument that was not given explicitly on the command line.
+fn apply_env_overrides(overrides: EnvOverrides, args: &mut Args) {
+    args.config = args.config.take().or(overrides.config);
+    args.peer_ip = args.peer_ip.take().or(overrides.peer_ip);
+    args.primary = args.primary.take().or(overrides.primary);
+    args.secondary = args.secondary.take().or(overrides.secondary);
+    args.interfaces = args.interfaces.take().or(overrides.interfaces);
+    args.interval = args.interval.or(overrides.interval);
+    args.speedtest_interval = args.speedtest_interval.or(overrides.speedtest_interval);
+    args.speed_threshold = args.speed_threshold.or(overrides.speed_threshold);
+    args.test_ips = args.test_ips.take().or(overrides.test_ips);
+    args.route_all_traffic = args.route_all_traffic || overrides.route_all_traffic.unwrap_or(false);
 }
 
 fn main() -> Result<()> {
+    #[cfg(feature = "gelf")]
+    gelf::init_logger();
+    #[cfg(not(feature = "gelf"))]
     env_logger::init();
     // Note: For detailed debug logging, set environment variable RUST_LOG=debug
     log_with_timestamp("Logger initialized");
     
     // 1. Load Configuration
     log_with_timestamp("Parsing command line arguments");
-    let args = Args::parse();
+    let mut args = Args::parse();
     log_with_timestamp(&format!("Command line arguments parsed: {:?}", args));
+
+    let env_overrides = EnvOverrides::from_env_prefix(&args.env_prefix)?;
+    log_with_timestamp(&format!("Environment overrides ({}_*): {:?}", args.env_prefix, env_overrides));
+    apply_env_overrides(env_overrides, &mut args);
+
+    if let Some(env_file) = args.env_file.clone() {
+        let file_overrides = EnvOverrides::from_env_file(&args.env_prefix, &env_file)?;
+        log_with_timestamp(&format!("Env file overrides from {:?}: {:?}", env_file, file_overrides));
+        apply_env_overrides(file_overrides, &mut args);
+    }
     
     log_with_timestamp("Determining configuration file path");
     let config_path = args.config.clone()
         .unwrap_or_else(|| PathBuf::from("/etc/wg-failover/config.toml"));
     log_with_timestamp(&format!("Configuration file path: {:?}", config_path));
         
-    let config_file: Option<Config> = if config_path.exists() {
-        log_with_timestamp(&format!("Configuration file exists, reading from {:?}", config_path));
-        let content = std::fs::read_to_string(&config_path)
-            .context(format!("Failed to read config file {:?}", config_path))?;
-        log_with_timestamp("Configuration file read successfully, parsing TOML");
-        let config = toml::from_str(&content).context("Failed to parse TOML")?;
-        debug!("Parsed config: {:?}", config);
-        Some(config)
-    } else {
-        log_with_timestamp("Configuration file does not exist, using command line arguments only");
-        None
-    };
+
+    if let Some(Commands::Completion { shell, output }) = &args.command {
+        let script = completion_script(*shell, &mut Args::command());
+        return match output {
+            Some(path) => std::fs::write(path, script).with_context(|| format!("Failed to write {:?}", path)),
+            None => {
+                print!("{}", script);
+                Ok(())
+            }
+        };
+    }
+
+    if let Some(Commands::Init { force }) = &args.command {
+        return run_init(&config_path, *force);
+    }
+
+    if let Some(Commands::PingTest { target, count, timeout, interface }) = &args.command {
+        let interfaces: Vec<String> = match interface {
+            Some(list) => list.split(',').map(|s| s.trim().to_string()).collect(),
+            None => {
+                let config_file = load_config_file(&config_path)?;
+                let iface_config = config_file.as_ref().and_then(|c| c.interfaces.as_ref());
+                let configured: Vec<String> = interface_slots(args.primary.clone(), args.secondary.clone(), configured_slots(&args, iface_config))
+                    .into_iter().flatten().collect();
+                if configured.is_empty() {
+                    log_with_timestamp("No interfaces configured, testing all physical interfaces");
+                    let exclude_patterns = config_file.as_ref().map(Config::exclude_interfaces).unwrap_or_default();
+                    list_physical_interfaces(&SystemCommandRunner, &exclude_patterns)
+                        .context("Could not list interfaces (pass --interface)")?
+                } else {
+                    configured
+                }
+            }
+        };
+        if interfaces.is_empty() {
+            return Err(anyhow::anyhow!("No interfaces to test (pass --interface)"));
+        }
+        return run_ping_test(&interfaces, target, *count, *timeout);
+    }
+
+    let config_file = load_config_file(&config_path)?;
 
     // Helper to extract config values with precedence: Args -> Config File -> Defaults
     log_with_timestamp("Extracting configuration values");
-    let peer_ip = args.peer_ip.clone()
-        .or_else(|| config_file.as_ref().and_then(|c| c.peer.as_ref()).and_then(|p| p.ip.clone()))
+    // With peer_from_config the peer comes from the WireGuard config, unless set explicitly
+    let wireguard_config = config_file.as_ref().and_then(|c| c.wireguard.as_ref());
+    let wg_config_path = wireguard_config
+        .filter(|w| w.peer_from_config.unwrap_or(false))
+        .map(|w| match (&w.config_file, &w.interface) {
+            (Some(path), _) => Ok(path.clone()),
+            (None, Some(iface)) => Ok(PathBuf::from(format!("/etc/wireguard/{}.conf", iface))),
+            (None, None) => Err(anyhow::anyhow!("peer_from_config requires [wireguard] interface or config_file")),
+        })
+        .transpose()?;
+    let explicit_peer_ip = args.peer_ip.clone()
+        .or_else(|| config_file.as_ref().and_then(|c| c.peer.as_ref()).and_then(|p| p.ip.clone()));
+    let wg_peer_source = wg_config_path.filter(|_| explicit_peer_ip.is_none());
+    let wg_peer_endpoint = wg_peer_source.as_deref().map(peer_from_wg_config).transpose()?;
+    if let (Some(path), Some(endpoint)) = (&wg_peer_source, wg_peer_endpoint) {
+        log_with_timestamp(&format!("Peer endpoint {} read from {:?}", endpoint, path));
+    }
+    let peer_ip = explicit_peer_ip
+        .map(|ip| IpAddress::resolve(&ip).with_context(|| format!("Invalid peer {:?}", ip)))
+        .transpose()?
+        .or_else(|| wg_peer_endpoint.map(|e| e.ip().into()))
         .context("Peer IP is required (in args or config)")?;
     log_with_timestamp(&format!("Peer IP determined: {}", peer_ip));
 
-    let primary_iface = args.primary.clone()
-        .or_else(|| config_file.as_ref().and_then(|c| c.interfaces.as_ref()).and_then(|i| i.primary.clone()))
-        .context("Primary interface is required")?;
-    log_with_timestamp(&format!("Primary interface determined: {}", primary_iface));
+    let iface_config = config_file.as_ref().and_then(|c| c.interfaces.as_ref());
+    let mut slots = interface_slots(args.primary.clone(), args.secondary.clone(), configured_slots(&args, iface_config));
+    slots.resize(slots.len().max(2), None);
+    let mut interfaces = Vec::new();
+    for (index, slot) in slots.into_iter().enumerate() {
+        let iface = slot.with_context(|| format!("{} interface is required", interface_role(index)))?;
+        log_with_timestamp(&format!("{} interface determined: {}", interface_role(index), iface));
+        interfaces.push(iface);
+    }
+    let max_interfaces = config_file.as_ref()
+        .and_then(|c| c.monitoring.as_ref())
+        .and_then(|m| m.max_interfaces)
+        .unwrap_or(DEFAULT_MAX_INTERFACES);
+    check_interface_count(interfaces.len(), max_interfaces)?;
+
+    if args.normalize_interface_names {
+        let links = list_links(&SystemCommandRunner).context("Failed to list interfaces for name normalization")?;
+        for iface in interfaces.iter_mut() {
+            let normalized = normalize_interface_name(iface, &links);
+            log_with_timestamp(&format!("Normalized interface name: {} -> {}", iface, normalized));
+            *iface = normalized;
+        }
+    }
+    for (index, iface) in interfaces.iter().enumerate() {
+        if interfaces[..index].contains(iface) {
+            return Err(anyhow::anyhow!("Interface {} is configured more than once", iface));
+        }
+    }
 
-    let secondary_iface = args.secondary.clone()
-        .or_else(|| config_file.as_ref().and_then(|c| c.interfaces.as_ref()).and_then(|i| i.secondary.clone()))
-        .context("Secondary interface is required")?;
-    log_with_timestamp(&format!("Secondary interface determined: {}", secondary_iface));
+    let exclude_patterns = config_file.as_ref().map(Config::exclude_interfaces).unwrap_or_default();
+    for iface in &interfaces {
+        if is_excluded_interface(iface, &exclude_patterns) {
+            return Err(anyhow::anyhow!(
+                "Interface {} matches exclude_interfaces {:?}, check the configuration", iface, exclude_patterns));
+        }
+    }
+
+    let preferred_interface = config_file.as_ref().and_then(|c| c.preferred_interface.clone());
+    if let Some(preferred) = preferred_interface.as_ref().filter(|p| !interfaces.contains(p)) {
+        return Err(anyhow::anyhow!(
+            "preferred_interface {} is not one of the configured interfaces {:?}", preferred, interfaces));
+    }
+    log_with_timestamp(&format!("Preferred interface: {:?}", preferred_interface));
         
     let interval_secs = args.interval
         .or_else(|| config_file.as_ref().and_then(|c| c.monitoring.as_ref()).and_then(|m| m.interval))
@@ -419,6 +856,76 @@ fn main() -> Result<()> {
         .unwrap_or(20);
     log_with_timestamp(&format!("Speed threshold determined: {}%", speed_threshold));
 
+    let max_acceptable_latency_ms = args.max_acceptable_latency_ms
+        .or_else(|| config_file.as_ref().and_then(|c| c.monitoring.as_ref()).and_then(|m| m.max_acceptable_latency_ms));
+    log_with_timestamp(&format!("Maximum acceptable latency: {:?}ms", max_acceptable_latency_ms));
+
+    let smart_recovery = config_file.as_ref()
+        .and_then(|c| c.monitoring.as_ref())
+        .and_then(|m| m.smart_recovery)
+        .unwrap_or(false);
+    let smart_recovery_success_rate = config_file.as_ref()
+        .and_then(|c| c.monitoring.as_ref())
+        .and_then(|m| m.smart_recovery_success_rate)
+        .unwrap_or(0.9);
+    log_with_timestamp(&format!("Smart recovery: {} (required success rate {:.2})", smart_recovery, smart_recovery_success_rate));
+
+    let monitoring_config = config_file.as_ref().and_then(|c| c.monitoring.as_ref());
+    let traceroute_on_failure = monitoring_config.and_then(|m| m.traceroute_on_failure).unwrap_or(false);
+    let traceroute_max_hops = monitoring_config.and_then(|m| m.traceroute_max_hops).unwrap_or(15);
+    let traceroute_min_interval = monitoring_config.and_then(|m| m.traceroute_min_interval).unwrap_or(600);
+    log_with_timestamp(&format!("Traceroute on failure: {} (max hops {}, min interval {}s)",
+        traceroute_on_failure, traceroute_max_hops, traceroute_min_interval));
+
+    let startup_delay_secs = monitoring_config.and_then(|m| m.startup_delay_secs).unwrap_or(0);
+    let startup_probes = monitoring_config.and_then(|m| m.startup_probes).unwrap_or(3);
+    log_with_timestamp(&format!("Startup delay: {}s, startup probe cycles: {}", startup_delay_secs, startup_probes));
+
+    let probe_strategy = monitoring_config.and_then(|m| m.probe_strategy).unwrap_or(ProbeStrategy::All);
+    let standby_probe_cycles = monitoring_config.and_then(|m| m.standby_probe_cycles).unwrap_or(10);
+    log_with_timestamp(&format!("Probe strategy: {:?} (standby probed every {} cycles)", probe_strategy, standby_probe_cycles));
+
+    let bandwidth_check_enabled = monitoring_config.and_then(|m| m.bandwidth_check_enabled).unwrap_or(false);
+    let min_bandwidth_mbps = monitoring_config.and_then(|m| m.min_bandwidth_mbps);
+    log_with_timestamp(&format!("Bandwidth check: {} (minimum {:?} Mbps)", bandwidth_check_enabled, min_bandwidth_mbps));
+
+    let probe_gateway_first = monitoring_config.and_then(|m| m.probe_gateway_first).unwrap_or(true);
+    log_with_timestamp(&format!("Probe gateway first: {}", probe_gateway_first));
+
+    let status_window = monitoring_config.and_then(|m| m.status_window).unwrap_or(1).max(1);
+    log_with_timestamp(&format!("Status smoothing window: {} cycles", status_window));
+
+    let min_hold_time = monitoring_config.and_then(|m| m.min_hold_time).unwrap_or(0);
+    let consecutive_better_checks = monitoring_config.and_then(|m| m.consecutive_better_checks).unwrap_or(1).max(1);
+    log_with_timestamp(&format!("Switch hysteresis: hold {}s, {} consecutive checks", min_hold_time, consecutive_better_checks));
+
+    let route_cache_ttl_secs = monitoring_config.and_then(|m| m.route_cache_ttl_secs).unwrap_or(5);
+    log_with_timestamp(&format!("Route cache TTL: {}s", route_cache_ttl_secs));
+
+    let interval_jitter_pct = monitoring_config.and_then(|m| m.interval_jitter_pct).unwrap_or(0);
+    if interval_jitter_pct > 50 {
+        return Err(anyhow::anyhow!("interval_jitter_pct must be between 0 and 50, got {}", interval_jitter_pct));
+    }
+    log_with_timestamp(&format!("Check interval jitter: +/-{}%", interval_jitter_pct));
+
+    let spread_probes = monitoring_config.and_then(|m| m.spread_probes).unwrap_or(false);
+    log_with_timestamp(&format!("Spread probes across interval: {}", spread_probes));
+
+    let auto_bring_up_interfaces = monitoring_config.and_then(|m| m.auto_bring_up_interfaces).unwrap_or(false);
+    let link_flap_cooldown_secs = monitoring_config.and_then(|m| m.link_flap_cooldown_secs).unwrap_or(DEFAULT_LINK_FLAP_COOLDOWN_SECS);
+    log_with_timestamp(&format!("Bring up interfaces found down: {} (flap cooldown {}s)", auto_bring_up_interfaces, link_flap_cooldown_secs));
+
+    let tie_break = monitoring_config.and_then(|m| m.tie_break);
+    let tie_break_epsilon_ms = monitoring_config.and_then(|m| m.tie_break_epsilon_ms).unwrap_or(DEFAULT_TIE_BREAK_EPSILON_MS);
+    log_with_timestamp(&format!("Tie-break between equally fast interfaces: {:?} (within {}ms)", tie_break, tie_break_epsilon_ms));
+
+    let scoring_weights = monitoring_config.and_then(|m| m.scoring_weights).unwrap_or_default();
+    log_with_timestamp(&format!("Health score weights: {:?}", scoring_weights));
+
+    let peer_cache_threshold = monitoring_config.and_then(|m| m.peer_cache_threshold).unwrap_or(5);
+    let peer_exclusion_secs = monitoring_config.and_then(|m| m.peer_exclusion_secs).unwrap_or(60);
+    log_with_timestamp(&format!("Peer probe exclusion: after {} failed cycles, for {}s", peer_cache_threshold, peer_exclusion_secs));
+
     // Get test IPs from args or config, default to common public DNS servers
     let test_ips = if let Some(ips_str) = args.test_ips {
         ips_str.split(',').map(|s| s.trim().to_string()).collect()
@@ -430,9 +937,12 @@ fn main() -> Result<()> {
             "8.8.8.8".to_string(),      // Google DNS
             "1.1.1.1".to_string(),      // Cloudflare DNS
             "208.67.222.222".to_string(), // OpenDNS
-            peer_ip.clone(),             // Include the WireGuard peer
+            peer_ip.to_string(),         // Include the WireGuard peer
         ]
     };
+    let include_peer_in_quorum = config_file.as_ref().and_then(|c| c.include_peer_in_quorum);
+    let test_ips: Vec<String> = test_ips.iter().map(|ip| strip_ip_brackets(ip).to_string()).collect();
+    let test_ips = apply_peer_quorum_setting(test_ips, &peer_ip.to_string(), include_peer_in_quorum)?;
     log_with_timestamp(&format!("Test IPs determined: {:?}", test_ips));
 
     let route_all_traffic = args.route_all_traffic
@@ -440,228 +950,451 @@ fn main() -> Result<()> {
     debug!("Config file route_all_traffic value: {:?}", config_file.as_ref().and_then(|c| c.route_all_traffic));
     log_with_timestamp(&format!("Route all traffic: {}", route_all_traffic));
 
-    log_with_timestamp("Creating application state");
-    let state = AppState {
+    let pre_failover_flush = config_file.as_ref().and_then(|c| c.pre_failover_flush).unwrap_or(true);
+    log_with_timestamp(&format!("Pre-failover route flush: {}", pre_failover_flush));
+
+    let reassert_routes = config_file.as_ref().and_then(|c| c.reassert_routes).unwrap_or(true);
+    log_with_timestamp(&format!("Reassert overwritten routes: {}", reassert_routes));
+
+    let maintenance_windows = config_file.as_ref()
+        .and_then(|c| c.maintenance_windows.as_ref())
+        .map(|windows| windows.iter().map(MaintenanceWindow::from_config).collect::<Result<Vec<_>>>())
+        .transpose()?
+        .unwrap_or_default();
+    log_with_timestamp(&format!("Maintenance windows: {:?}", maintenance_windows));
+
+    let post_switch_confirm_target = config_file.as_ref().and_then(|c| c.post_switch_confirm_target.clone());
+    log_with_timestamp(&format!("Post-switch confirmation target: {:?}", post_switch_confirm_target));
+
+    let post_switch_mtu_check = config_file.as_ref().and_then(|c| c.post_switch_mtu_check);
+    if let Some(mtu) = post_switch_mtu_check {
+        if !(IP_ICMP_HEADER_BYTES + 1..=9000).contains(&mtu) {
+            return Err(anyhow::anyhow!("post_switch_mtu_check must be between {} and 9000, got {}", IP_ICMP_HEADER_BYTES + 1, mtu));
+        }
+    }
+    log_with_timestamp(&format!("Post-switch MTU check: {:?}", post_switch_mtu_check));
+
+    let connection_drain_wait = config_file.as_ref().and_then(|c| c.connection_drain_wait_secs).map(Duration::from_secs);
+    log_with_timestamp(&format!("Connection drain wait: {:?}", connection_drain_wait));
+
+    let wg_endpoint_check = if config_file.as_ref().and_then(|c| c.pre_failover_wg_check).unwrap_or(false) {
+        let endpoint = match config_file.as_ref().and_then(|c| c.peer.as_ref()).and_then(|p| p.endpoint.as_ref()) {
+            Some(endpoint) => endpoint.parse()
+                .with_context(|| format!("Invalid WireGuard endpoint {:?}, expected ip:port", endpoint))?,
+            None => wg_peer_endpoint.context("pre_failover_wg_check requires [peer] endpoint")?,
+        };
+        let timeout = config_file.as_ref().and_then(|c| c.wg_probe_timeout_secs).unwrap_or(3).max(1);
+        Some((endpoint, Duration::from_secs(timeout)))
+    } else {
+        None
+    };
+    log_with_timestamp(&format!("Pre-failover WireGuard endpoint check: {:?}", wg_endpoint_check));
+
+    if let Some(endpoint) = config_file.as_ref().and_then(|c| c.gelf_endpoint.as_ref()) {
+        #[cfg(feature = "gelf")]
+        {
+            gelf::set_endpoint(endpoint)?;
+            log_with_timestamp(&format!("Sending logs to GELF endpoint {}", endpoint));
+        }
+        #[cfg(not(feature = "gelf"))]
+        warn!("gelf_endpoint {} is set, but this build does not include the gelf feature", endpoint);
+    }
+
+    let route_exclusions = config_file.as_ref().and_then(|c| c.route_exclusions.clone()).unwrap_or_default();
+    for exclusion in &route_exclusions {
+        exclusion.validate()?;
+    }
+    if !route_exclusions.is_empty() && !route_all_traffic {
+        warn!("route_exclusions only apply with route_all_traffic enabled, ignoring them");
+    }
+    log_with_timestamp(&format!("Route-all exclusions: {:?}", route_exclusions));
+
+    let failover_to_no_route = config_file.as_ref().and_then(|c| c.failover_to_no_route).unwrap_or(false);
+    if failover_to_no_route && route_all_traffic {
+        warn!("failover_to_no_route only applies to the peer route, ignoring it with route_all_traffic");
+    }
+    log_with_timestamp(&format!("Remove peer route when all interfaces fail: {}", failover_to_no_route));
+
+    let probe_namespaces = config_file.as_ref().and_then(|c| c.probe_namespaces.as_ref());
+    let connectivity_netns = probe_namespaces.and_then(|n| n.connectivity.clone());
+    let speed_netns = probe_namespaces.and_then(|n| n.speed.clone());
+    log_with_timestamp(&format!("Probe namespaces - Connectivity: {:?}, Speed: {:?}", connectivity_netns, speed_netns));
+
+    let probe_timeouts = match config_file.as_ref().and_then(|c| c.probe_timeouts.as_ref()) {
+        Some(raw) => parse_probe_timeouts(raw)?,
+        None => HashMap::new(),
+    };
+    let ping_timeout = config_file.as_ref()
+        .and_then(|c| c.monitoring.as_ref())
+        .and_then(|m| m.ping_timeout)
+        .unwrap_or(DEFAULT_PING_TIMEOUT);
+    log_with_timestamp(&format!("Probe timeouts: {:?} (default {}s)", probe_timeouts, ping_timeout));
+
+    let probe_src_addrs = iface_config.map(InterfacesSection::probe_src_addrs).unwrap_or_default();
+    log_with_timestamp(&format!("Probe source address overrides: {:?}", probe_src_addrs));
+
+    let max_loss_pct = config_file.as_ref()
+        .and_then(|c| c.monitoring.as_ref())
+        .and_then(|m| m.max_loss_pct)
+        .unwrap_or(DEFAULT_MAX_LOSS_PCT);
+    let interface_max_loss_pct = iface_config.map(InterfacesSection::max_loss_pcts).unwrap_or_default();
+    log_with_timestamp(&format!("Maximum packet loss: {}% (per interface: {:?})", max_loss_pct, interface_max_loss_pct));
+
+    let capture = if config_file.as_ref().and_then(|c| c.rolling_capture).unwrap_or(false) {
+        let config = config_file.as_ref().expect("rolling_capture comes from the config file");
+        let settings = CaptureSettings {
+            interface: config.capture_interface.clone(),
+            dir: config.capture_dir.clone().unwrap_or_else(|| PathBuf::from("/var/lib/wg-failover/captures")),
+            max_file_size_mb: config.capture_max_file_size_mb.unwrap_or(100),
+            retention_count: config.capture_retention_count.unwrap_or(5),
+        };
+        if settings.max_file_size_mb == 0 || settings.retention_count == 0 {
+            return Err(anyhow::anyhow!("capture_max_file_size_mb and capture_retention_count must be at least 1"));
+        }
+        std::fs::create_dir_all(&settings.dir)
+            .with_context(|| format!("Failed to create capture directory {:?}", settings.dir))?;
+        log_with_timestamp(&format!("Rolling capture: {} x {}MB in {:?} on {}",
+            settings.retention_count, settings.max_file_size_mb, settings.dir,
+            settings.interface.as_deref().unwrap_or("the active interface")));
+        Some(settings)
+    } else {
+        None
+    };
+
+    let switch_hooks = SwitchHooks {
+        command: args.on_switch.clone().or_else(|| config_file.as_ref().and_then(|c| c.on_switch.clone())),
+        webhook: args.on_switch_webhook.clone()
+            .or_else(|| config_file.as_ref().and_then(|c| c.on_switch_webhook.clone()))
+            .map(|url| WebhookUrl::parse(&url))
+            .transpose()?,
+        timing: config_file.as_ref().and_then(|c| c.hook_timing).unwrap_or_default(),
+        pre_hook_aborts: config_file.as_ref().and_then(|c| c.pre_hook_aborts_switch).unwrap_or(true),
+        timeout: Duration::from_secs(config_file.as_ref().and_then(|c| c.hook_timeout_secs).unwrap_or(DEFAULT_HOOK_TIMEOUT_SECS).max(1)),
+    };
+    if let Some(command) = &switch_hooks.command {
+        if let Err(e) = validate_hook_command(command) {
+            if config_file.as_ref().and_then(|c| c.strict_hook_validation).unwrap_or(false) {
+                return Err(e);
+            }
+            warn!("{:#}; the on_switch hook will fail when it runs", e);
+        }
+    }
+    log_with_timestamp(&format!("Switch hooks: command {:?} ({:?}), webhook {:?}, timeout {:?}",
+        switch_hooks.command, switch_hooks.timing, switch_hooks.webhook.as_ref().map(WebhookUrl::host_header), switch_hooks.timeout));
+
+    let send_gratuitous_arp = config_file.as_ref().and_then(|c| c.send_gratuitous_arp).unwrap_or(false);
+    log_with_timestamp(&format!("Announce addresses after a switch: {}", send_gratuitous_arp));
+
+    let state_dump_file = config_file.as_ref().and_then(|c| c.state_dump_file.clone());
+    log_with_timestamp(&format!("SIGUSR2 state dump target: {}",
+        state_dump_file.as_ref().map_or("log".to_string(), |p| format!("{:?}", p))));
+
+    let state_file = if config_file.as_ref().and_then(|c| c.persist_state).unwrap_or(true) {
+        Some(config_file.as_ref().and_then(|c| c.state_file.clone()).unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_FILE)))
+    } else {
+        None
+    };
+    log_with_timestamp(&format!("Active interface state file: {:?}", state_file));
+
+    // Pin gateway MACs on links where ARP to the gateway is unreliable (some LTE modems)
+    let static_arp: Vec<(String, String)> = config_file.as_ref()
+        .and_then(|c| c.static_arp.as_ref())
+        .map(|entries| entries.iter().map(|(ip, mac)| (ip.clone(), mac.clone())).collect())
+        .unwrap_or_default();
+    for (ip, mac) in &static_arp {
+        if ip.parse::<IpAddr>().is_err() || !is_valid_mac(mac) {
+            return Err(anyhow::anyhow!("Invalid static_arp entry {} = {:?}, expected IP = \"aa:bb:cc:dd:ee:ff\"", ip, mac));
+        }
+    }
+
+    let config = FailoverConfig {
         peer_ip,
-        primary_iface,
-        secondary_iface,
+        interfaces,
         test_ips,
         check_interval: Duration::from_secs(interval_secs),
         speed_check_interval: Duration::from_secs(speed_interval_secs),
         speed_threshold,
+        tie_break,
+        tie_break_epsilon_ms,
+        scoring_weights,
+        max_acceptable_latency_ms,
+        smart_recovery,
+        smart_recovery_success_rate,
+        traceroute_on_failure,
+        traceroute_max_hops,
+        traceroute_min_interval: Duration::from_secs(traceroute_min_interval),
+        startup_delay: Duration::from_secs(startup_delay_secs),
+        startup_probes,
+        probe_strategy,
+        standby_probe_cycles,
+        bandwidth_check_enabled,
+        min_bandwidth_mbps,
+        probe_gateway_first,
+        status_window,
+        min_hold_time: Duration::from_secs(min_hold_time),
+        consecutive_better_checks,
+        route_cache_ttl: Duration::from_secs(route_cache_ttl_secs),
+        interval_jitter_pct,
+        spread_probes,
+        auto_bring_up_interfaces,
+        link_flap_cooldown: Duration::from_secs(link_flap_cooldown_secs),
+        state_dump_file,
+        state_file,
+        capture,
+        switch_hooks,
+        send_gratuitous_arp,
+        peer_cache_threshold,
+        peer_exclusion: Duration::from_secs(peer_exclusion_secs),
         route_all_traffic,
+        pre_failover_flush,
+        reassert_routes,
+        maintenance_windows,
+        post_switch_confirm_target,
+        post_switch_mtu_check,
+        connection_drain_wait,
+        wg_endpoint_check,
+        route_exclusions,
+        failover_to_no_route,
+        probe_src_addrs,
+        max_loss_pct,
+        interface_max_loss_pct,
+        preferred_interface,
+        connectivity_netns,
+        speed_netns,
+        ping_timeout,
+        probe_timeouts,
+        static_arp,
+        external_control_file: config_file.as_ref().and_then(|c| c.external_control_file.clone()),
+        env_file: args.env_file.clone(),
+        env_prefix: args.env_prefix.clone(),
+        wg_config_file: wg_peer_source,
     };
-    log_with_timestamp("Application state created successfully");
-
-    log_with_timestamp("Starting WireGuard Failover (Multiple IP Test Mode)");
-    info!("Peer: {}", state.peer_ip);
-    info!("Primary: {}, Secondary: {}", state.primary_iface, state.secondary_iface);
-    info!("Test IPs: {:?}", state.test_ips);
-    info!("Route all traffic: {}", state.route_all_traffic);
-    info!("Intervals - Check: {:?}, Speed: {:?}", state.check_interval, state.speed_check_interval);
-    log_with_timestamp("Initialization complete, entering main loop");
-
-    log_with_timestamp("Initializing metrics and state variables");
-    let mut primary_metrics = InterfaceMetrics::default();
-    let mut secondary_metrics = InterfaceMetrics::default();
-    log_with_timestamp("Metrics initialized to default values");
-    
-    // Force check on start if possible, otherwise start timer now
-    log_with_timestamp("Setting up speed check timer");
-    let mut last_speed_check = Instant::now()
-        .checked_sub(state.speed_check_interval)
-        .unwrap_or(Instant::now());
-    log_with_timestamp(&format!("Last speed check time set to: {:?}", last_speed_check));
-    
-    let mut current_active_interface: Option<String> = None;
-    log_with_timestamp("Current active interface initialized to None");
+    config.validate()?;
+    if args.once_then_watch && config.external_control_file.is_some() {
+        return Err(anyhow::anyhow!("--once-then-watch cannot be used with external_control_file, which never probes"));
+    }
+    log_with_timestamp("Configuration resolved successfully");
 
-    loop {
-        log_with_timestamp("Starting main loop iteration");
-        let now = Instant::now();
-        log_with_timestamp(&format!("Current time instant: {:?}", now));
-        
-        // ----------------------------------------
-        // 1. Identify Gateways (Dynamic, in case of network changes)
-        // ----------------------------------------
-        log_with_timestamp("Identifying gateways for interfaces");
-        let primary_gw = get_gateway_for_interface(&state.primary_iface);
-        let secondary_gw = get_gateway_for_interface(&state.secondary_iface);
-        log_with_timestamp(&format!("Primary gateway: {:?}, Secondary gateway: {:?}", primary_gw, secondary_gw));
-
-        // ----------------------------------------
-        // 2. Connectivity Check (Frequent) - Multiple IPs
-        // ----------------------------------------
-        log_with_timestamp("Starting connectivity checks with multiple IPs");
-        log_with_timestamp(&format!("Checking connectivity via primary interface: {}", state.primary_iface));
-        let (p_ok, p_lat, p_results) = test_connectivity_multiple_ips(&state.primary_iface, &state.test_ips);
-        log_with_timestamp(&format!("Primary interface connectivity result: success={}, average latency={:.1}ms", p_ok, p_lat));
-        
-        log_with_timestamp(&format!("Checking connectivity via secondary interface: {}", state.secondary_iface));
-        let (s_ok, s_lat, s_results) = test_connectivity_multiple_ips(&state.secondary_iface, &state.test_ips);
-        log_with_timestamp(&format!("Secondary interface connectivity result: success={}, average latency={:.1}ms", s_ok, s_lat));
-
-        log_with_timestamp("Updating metrics based on connectivity results");
-        primary_metrics.status = if p_ok { InterfaceStatus::Working } else { InterfaceStatus::Failed };
-        primary_metrics.connectivity_latency_ms = p_lat;
-        primary_metrics.test_results = p_results;
-        log_with_timestamp(&format!("Primary metrics updated: status={:?}, latency={:.1}ms", primary_metrics.status, primary_metrics.connectivity_latency_ms));
-        
-        secondary_metrics.status = if s_ok { InterfaceStatus::Working } else { InterfaceStatus::Failed };
-        secondary_metrics.connectivity_latency_ms = s_lat;
-        secondary_metrics.test_results = s_results;
-        log_with_timestamp(&format!("Secondary metrics updated: status={:?}, latency={:.1}ms", secondary_metrics.status, secondary_metrics.connectivity_latency_ms));
-
-        // Log detailed test results
-        for (ip, p_reachable) in &primary_metrics.test_results {
-            let s_reachable = secondary_metrics.test_results.get(ip).unwrap_or(&false);
-            debug!("IP {}: Primary={}, Secondary={}", ip, p_reachable, s_reachable);
-        }
-
-        // ----------------------------------------
-        // 3. Speed Check (Periodic)
-        // ----------------------------------------
-        // Only if both are working, we might want to run a heavier test to decide best path.
-        log_with_timestamp("Checking if speed test is due");
-        let time_since_last_speed_check = now.duration_since(last_speed_check);
-        log_with_timestamp(&format!("Time since last speed check: {:?}, required interval: {:?}", time_since_last_speed_check, state.speed_check_interval));
-        
-        if now.duration_since(last_speed_check) >= state.speed_check_interval {
-            log_with_timestamp("Speed check interval reached, performing speed/quality check...");
-            if primary_metrics.status == InterfaceStatus::Working && secondary_metrics.status == InterfaceStatus::Working {
-                log_with_timestamp("Both interfaces working, running detailed latency measurements");
-                // Run heavier ping to peer IP for speed comparison
-                log_with_timestamp("Measuring detailed latency on primary interface to peer");
-                let (_, p_avg) = measure_latency(&state.primary_iface, &state.peer_ip, 5, 5);
-                log_with_timestamp("Measuring detailed latency on secondary interface to peer");
-                let (_, s_avg) = measure_latency(&state.secondary_iface, &state.peer_ip, 5, 5);
-                
-                primary_metrics.speed_latency_ms = p_avg;
-                secondary_metrics.speed_latency_ms = s_avg;
-                
-                info!("Speed/Latency Result - {}: {:.1}ms, {}: {:.1}ms", 
-                     state.primary_iface, p_avg, state.secondary_iface, s_avg);
-                log_with_timestamp("Speed metrics updated successfully");
-            } else {
-                log_with_timestamp("Skipping speed test because at least one interface is not working");
-            }
-            last_speed_check = now;
-            log_with_timestamp(&format!("Last speed check time updated to: {:?}", last_speed_check));
-        } else {
-            log_with_timestamp("Speed check not due yet, skipping");
-        }
-
-        // ----------------------------------------
-        // 4. Decision Logic
-        // ----------------------------------------
-        log_with_timestamp("Starting decision logic for interface selection");
-        let target_interface = match (&primary_metrics.status, &secondary_metrics.status) {
-            (InterfaceStatus::Working, InterfaceStatus::Failed) => {
-                log_with_timestamp("Decision: Primary works, secondary fails -> Selecting Primary");
-                // Primary works, secondary fails -> Primary
-                Some((&state.primary_iface, &primary_gw))
-            },
-            (InterfaceStatus::Failed, InterfaceStatus::Working) => {
-                log_with_timestamp("Decision: Primary fails, secondary works -> Selecting Secondary");
-                // Primary fails, secondary works -> Secondary
-                Some((&state.secondary_iface, &secondary_gw))
-            },
-            (InterfaceStatus::Working, InterfaceStatus::Working) => {
-                log_with_timestamp("Decision: Both interfaces working, evaluating speed");
-                // Both work. Check preference and speed.
-                // Default is primary.
-                // If secondary is significantly faster (lower latency), switch.
-                // Note: "Faster" here uses latency as proxy. Lower is better.
-                
-                let p_lat = primary_metrics.speed_latency_ms;
-                let s_lat = secondary_metrics.speed_latency_ms;
-                log_with_timestamp(&format!("Speed latencies - Primary: {:.1}ms, Secondary: {:.1}ms", p_lat, s_lat));
-                
-                // If we are currently on Primary, only switch if Secondary is MUCH better (lower latency)
-                // Threshold is percentage.
-                // If Secondary is < Primary * (1 - threshold/100)
-                let threshold_factor = 1.0 - (state.speed_threshold as f64 / 100.0);
-                log_with_timestamp(&format!("Speed threshold factor: {:.2} (threshold: {}%)", threshold_factor, state.speed_threshold));
-                
-                if s_lat > 0.0 && p_lat > 0.0 && s_lat < (p_lat * threshold_factor) {
-                    log_with_timestamp(&format!("Secondary is significantly faster ({} < {} * {}), switching to Secondary", s_lat, p_lat, threshold_factor));
-                    info!("Secondary {} ({:.1}ms) is significantly faster than Primary {} ({:.1}ms). Switching.", 
-                          state.secondary_iface, s_lat, state.primary_iface, p_lat);
-                    Some((&state.secondary_iface, &secondary_gw))
-                } else {
-                    log_with_timestamp("Secondary not significantly faster or speed data unavailable, sticking with Primary");
-                    // Stick with Primary usually
-                    Some((&state.primary_iface, &primary_gw))
-                }
-            },
-            (InterfaceStatus::Failed, InterfaceStatus::Failed) => {
-                log_with_timestamp("Decision: Both interfaces failed");
-                warn!("Both interfaces failed connectivity check.");
-                // Keep existing or do nothing?
-                // If we do nothing, we stay on the last set route.
-                None
-            },
-            _ => {
-                log_with_timestamp("Decision: Unknown interface status combination, no target selected");
-                None
-            }
+    let mut monitor = FailoverMonitor::new(config);
+    if args.simulate_network {
+        let network = match &args.simulation_config {
+            Some(path) => SimulatedNetwork::from_file(path)?,
+            None => SimulatedNetwork::default(),
         };
-        log_with_timestamp(&format!("Decision result: target_interface = {:?}", target_interface));
-
-        // ----------------------------------------
-        // 5. Apply Route Change
-        // ----------------------------------------
-        log_with_timestamp("Evaluating route changes");
-        if let Some((target_iface, target_gw)) = target_interface {
-            log_with_timestamp(&format!("Target interface selected: {}, gateway: {:?}", target_iface, target_gw));
-            let should_update = match &current_active_interface {
-                Some(current) => {
-                    let update_needed = current != target_iface;
-                    log_with_timestamp(&format!("Current active interface: {}, update needed: {}", current, update_needed));
-                    update_needed // Changed interface
-                },
-                None => {
-                    log_with_timestamp("No current active interface (first run), update needed");
-                    true // First run
-                },
-            };
-
-            if should_update {
-                if state.route_all_traffic {
-                    log_with_timestamp(&format!("Routing ALL traffic via {}", target_iface));
-                    match update_default_route(target_iface, target_gw.as_ref()) {
-                        Ok(_) => {
-                            current_active_interface = Some(target_iface.clone());
-                            log_with_timestamp("Default route updated successfully.");
-                        },
-                        Err(e) => {
-                            error!("Failed to update default route: {}", e);
-                            log_with_timestamp(&format!("Default route update failed with error: {}", e));
-                        }
-                    }
-                } else {
-                    log_with_timestamp(&format!("Routing WireGuard Peer {} via {}", state.peer_ip, target_iface));
-                    match update_route_for_peer(&state.peer_ip, target_iface, target_gw.as_ref()) {
-                        Ok(_) => {
-                            current_active_interface = Some(target_iface.clone());
-                            log_with_timestamp("Peer route updated successfully.");
-                        },
-                        Err(e) => {
-                            error!("Failed to update peer route: {}", e);
-                            log_with_timestamp(&format!("Peer route update failed with error: {}", e));
-                        }
-                    }
-                }
-            } else {
-                log_with_timestamp("No route change needed, interface unchanged");
-            }
-        } else {
-            log_with_timestamp("No target interface selected, skipping route update");
+        warn!("Simulating the network, no real interfaces or routes are touched");
+        monitor = monitor.with_command_runner(network);
+    }
+
+    if let Some(Commands::ExplainDecision) = &args.command {
+        print!("{}", monitor.explain_decision()?);
+        return Ok(());
+    }
+
+    if let Some(Commands::Audit) = &args.command {
+        print!("{}", monitor.audit_routes()?);
+        return Ok(());
+    }
+
+    if args.summary {
+        let color = args.color.enabled();
+        monitor = monitor.with_cycle_hook(move |status| println!("{}", status.summary_line(color)));
+    }
+
+    // Keep the daemon from competing with packet forwarding on small routers
+    if let Some(cpus) = config_file.as_ref().and_then(|c| c.cpu_affinity.as_ref()) {
+        set_cpu_affinity(cpus).with_context(|| format!("Failed to set CPU affinity to {:?}", cpus))?;
+        info!("Pinned to CPUs {:?}", cpus);
+    }
+    if let Some(nice_level) = config_file.as_ref().and_then(|c| c.nice_level) {
+        match set_nice_level(nice_level) {
+            Ok(()) => log_with_timestamp(&format!("Nice level set to {}", nice_level)),
+            Err(e) => warn!("Failed to set nice level {}: {}", nice_level, e),
         }
+    }
 
-        // Sleep
-        log_with_timestamp(&format!("Sleeping for {:?} before next iteration", state.check_interval));
-        thread::sleep(state.check_interval);
-        log_with_timestamp("Awake from sleep, starting next loop iteration");
+    if args.once_then_watch {
+        monitor.commit_initial_route();
     }
-}
\ No newline at end of file
+
+    if args.daemon {
+        info!("Detaching into the background");
+        daemonize(args.pid_file.as_deref()).context("Failed to detach into the background")?;
+    }
+
+    install_shutdown_handler();
+    monitor.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wg_failover::config::FailoverConfigBuilder;
+
+    #[test]
+    fn env_overrides_do_not_replace_explicit_args() {
+        let mut args = Args::parse_from(["wg-failover", "--peer-ip", "192.0.2.10"]);
+        let env = EnvOverrides {
+            peer_ip: Some("203.0.113.1".to_string()),
+            primary: Some("eth0".to_string()),
+            ..Default::default()
+        };
+        apply_env_overrides(env, &mut args);
+
+        assert_eq!(args.peer_ip.as_deref(), Some("192.0.2.10"));
+        assert_eq!(args.primary.as_deref(), Some("eth0"));
+    }
+
+    #[test]
+    fn nice_level_is_optional_and_range_checked() {
+        let parse = |text: &str| toml::from_str::<Config>(text).unwrap();
+        assert_eq!(parse("").nice_level, None);
+        assert!(parse("nice_level = 10").validate().is_ok());
+        assert!(parse("nice_level = 25").validate().is_err());
+    }
+
+    #[test]
+    fn init_config_parses_back_to_the_chosen_values() {
+        let test_ips = vec!["1.1.1.1".to_string(), "203.0.113.1".to_string()];
+        let content = render_init_config("eno1", "wwan0", "203.0.113.1", &test_ips);
+        let config: Config = toml::from_str(&content).unwrap();
+
+        assert_eq!(
+            config.interfaces.unwrap().slots(),
+            vec![Some("eno1".to_string()), Some("wwan0".to_string())]
+        );
+        assert_eq!(config.peer.unwrap().ip.as_deref(), Some("203.0.113.1"));
+        assert_eq!(config.test_ips, Some(test_ips));
+    }
+
+    #[test]
+    fn completion_scripts_cover_subcommands_and_flags() {
+        let bash = completion_script(Shell::Bash, &mut Args::command());
+        assert!(bash.contains("wg__failover,ping-test)"));
+        assert!(bash.contains("--peer-ip"));
+        assert!(bash.contains("--primary|-p|--secondary|-s|--interfaces|--interface) COMPREPLY"));
+        assert!(bash.contains("    _wg-failover \"$@\"\n"));
+        assert!(bash.ends_with("complete -F _wg_failover_interfaces -o bashdefault -o default wg-failover\n"));
+        // Every subcommand state the function can reach has a branch
+        for state in bash.lines().filter_map(|l| l.trim().strip_prefix("cmd=\"")).map(|l| l.trim_end_matches('"')).filter(|s| !s.is_empty()) {
+            assert!(bash.contains(&format!("        {})\n", state)), "no branch for {}", state);
+        }
+
+        let zsh = completion_script(Shell::Zsh, &mut Args::command());
+        assert!(zsh.contains("'--primary=[Primary network interface (e.g., eth0)]:PRIMARY:_net_interfaces'"));
+        assert!(zsh.contains(":INTERFACE:_net_interfaces'"));
+        assert!(!zsh.contains(":SECONDARY:_default'"));
+
+        let fish = completion_script(Shell::Fish, &mut Args::command());
+        assert!(fish.contains("complete -c wg-failover -l interface -x -a '(ls /sys/class/net)'"));
+        assert!(fish.contains("\"explain-decision\""));
+    }
+
+    #[test]
+    fn interface_list_is_ordered_by_priority() {
+        let config: Config = toml::from_str(r#"
+            exclude_interfaces = ["docker*"]
+
+            [[interfaces]]
+            name = "wwan0"
+            priority = 30
+
+            [[interfaces]]
+            name = "wlan0"
+
+            [[interfaces]]
+            name = "eth0"
+            priority = 10
+            probe_src_addr = "192.0.2.10"
+            max_loss_pct = 20
+        "#).unwrap();
+        assert_eq!(config.exclude_interfaces(), ["docker*"]);
+        let section = config.interfaces.unwrap();
+        let names = |slots: Vec<Option<String>>| slots.into_iter().flatten().collect::<Vec<_>>();
+
+        assert_eq!(names(section.slots()), ["eth0", "wwan0", "wlan0"]);
+        assert_eq!(section.probe_src_addrs().get("eth0").map(String::as_str), Some("192.0.2.10"));
+        assert_eq!(section.max_loss_pcts(), HashMap::from([("eth0".to_string(), 20)]));
+        // --secondary moves an interface up instead of listing it twice
+        assert_eq!(
+            names(interface_slots(None, Some("wlan0".to_string()), section.slots())),
+            ["eth0", "wlan0", "wwan0"]
+        );
+        assert_eq!(
+            interface_slots(None, Some("wlan0".to_string()), Vec::new()),
+            vec![None, Some("wlan0".to_string())]
+        );
+    }
+
+    const SAMPLE_CONFIG: &str = r#"
+test_ips = ["1.1.1.1", "8.8.8.8"]
+route_all_traffic = true
+
+[peer]
+ip = "10.0.0.1"
+
+[interfaces]
+primary = "eno1"
+secondary = "wwan0"
+
+[monitoring]
+interval = 5
+max_acceptable_latency_ms = 150.0
+smart_recovery_success_rate = 0.8
+
+[[route_exclusions]]
+prefix = "192.168.1.0/24"
+"#;
+
+    #[test]
+    fn configs_parsed_from_same_source_compare_equal() {
+        let a: Config = toml::from_str(SAMPLE_CONFIG).unwrap();
+        let b: Config = toml::from_str(SAMPLE_CONFIG).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn configs_differing_in_one_field_compare_unequal() {
+        let a: Config = toml::from_str(SAMPLE_CONFIG).unwrap();
+
+        let mut b = a.clone();
+        b.route_all_traffic = Some(false);
+        assert_ne!(a, b);
+
+        let mut b = a.clone();
+        let Some(InterfacesSection::Table(interfaces)) = b.interfaces.as_mut() else {
+            panic!("sample config uses the [interfaces] table");
+        };
+        interfaces.secondary = Some("wwan1".to_string());
+        assert_ne!(a, b);
+
+        let mut b = a.clone();
+        b.monitoring.as_mut().unwrap().max_acceptable_latency_ms = Some(150.5);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn monitoring_config_floats_compare_with_tolerance() {
+        let a: Config = toml::from_str(SAMPLE_CONFIG).unwrap();
+        let mut b = a.clone();
+        b.monitoring.as_mut().unwrap().smart_recovery_success_rate = Some(0.8 + 1e-12);
+        assert_eq!(a, b);
+        b.monitoring.as_mut().unwrap().smart_recovery_success_rate = None;
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn failover_config_clone_compares_equal_until_a_field_changes() {
+        let config = FailoverConfigBuilder::new().peer_ip("203.0.113.1").interfaces(["eth0", "wlan0"]).build().unwrap();
+        assert_eq!(config.clone(), config);
+
+        let mut changed = config.clone();
+        changed.interfaces.push("wwan0".to_string());
+        assert_ne!(changed, config);
+
+        let mut changed = config.clone();
+        changed.smart_recovery_success_rate += 1e-12;
+        assert_eq!(changed, config);
+        changed.min_bandwidth_mbps = Some(1.0);
+        assert_ne!(changed, config);
+    }
+}
@@ -0,0 +1,375 @@
+//! A simulated network for running the daemon without real interfaces, e.g.
+//! in CI: sim0, sim1 and sim2 answer `ip`, `ping` and their sysfs files with
+//! configured latency and loss, and go down and come back at configured times. [`MockClock`]
+//! lets tests move time forward for the monitor and the network together.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::net::SocketAddr;
+use std::process::{Child, ExitStatus, Output};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::network::CommandRunner;
+
+/// A [`Clock`] that only moves when told to. Clones share the same time, so
+/// a test can keep one and hand another to the monitor.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<(Instant, DateTime<Utc>)>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    /// Starts at the current system time.
+    pub fn new() -> Self {
+        Self { now: Arc::new(Mutex::new((Instant::now(), Utc::now()))) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        now.0 += by;
+        now.1 += chrono::Duration::from_std(by).expect("MockClock advanced by an absurd duration");
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.now.lock().unwrap_or_else(|e| e.into_inner()).0
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        self.now.lock().unwrap_or_else(|e| e.into_inner()).1
+    }
+
+    // Returns at once, so a monitor under test runs as fast as it can
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// One simulated uplink, as given in the simulation config.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimulatedInterface {
+    #[serde(default)]
+    pub latency_ms: f64,
+    #[serde(default)]
+    pub loss: f64, // fraction of pings lost, 0.0 to 1.0
+    #[serde(default)]
+    pub outages: Vec<OutageEvent>,
+    #[serde(default)]
+    pub ipv6_broken: bool, // IPv6 pings go unanswered while IPv4 works
+}
+
+/// The interface stops answering `at_secs` after start, for `duration_secs`
+/// (for good if not given).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutageEvent {
+    pub at_secs: u64,
+    pub duration_secs: Option<u64>,
+}
+
+impl SimulatedInterface {
+    fn down_at(&self, elapsed: Duration) -> bool {
+        self.outages.iter().any(|outage| {
+            let start = Duration::from_secs(outage.at_secs);
+            elapsed >= start && outage.duration_secs.is_none_or(|d| elapsed < start + Duration::from_secs(d))
+        })
+    }
+}
+
+/// A [`CommandRunner`] that keeps a routing table in memory and answers pings
+/// from the configured interface parameters instead of running anything.
+/// Interface N has address 10.200.N.2/24 and gateway 10.200.N.1, and for
+/// IPv6 fd00:200:N::2/64 and gateway fd00:200:N::1.
+#[derive(Debug)]
+pub struct SimulatedNetwork {
+    interfaces: BTreeMap<String, SimulatedInterface>,
+    clock: Arc<dyn Clock>,
+    started: Instant,
+    routes: Mutex<HashMap<(bool, String), String>>, // (IPv6, destination) -> interface
+    // Fixed seed, so runs are repeatable
+    rng: Mutex<u64>,
+}
+
+impl Default for SimulatedNetwork {
+    fn default() -> Self {
+        let iface = |latency_ms| SimulatedInterface { latency_ms, loss: 0.0, outages: Vec::new(), ipv6_broken: false };
+        Self::new(BTreeMap::from([
+            ("sim0".to_string(), iface(10.0)),
+            ("sim1".to_string(), iface(30.0)),
+            ("sim2".to_string(), iface(50.0)),
+        ]))
+    }
+}
+
+impl SimulatedNetwork {
+    pub fn new(interfaces: BTreeMap<String, SimulatedInterface>) -> Self {
+        Self { interfaces, clock: Arc::new(SystemClock), started: Instant::now(), routes: Mutex::new(HashMap::new()), rng: Mutex::new(0) }
+    }
+
+    /// Times outages by `clock` instead of the system clock, starting now.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.started = clock.now();
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Parses a simulation config such as
+    /// `{"sim0": {"latency_ms": 10, "loss": 0.0}, "sim1": {"latency_ms": 50, "loss": 0.1,
+    /// "outages": [{"at_secs": 60, "duration_secs": 30}]}}`.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let interfaces: BTreeMap<String, SimulatedInterface> = serde_json::from_str(json).context("Invalid simulation config")?;
+        for (name, iface) in &interfaces {
+            if !(0.0..=1.0).contains(&iface.loss) {
+                return Err(anyhow::anyhow!("Simulated interface {}: loss must be between 0.0 and 1.0, got {}", name, iface.loss));
+            }
+            if iface.latency_ms < 0.0 {
+                return Err(anyhow::anyhow!("Simulated interface {}: latency_ms must not be negative", name));
+            }
+        }
+        Ok(Self::new(interfaces))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path).with_context(|| format!("Failed to read simulation config {:?}", path))?;
+        Self::from_json(&json).with_context(|| format!("In {:?}", path))
+    }
+
+    // Position in the sorted names, for the made-up addresses
+    fn index(&self, iface: &str) -> Option<usize> {
+        self.interfaces.keys().position(|name| name == iface)
+    }
+
+    fn up(&self, iface: &str, elapsed: Duration) -> bool {
+        self.interfaces.get(iface).is_some_and(|i| !i.down_at(elapsed))
+    }
+
+    // splitmix64, uniform in [0, 1)
+    fn random(&self) -> f64 {
+        let mut state = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (z ^ (z >> 31)) as f64 / 2f64.powi(64)
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.started)
+    }
+
+    // The sysfs files carrier_up and the neighbour announcements read, and a
+    // /proc/net/tcp without connections
+    fn read_at(&self, elapsed: Duration, path: &Path) -> io::Result<String> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("{:?}: not in the simulated network", path));
+        if path == Path::new("/proc/net/tcp") || path == Path::new("/proc/net/tcp6") {
+            return Ok("  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n".to_string());
+        }
+        let Ok(rest) = path.strip_prefix("/sys/class/net") else {
+            return Err(not_found());
+        };
+        let mut parts = rest.iter().filter_map(|p| p.to_str());
+        let (Some(iface), Some(file), None) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(not_found());
+        };
+        let n = self.index(iface).ok_or_else(not_found)?;
+        let up = self.up(iface, elapsed);
+        match file {
+            "carrier" => Ok(if up { "1\n" } else { "0\n" }.to_string()),
+            "operstate" => Ok(if up { "up\n" } else { "down\n" }.to_string()),
+            // Outages pull the cable; the interface itself stays administratively up
+            "flags" => Ok("0x1003\n".to_string()),
+            "address" => Ok(format!("02:00:00:00:00:{:02x}\n", n)),
+            _ => Err(not_found()),
+        }
+    }
+
+    fn run_at(&self, elapsed: Duration, program: &str, args: &[&str]) -> Output {
+        match (program, args) {
+            ("ip", ["netns", "exec", _, "ping", rest @ ..]) => self.ping(elapsed, rest),
+            ("ping", rest) => self.ping(elapsed, rest),
+            ("ip", ["-6", "route", rest @ ..]) => self.route(rest, true),
+            ("ip", ["route", rest @ ..]) => self.route(rest, false),
+            ("ip", ["-o", "addr", "show", "dev", iface]) => match self.index(iface) {
+                Some(n) => exited(0, &format!(
+                    "{}: {}    inet 10.200.{}.2/24 brd 10.200.{}.255 scope global {}\\       valid_lft forever preferred_lft forever\n",
+                    n + 2, iface, n, n, iface)),
+                None => failed(1, &format!("Device \"{}\" does not exist.\n", iface)),
+            },
+            ("ip", ["-details", "link", "show"]) => exited(0, &self.links(elapsed)),
+            ("ip", ["neigh", ..]) => exited(0, ""),
+            ("traceroute", _) => exited(0, ""),
+            _ => failed(1, &format!("{} {}: not available in the simulated network\n", program, args.join(" "))),
+        }
+    }
+
+    fn links(&self, elapsed: Duration) -> String {
+        self.interfaces.keys().enumerate()
+            .map(|(n, iface)| {
+                let (flags, state) = if self.up(iface, elapsed) { (",UP,LOWER_UP", "UP") } else { ("", "DOWN") };
+                format!("{}: {}: <BROADCAST,MULTICAST{}> mtu 1500 qdisc mq state {} mode DEFAULT group default qlen 1000\n    \
+                         link/ether 02:00:00:00:00:{:02x} brd ff:ff:ff:ff:ff:ff promiscuity 0\n",
+                        n + 2, iface, flags, state, n)
+            })
+            .collect()
+    }
+
+    fn route(&self, args: &[&str], ipv6: bool) -> Output {
+        let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let address = |n: usize, host: u8| if ipv6 { format!("fd00:200:{}::{}", n, host) } else { format!("10.200.{}.{}", n, host) };
+        let via = |iface: &str| self.index(iface).map(|n| format!("via {} dev {}", address(n, 1), iface));
+        let key = |dest: &str| (ipv6, dest.to_string());
+        match args {
+            ["show", "dev", iface] => match (via(iface), self.index(iface)) {
+                (Some(via), Some(n)) if ipv6 => exited(0, &format!("default {}\nfd00:200:{}::/64 dev {} proto kernel metric 256\n", via, n, iface)),
+                (Some(via), Some(n)) => exited(0, &format!("default {}\n10.200.{}.0/24 dev {} proto kernel scope link\n", via, n, iface)),
+                _ => failed(1, &format!("Cannot find device \"{}\"\n", iface)),
+            },
+            ["show", dest] => exited(0, &routes.get(&key(dest))
+                .and_then(|iface| via(iface))
+                .map(|via| format!("{} {} metric 100\n", dest, via))
+                .unwrap_or_default()),
+            ["get", target] => match routes.get(&key(target)).or_else(|| routes.get(&key("default"))) {
+                Some(iface) => exited(0, &format!("{} {} src {} uid 0\n    cache\n",
+                    target, via(iface).unwrap_or_default(), address(self.index(iface).unwrap_or(0), 2))),
+                None => failed(2, "RTNETLINK answers: Network is unreachable\n"),
+            },
+            ["replace", dest, rest @ ..] => match rest.iter().position(|a| *a == "dev").and_then(|i| rest.get(i + 1)) {
+                Some(iface) if self.index(iface).is_some() => {
+                    routes.insert(key(dest), iface.to_string());
+                    exited(0, "")
+                }
+                Some(iface) => failed(1, &format!("Cannot find device \"{}\"\n", iface)),
+                None => failed(255, "Error: either \"to\" is duplicate, or \"dev\" is a garbage.\n"),
+            },
+            ["del", dest, ..] => match routes.remove(&key(dest)) {
+                Some(_) => exited(0, ""),
+                None => failed(2, "RTNETLINK answers: No such process\n"),
+            },
+            _ => failed(1, &format!("ip route {}: not available in the simulated network\n", args.join(" "))),
+        }
+    }
+
+    // Answers like iputils ping: per-packet loss, the configured latency,
+    // exit status 1 when nothing came back
+    fn ping(&self, elapsed: Duration, args: &[&str]) -> Output {
+        let value = |flag: &str| args.iter().position(|a| *a == flag).and_then(|i| args.get(i + 1)).copied();
+        let (Some(iface), Some(target)) = (value("-I"), args.last()) else {
+            return failed(2, "ping: usage error: simulated ping needs -I <interface>\n");
+        };
+        let Some(sim) = self.interfaces.get(iface) else {
+            return failed(2, &format!("ping: SO_BINDTODEVICE {}: No such device\n", iface));
+        };
+        let count: u32 = value("-c").and_then(|c| c.parse().ok()).unwrap_or(1);
+        let unanswered = sim.down_at(elapsed) || (sim.ipv6_broken && crate::network::is_ipv6_target(target));
+        let received = if unanswered { 0 } else { (0..count).filter(|_| self.random() >= sim.loss).count() as u32 };
+        let loss_pct = 100 * (count - received) / count.max(1);
+        let mut out = format!("PING {} ({}) from 10.200.{}.2 {}: 56(84) bytes of data.\n", target, target, self.index(iface).unwrap_or(0), iface);
+        for seq in 1..=received {
+            out.push_str(&format!("64 bytes from {}: icmp_seq={} ttl=64 time={:.1} ms\n", target, seq, sim.latency_ms));
+        }
+        // Adaptive mode (-A) sends the next ping once the last reply is back
+        let interval_ms = if args.contains(&"-A") { sim.latency_ms } else { 1000.0 };
+        out.push_str(&format!("\n--- {} ping statistics ---\n{} packets transmitted, {} received, {}% packet loss, time {}ms\n",
+            target, count, received, loss_pct, (count.saturating_sub(1) as f64 * interval_ms + sim.latency_ms) as u64));
+        if received > 0 {
+            out.push_str(&format!("rtt min/avg/max/mdev = {0:.3}/{0:.3}/{0:.3}/0.000 ms\n", sim.latency_ms));
+            exited(0, &out)
+        } else {
+            exited(1, &out)
+        }
+    }
+}
+
+impl CommandRunner for SimulatedNetwork {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        Ok(self.run_at(self.elapsed(), program, args))
+    }
+
+    fn spawn(&self, program: &str, _args: &[&str]) -> io::Result<Child> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("{}: not available in the simulated network", program)))
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        self.read_at(self.elapsed(), path)
+    }
+
+    // Reachable whenever the interface is up; the simulated peer has no endpoint to be missing
+    fn probe_udp(&self, iface: &str, _endpoint: SocketAddr, _timeout: Duration) -> Result<bool> {
+        Ok(self.up(iface, self.elapsed()))
+    }
+}
+
+fn exited(code: i32, stdout: &str) -> Output {
+    Output { status: ExitStatus::from_raw(code << 8), stdout: stdout.as_bytes().to_vec(), stderr: Vec::new() }
+}
+
+fn failed(code: i32, stderr: &str) -> Output {
+    Output { stderr: stderr.as_bytes().to_vec(), ..exited(code, "") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{
+        carrier_up, count_active_connections_via_iface, get_route_interface, list_physical_interfaces, measure_latency,
+        parse_ip_addr_output,
+    };
+
+    #[test]
+    fn simulated_network_answers_pings_and_keeps_routes() {
+        let network = SimulatedNetwork::from_json(r#"{
+            "sim0": {"latency_ms": 10, "loss": 0.0, "outages": [{"at_secs": 60, "duration_secs": 30}]},
+            "sim1": {"latency_ms": 50, "loss": 0.5},
+            "sim2": {"latency_ms": 80, "loss": 1.0}
+        }"#).unwrap();
+
+        assert_eq!(measure_latency(&network, "sim0", "203.0.113.1", 4, 1, None), (true, 10.0, 0.0));
+        let (_, latency, loss) = measure_latency(&network, "sim1", "203.0.113.1", 100, 1, None);
+        assert_eq!(latency, 50.0);
+        assert!((30.0..=70.0).contains(&loss), "{}", loss);
+        assert_eq!(measure_latency(&network, "sim2", "203.0.113.1", 4, 1, None), (false, 0.0, 100.0));
+        assert_eq!(measure_latency(&network, "eth0", "203.0.113.1", 4, 1, None), (false, 0.0, 100.0));
+
+        let ping = |elapsed| network.run_at(Duration::from_secs(elapsed), "ping", &["-I", "sim0", "-c", "1", "-W", "1", "203.0.113.1"]);
+        assert!(ping(59).status.success());
+        assert!(!ping(60).status.success());
+        assert!(ping(90).status.success());
+        assert!(carrier_up(&network, "sim0").unwrap());
+        assert_eq!(network.read_at(Duration::from_secs(60), Path::new("/sys/class/net/sim0/carrier")).unwrap(), "0\n");
+        assert!(carrier_up(&network, "eth0").is_err());
+        assert_eq!(count_active_connections_via_iface(&network, "sim0").unwrap(), 0);
+
+        assert_eq!(get_route_interface(&network, "203.0.113.1"), None);
+        assert!(network.run("ip", &["route", "replace", "203.0.113.1", "via", "10.200.1.1", "dev", "sim1", "metric", "100"]).unwrap().status.success());
+        assert_eq!(get_route_interface(&network, "203.0.113.1").as_deref(), Some("sim1"));
+        assert!(network.run("ip", &["route", "del", "203.0.113.1"]).unwrap().status.success());
+        assert!(!network.run("ip", &["route", "del", "203.0.113.1"]).unwrap().status.success());
+
+        let addresses = parse_ip_addr_output(&String::from_utf8_lossy(&network.run("ip", &["-o", "addr", "show", "dev", "sim2"]).unwrap().stdout));
+        assert_eq!(addresses[0].addr.to_string(), "10.200.2.2");
+        assert_eq!(list_physical_interfaces(&network, &[]).unwrap(), ["sim0", "sim1", "sim2"]);
+    }
+
+    #[test]
+    fn simulation_config_is_validated() {
+        assert!(SimulatedNetwork::from_json(r#"{"sim0": {"latency_ms": 10, "loss": 1.5}}"#).is_err());
+        assert!(SimulatedNetwork::from_json(r#"{"sim0": {"latency": 10}}"#).is_err());
+        assert!(SimulatedNetwork::from_json(r#"["sim0"]"#).is_err());
+        assert_eq!(SimulatedNetwork::default().interfaces.len(), 3);
+    }
+}
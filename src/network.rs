@@ -0,0 +1,1827 @@
+//! Everything that touches the network stack: ip(8), ping(8), sysfs,
+//! /proc and the raw sockets used to probe and announce addresses.
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::error::{FailoverError, FailoverResult};
+use crate::log_with_timestamp;
+
+pub const CONNECTION_DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Runs the external programs (ip, ping, traceroute, tcpdump) this module
+/// shells out to, and reads the kernel's view of the links (sysfs, procfs,
+/// UDP probes), so tests can feed canned answers instead of touching the
+/// system.
+#[cfg_attr(test, mockall::automock)]
+pub trait CommandRunner: Send + Sync {
+    // mockall cannot mock the elided lifetime inside the slice
+    #[allow(clippy::needless_lifetimes)]
+    fn run<'a>(&self, program: &str, args: &[&'a str]) -> std::io::Result<Output>;
+
+    /// Starts a long-running program with its output discarded, without
+    /// waiting for it.
+    #[allow(clippy::needless_lifetimes)]
+    fn spawn<'a>(&self, program: &str, args: &[&'a str]) -> std::io::Result<Child>;
+
+    /// Reads a file under /sys or /proc.
+    fn read_file(&self, path: &Path) -> std::io::Result<String>;
+
+    /// See [`probe_wg_endpoint`].
+    fn probe_udp(&self, iface: &str, endpoint: SocketAddr, timeout: Duration) -> Result<bool>;
+}
+
+/// Runs programs and reads the system for real, waiting for programs to exit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> std::io::Result<Output> {
+        Command::new(program).args(args).output()
+    }
+
+    fn spawn(&self, program: &str, args: &[&str]) -> std::io::Result<Child> {
+        Command::new(program).args(args).stdout(Stdio::null()).stderr(Stdio::null()).spawn()
+    }
+
+    fn read_file(&self, path: &Path) -> std::io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn probe_udp(&self, iface: &str, endpoint: SocketAddr, timeout: Duration) -> Result<bool> {
+        send_udp_probe(iface, endpoint, timeout)
+    }
+}
+
+/// Lets a test keep a handle on the runner it gives the monitor.
+impl<T: CommandRunner + ?Sized> CommandRunner for Arc<T> {
+    fn run(&self, program: &str, args: &[&str]) -> std::io::Result<Output> {
+        (**self).run(program, args)
+    }
+
+    fn spawn(&self, program: &str, args: &[&str]) -> std::io::Result<Child> {
+        (**self).spawn(program, args)
+    }
+
+    fn read_file(&self, path: &Path) -> std::io::Result<String> {
+        (**self).read_file(path)
+    }
+
+    fn probe_udp(&self, iface: &str, endpoint: SocketAddr, timeout: Duration) -> Result<bool> {
+        (**self).probe_udp(iface, endpoint, timeout)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PingStats {
+    pub min_ms: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+    pub mdev_ms: f64,
+    pub loss_pct: f64,
+    pub received: u32,
+    pub time_ms: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct BandwidthEstimate {
+    pub throughput_mbps: f64,
+    pub latency_ms: f64,
+}
+
+// Gateway (if any) and when it was looked up
+type CachedGateway = (Option<String>, Instant);
+
+/// Short-lived cache of per-interface gateway lookups, so the gateway is not
+/// looked up with `ip route show` several times per cycle. A TTL of 0 disables it.
+#[derive(Debug)]
+pub struct RouteCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<(String, bool), CachedGateway>>, // keyed by (iface, ipv6)
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RouteCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, entries: Mutex::new(HashMap::new()), hits: AtomicU64::new(0), misses: AtomicU64::new(0) }
+    }
+
+    pub fn gateway_for(&self, runner: &dyn CommandRunner, iface: &str, ipv6: bool) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((gateway, cached_at)) = entries.get(&(iface.to_string(), ipv6)) {
+            if cached_at.elapsed() < self.ttl {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return gateway.clone();
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let gateway = get_gateway_for_interface(runner, iface, ipv6);
+        entries.insert((iface.to_string(), ipv6), (gateway.clone(), Instant::now()));
+        gateway
+    }
+
+    /// Drops all entries, e.g. after we changed routes ourselves.
+    pub fn invalidate(&self) {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// (hits, misses)
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+}
+
+/// Accepts IPv6 literals in URL-style brackets (`[2001:db8::1]`) as well as bare ones.
+pub fn strip_ip_brackets(target: &str) -> &str {
+    target.strip_prefix('[')
+        .and_then(|t| t.strip_suffix(']'))
+        .filter(|t| t.parse::<Ipv6Addr>().is_ok())
+        .unwrap_or(target)
+}
+
+/// True for IPv6 addresses and prefixes ("2001:db8::1", "2001:db8::/32").
+/// Hostnames are treated as IPv4, as ping and ip do by default.
+pub fn is_ipv6_target(target: &str) -> bool {
+    let addr = strip_ip_brackets(target).split('/').next().unwrap_or_default();
+    matches!(addr.parse::<IpAddr>(), Ok(IpAddr::V6(_)))
+}
+
+// Command: ip [-6] route <args>
+fn ip_route(runner: &dyn CommandRunner, ipv6: bool, args: &[&str]) -> std::io::Result<Output> {
+    let mut full_args = if ipv6 { vec!["-6", "route"] } else { vec!["route"] };
+    full_args.extend_from_slice(args);
+    debug!("Running ip {}", full_args.join(" "));
+    runner.run("ip", &full_args)
+}
+
+pub fn get_gateway_for_interface(runner: &dyn CommandRunner, iface: &str, ipv6: bool) -> Option<String> {
+    debug!("Getting gateway for interface: {} (IPv6: {})", iface, ipv6);
+    
+    // Try to get default gateway for this interface
+    let output = ip_route(runner, ipv6, &["show", "dev", iface]);
+
+    match output {
+        Ok(out) if out.status.success() => {
+            let stdout = String::from_utf8_lossy(&out.stdout);
+            debug!("Route output for {}: {}", iface, stdout);
+            
+            // Look for default route via gateway
+            for line in stdout.lines() {
+                if line.starts_with("default via ") {
+                    let parts: Vec<&str> = line.split_whitespace().collect();
+                    if parts.len() >= 3 {
+                        debug!("Found default gateway for {}: {}", iface, parts[2]);
+                        return Some(parts[2].to_string());
+                    }
+                }
+            }
+            
+            // If no default route, look for any route with a gateway
+            for line in stdout.lines() {
+                if line.contains(" via ") {
+                    let parts: Vec<&str> = line.split_whitespace().collect();
+                    for (i, part) in parts.iter().enumerate() {
+                        if *part == "via" && i + 1 < parts.len() {
+                            debug!("Found gateway for {}: {}", iface, parts[i + 1]);
+                            return Some(parts[i + 1].to_string());
+                        }
+                    }
+                }
+            }
+            
+            debug!("No gateway found for interface {}", iface);
+            None
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            debug!("Failed to get routes for {}: {}", iface, stderr);
+            None
+        }
+        Err(e) => {
+            debug!("Failed to execute ip command for {}: {}", iface, e);
+            None
+        }
+    }
+}
+
+// ping with the address family of the target, so mixed test IP lists work
+fn run_ping(runner: &dyn CommandRunner, netns: Option<&str>, target: &str, args: &[&str]) -> std::io::Result<Output> {
+    let (program, mut full_args) = match netns {
+        // Command: ip netns exec <ns> ping ...
+        Some(ns) => ("ip", vec!["netns", "exec", ns, "ping"]),
+        None => ("ping", Vec::new()),
+    };
+    if is_ipv6_target(target) {
+        full_args.push("-6");
+    }
+    full_args.extend_from_slice(args);
+    runner.run(program, &full_args)
+}
+
+/// How this process is able to send ICMP echo requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IcmpCapability {
+    /// Raw ICMP sockets can be opened (root or CAP_NET_RAW)
+    RawSocket,
+    /// The ping binary at this path is setuid root
+    SuidPing(PathBuf),
+    /// Unprivileged ICMP datagram sockets (net.ipv4.ping_group_range, Linux 3.11+)
+    UnprivilegedIcmp,
+    None,
+}
+
+/// Finds out whether pings can work at all, checking the cheapest and most
+/// capable option first. All probes shell out to ping either way; ping picks
+/// raw or datagram sockets itself, so this only tells us whether it will fail.
+pub fn detect_icmp_capability(runner: &dyn CommandRunner) -> IcmpCapability {
+    // SAFETY: plain socket(2)/close(2) on a descriptor we own
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_ICMP) };
+    if fd >= 0 {
+        unsafe { libc::close(fd) };
+        return IcmpCapability::RawSocket;
+    }
+
+    if let Some(ping) = find_in_path("ping") {
+        use std::os::unix::fs::PermissionsExt;
+        if std::fs::metadata(&ping).is_ok_and(|m| m.permissions().mode() & 0o4000 != 0) {
+            return IcmpCapability::SuidPing(ping);
+        }
+    }
+
+    // Command: ping -c 1 -W 1 127.0.0.1
+    let loopback_ping = runner.run("ping", &["-c", "1", "-W", "1", "127.0.0.1"]);
+    if loopback_ping.is_ok_and(|o| o.status.success()) {
+        return IcmpCapability::UnprivilegedIcmp;
+    }
+    IcmpCapability::None
+}
+
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Pings `target` through `iface` and returns (reachable, average RTT in ms,
+/// packet loss in percent). An unanswered ping is `Ok` with reachable false;
+/// errors mean ping could not tell, e.g. because it could not be started
+/// ([`FailoverError::Io`]), lacks CAP_NET_RAW
+/// ([`FailoverError::InsufficientPermissions`]) or the interface is gone
+/// ([`FailoverError::InterfaceNotFound`]).
+pub fn ping_interface(
+    runner: &dyn CommandRunner,
+    iface: &str,
+    target: &str,
+    count: u8,
+    timeout: u8,
+    netns: Option<&str>,
+) -> FailoverResult<(bool, f64, f64)> {
+    let output = run_ping(runner, netns, target, &[
+        "-I", iface,
+        "-c", &count.to_string(),
+        "-W", &timeout.to_string(),
+        target,
+    ])
+    .map_err(|e| FailoverError::io("ping", e))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let result = if output.status.success() {
+        let avg_ms = parse_ping_stats(&stdout).map_or(0.0, |s| s.avg_ms);
+        (true, avg_ms, ping_loss_pct(&stdout, true))
+    } else {
+        if let Some(err) = ping_error(iface, &String::from_utf8_lossy(&output.stderr)) {
+            return Err(err);
+        }
+        (false, 0.0, ping_loss_pct(&stdout, false))
+    };
+    probe_debug!("ping {} via {} (netns {:?}): {:?}", target, iface, netns, result);
+    Ok(result)
+}
+
+// Tells ping failures that say nothing about reachability apart from
+// unanswered or unroutable pings, by what iputils and busybox print
+fn ping_error(iface: &str, stderr: &str) -> Option<FailoverError> {
+    let message = stderr.trim();
+    if message.contains("Operation not permitted") || message.contains("lacking privilege") || message.contains("Permission denied") {
+        Some(FailoverError::InsufficientPermissions(message.to_string()))
+    } else if message.contains("No such device") || message.contains("unknown iface") {
+        Some(FailoverError::InterfaceNotFound(iface.to_string()))
+    } else {
+        None
+    }
+}
+
+/// [`ping_interface`] for callers that treat errors as an unreachable target
+/// with full loss.
+pub fn measure_latency(runner: &dyn CommandRunner, iface: &str, target: &str, count: u8, timeout: u8, netns: Option<&str>) -> (bool, f64, f64) {
+    ping_interface(runner, iface, target, count, timeout, netns).unwrap_or_else(|e| {
+        probe_debug!("Ping via {} to {} failed: {}", iface, target, e);
+        (false, 0.0, 100.0)
+    })
+}
+
+pub fn test_connectivity_multiple_ips(
+    runner: &dyn CommandRunner,
+    clock: &dyn Clock,
+    iface: &str,
+    test_ips: &[String],
+    timeout: u8,
+    netns: Option<&str>,
+    spacing: Duration,
+) -> (bool, f64, f64, HashMap<String, bool>, Option<FailoverError>) {
+    probe_debug!("Testing connectivity for interface {} to {} IPs", iface, test_ips.len());
+    
+    let mut successful_tests = 0;
+    let mut total_latency = 0.0;
+    let mut total_loss = 0.0;
+    let mut test_results = HashMap::new();
+    let mut first_error = None;
+    
+    for (i, ip) in test_ips.iter().enumerate() {
+        if i > 0 && !spacing.is_zero() {
+            clock.sleep(spacing);
+        }
+        probe_debug!("Pinging {} via {}", ip, iface);
+        // Errors count as an unanswered ping; the first is handed back so the
+        // caller can tell a missing capability from a dead link
+        let (success, latency, loss) = ping_interface(runner, iface, ip, 1, timeout, netns).unwrap_or_else(|e| {
+            probe_debug!("Ping of {} via {} failed: {}", ip, iface, e);
+            first_error.get_or_insert(e);
+            (false, 0.0, 100.0)
+        });
+        test_results.insert(ip.clone(), success);
+        total_loss += loss;
+        
+        if success {
+            successful_tests += 1;
+            total_latency += latency;
+            probe_debug!("Successfully reached {} via {} with latency {:.1}ms", ip, iface, latency);
+        } else {
+            probe_debug!("Failed to reach {} via {}", ip, iface);
+        }
+    }
+    
+    let avg_latency = if successful_tests > 0 {
+        total_latency / successful_tests as f64
+    } else {
+        0.0
+    };
+    
+    let loss_pct = if test_ips.is_empty() { 0.0 } else { total_loss / test_ips.len() as f64 };
+    
+    // Consider interface working if at least 50% of tests succeed
+    let interface_working = successful_tests > 0 && (successful_tests as f32 / test_ips.len() as f32) >= 0.5;
+    
+    probe_debug!("Interface {}: {} successful tests out of {}, average latency: {:.1}ms, loss: {:.1}%, working: {}", 
+           iface, successful_tests, test_ips.len(), avg_latency, loss_pct, interface_working);
+    
+    (interface_working, avg_latency, loss_pct, test_results, first_error)
+}
+
+pub fn update_route_for_peer(runner: &dyn CommandRunner, peer_ip: &str, iface: &str, gateway: Option<&String>) -> Result<()> {
+    debug!("update_route_for_peer called: peer_ip={}, iface={}, gateway={:?}", peer_ip, iface, gateway);
+    
+    // Command: ip [-6] route replace <peer_ip> [via <gateway>] dev <iface>
+    let mut args = vec!["replace", peer_ip];
+    
+    if let Some(gw) = gateway {
+        debug!("Adding gateway to route: via {}", gw);
+        args.extend(["via", gw]);
+    } else {
+        debug!("No gateway specified for route");
+    }
+    
+    args.extend(["dev", iface, "metric", "100"]);
+
+    let output = ip_route(runner, is_ipv6_target(peer_ip), &args).context("Failed to execute ip route command")?;
+    
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        debug!("Route command failed with status: {}", output.status);
+        debug!("Route command stderr: {}", stderr);
+        debug!("Route command stdout: {}", stdout);
+        return Err(anyhow::anyhow!("ip route failed: {}", stderr));
+    }
+    
+    debug!("Route command succeeded with status: {}", output.status);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.is_empty() {
+        debug!("Route command stdout: {}", stdout);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.is_empty() {
+        debug!("Route command stderr: {}", stderr);
+    }
+    
+    debug!("Updated route for {} via {} (gw: {:?})", peer_ip, iface, gateway);
+    Ok(())
+}
+
+/// Removes the route for `dest` from the main table, or from `table`.
+/// Deleting a route that does not exist succeeds.
+pub fn delete_route(runner: &dyn CommandRunner, dest: &str, table: Option<u32>) -> FailoverResult<()> {
+    debug!("delete_route called: dest={}, table={:?}", dest, table);
+
+    // Command: ip [-6] route del <dest> [table <N>]
+    let table = table.map(|t| t.to_string());
+    let mut args = vec!["del", dest];
+    args.extend(table.as_deref().map(|t| ["table", t]).into_iter().flatten());
+    let output = ip_route(runner, is_ipv6_target(dest), &args)
+        .map_err(|e| FailoverError::io("ip route del", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // ESRCH means there was no route to delete, which is what we wanted anyway
+        if stderr.contains("No such process") {
+            debug!("No existing route for {}, nothing to delete", dest);
+            return Ok(());
+        }
+        debug!("Route delete command failed with status: {}", output.status);
+        debug!("Route delete command stderr: {}", stderr);
+        if stderr.contains("Operation not permitted") {
+            return Err(FailoverError::InsufficientPermissions(stderr.trim().to_string()));
+        }
+        return Err(FailoverError::command("ip route del", stderr.trim()));
+    }
+
+    debug!("Deleted route for {}", dest);
+    Ok(())
+}
+
+pub const DEFAULT_ROUTE_METRIC: u32 = 100;
+
+pub fn update_default_route(runner: &dyn CommandRunner, iface: &str, gateway: Option<&String>, ipv6: bool) -> Result<()> {
+    debug!("update_default_route called: iface={}, gateway={:?}, ipv6={}", iface, gateway, ipv6);
+    
+    // Command: ip [-6] route replace default [via <gateway>] dev <iface>
+    let mut args = vec!["replace", "default"];
+    
+    if let Some(gw) = gateway {
+        debug!("Adding gateway to default route: via {}", gw);
+        args.extend(["via", gw]);
+    } else {
+        debug!("No gateway specified for default route");
+    }
+    
+    let metric = DEFAULT_ROUTE_METRIC.to_string();
+    args.extend(["dev", iface, "metric", &metric]);
+
+    let output = ip_route(runner, ipv6, &args).context("Failed to execute ip route command")?;
+    
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        debug!("Default route command failed with status: {}", output.status);
+        debug!("Default route command stderr: {}", stderr);
+        debug!("Default route command stdout: {}", stdout);
+        return Err(anyhow::anyhow!("ip route default failed: {}", stderr));
+    }
+    
+    debug!("Default route command succeeded with status: {}", output.status);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.is_empty() {
+        debug!("Default route command stdout: {}", stdout);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.is_empty() {
+        debug!("Default route command stderr: {}", stderr);
+    }
+    
+    debug!("Updated default route via {} (gw: {:?})", iface, gateway);
+    Ok(())
+}
+
+/// Counts from the ping summary line. Handles both
+///   GNU:     "10 packets transmitted, 9 received, +1 errors, 10% packet loss, time 9012ms"
+///   BusyBox: "10 packets transmitted, 9 packets received, 10% packet loss"
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingSummary {
+    pub transmitted: u32,
+    pub received: u32,
+    pub loss_pct: f64,
+    pub time_ms: Option<f64>,
+}
+
+pub fn parse_ping_summary(line: &str) -> Option<PingSummary> {
+    if !line.contains("transmitted") {
+        return None;
+    }
+    let mut transmitted: Option<u32> = None;
+    let mut received = None;
+    let mut loss_pct = None;
+    let mut time_ms = None;
+    for part in line.split(',').map(str::trim) {
+        let number = part.split_whitespace().next().unwrap_or_default();
+        if part.ends_with("transmitted") {
+            transmitted = number.parse().ok();
+        } else if part.ends_with("received") {
+            received = number.parse().ok();
+        } else if part.ends_with("packet loss") {
+            loss_pct = number.trim_end_matches('%').parse().ok();
+        } else if let Some(time) = part.strip_prefix("time ").and_then(|t| t.strip_suffix("ms")) {
+            time_ms = time.trim().parse().ok();
+        }
+    }
+    let (transmitted, received) = (transmitted?, received?);
+    // Older pings omit the percentage when nothing was sent
+    let loss_pct = loss_pct.or_else(|| {
+        (transmitted > 0).then(|| transmitted.saturating_sub(received) as f64 * 100.0 / transmitted as f64)
+    })?;
+    Some(PingSummary { transmitted, received, loss_pct, time_ms })
+}
+
+// Loss reported by ping, or all-or-nothing from the exit status if there is no summary
+pub fn ping_loss_pct(stdout: &str, success: bool) -> f64 {
+    stdout.lines()
+        .find_map(parse_ping_summary)
+        .map_or(if success { 0.0 } else { 100.0 }, |s| s.loss_pct)
+}
+
+pub fn parse_ping_stats(stdout: &str) -> Option<PingStats> {
+    let mut stats = PingStats::default();
+    let mut found = false;
+
+    for line in stdout.lines() {
+        if let Some(summary) = parse_ping_summary(line) {
+            stats.loss_pct = summary.loss_pct;
+            stats.received = summary.received;
+            stats.time_ms = summary.time_ms.unwrap_or(0.0);
+            found = true;
+        }
+        // rtt min/avg/max/mdev = 1.1/2.2/3.3/0.4 ms[, ipg/ewma 1.2/2.1 ms]
+        if line.contains("min/avg/max") {
+            if let Some(values) = line.split('=').nth(1) {
+                let parts: Vec<f64> = values
+                    .split(',')
+                    .next()
+                    .unwrap_or(values)
+                    .trim()
+                    .trim_end_matches("ms")
+                    .split('/')
+                    .filter_map(|v| v.trim().parse::<f64>().ok())
+                    .collect();
+                if parts.len() >= 3 {
+                    stats.min_ms = parts[0];
+                    stats.avg_ms = parts[1];
+                    stats.max_ms = parts[2];
+                    stats.mdev_ms = parts.get(3).copied().unwrap_or(0.0);
+                    found = true;
+                }
+            }
+        }
+    }
+
+    if found { Some(stats) } else { None }
+}
+
+pub fn run_ping_stats(runner: &dyn CommandRunner, iface: &str, target: &str, count: u8, timeout: u8) -> Option<PingStats> {
+    debug!("run_ping_stats called: iface={}, target={}, count={}, timeout={}", iface, target, count, timeout);
+
+    let output = run_ping(runner, None, target, &[
+        "-I", iface,
+        "-c", &count.to_string(),
+        "-W", &timeout.to_string(),
+        target,
+    ]);
+
+    match output {
+        Ok(out) => {
+            let stdout = String::from_utf8_lossy(&out.stdout);
+            debug!("Ping stdout: {}", stdout);
+            parse_ping_stats(&stdout)
+        }
+        Err(e) => {
+            debug!("Failed to execute ping command: {}", e);
+            None
+        }
+    }
+}
+
+const BANDWIDTH_PROBE_SIZE: u32 = 1400;
+
+/// Rough throughput estimate from ten large (1400-byte) pings: bytes echoed
+/// over the total time in ping's statistics line. Adaptive mode (`-A`) sends
+/// each ping as soon as the last reply arrives, so that time tracks the link
+/// rather than ping's one-second default interval. Not a real bandwidth test,
+/// but a link that is congested or heavily shaped shows up clearly against a
+/// healthy one.
+pub fn bandwidth_probe(runner: &dyn CommandRunner, iface: &str, target: &str, timeout: u8, netns: Option<&str>) -> Result<BandwidthEstimate> {
+    // Command: ping -I <iface> -A -s 1400 -c 10 -W <timeout> <target>
+    let output = run_ping(runner, netns, target, &[
+        "-I", iface,
+        "-A",
+        "-s", &BANDWIDTH_PROBE_SIZE.to_string(),
+        "-c", "10",
+        "-W", &timeout.to_string(),
+        target,
+    ])
+    .context("Failed to execute ping command")?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    debug!("Bandwidth probe via {}: {}", iface, stdout);
+    parse_bandwidth_estimate(&stdout).with_context(|| format!("No replies to bandwidth probe via {}", iface))
+}
+
+pub fn parse_bandwidth_estimate(stdout: &str) -> Option<BandwidthEstimate> {
+    let stats = parse_ping_stats(stdout).filter(|s| s.received > 0 && s.time_ms > 0.0)?;
+    // ICMP header adds 8 bytes; each echo carries the payload both ways
+    let bits = (stats.received * (BANDWIDTH_PROBE_SIZE + 8) * 2 * 8) as f64;
+    Some(BandwidthEstimate {
+        throughput_mbps: bits / (stats.time_ms / 1000.0) / 1_000_000.0,
+        latency_ms: stats.avg_ms,
+    })
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkInfo {
+    pub name: String,
+    pub link_type: String,      // e.g. "ether", "loopback", "none"
+    pub kinds: Vec<String>,     // detail kinds, e.g. "wireguard", "bridge_slave"
+    pub master: Option<String>, // bridge/bond this link is enslaved to
+    pub altnames: Vec<String>,  // alternative names, e.g. "enp3s0f0" for "eno3"
+    pub alias: Option<String>,  // free-form ifalias, set with `ip link set ... alias`
+}
+
+impl LinkInfo {
+    /// Kernel name followed by any altnames and the alias
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.name.as_str())
+            .chain(self.altnames.iter().map(String::as_str))
+            .chain(self.alias.as_deref())
+    }
+}
+
+pub fn parse_link_details(stdout: &str) -> Vec<LinkInfo> {
+    let mut links: Vec<LinkInfo> = Vec::new();
+
+    for line in stdout.lines() {
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with(char::is_whitespace) {
+            // 3: veth1@if2: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 ... master br0 state UP ...
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let Some(raw_name) = parts.get(1) else { continue };
+            let name = raw_name.trim_end_matches(':');
+            let name = name.split('@').next().unwrap_or(name);
+            let master = parts.iter()
+                .position(|p| *p == "master")
+                .and_then(|i| parts.get(i + 1))
+                .map(|m| m.to_string());
+            links.push(LinkInfo { name: name.to_string(), master, ..Default::default() });
+        } else if let Some(link) = links.last_mut() {
+            let Some(first) = line.split_whitespace().next() else { continue };
+            if let Some(link_type) = first.strip_prefix("link/") {
+                link.link_type = link_type.to_string();
+            } else if first == "altname" {
+                link.altnames.extend(line.split_whitespace().nth(1).map(str::to_string));
+            } else if first == "alias" {
+                link.alias = line.trim().strip_prefix("alias").map(|a| a.trim().to_string());
+            } else {
+                link.kinds.push(first.to_string());
+            }
+        }
+    }
+
+    links
+}
+
+pub fn is_physical_interface(link: &LinkInfo) -> bool {
+    link.link_type != "loopback"
+        && link.name != "lo"
+        && !link.kinds.iter().any(|k| k == "wireguard" || k.ends_with("_slave"))
+        && link.master.is_none()
+}
+
+// Turns the result of `ip -details link show` into links. A failed command is an
+// error; a successful one without links is a (suspicious, but valid) empty list.
+pub fn parse_link_show_output(success: bool, stdout: &str, stderr: &str) -> FailoverResult<Vec<LinkInfo>> {
+    if !success {
+        let message = stderr.trim();
+        if message.contains("Operation not permitted") {
+            return Err(FailoverError::InsufficientPermissions(message.to_string()));
+        }
+        return Err(FailoverError::command("ip link show", message));
+    }
+    Ok(parse_link_details(stdout))
+}
+
+pub fn list_links(runner: &dyn CommandRunner) -> FailoverResult<Vec<LinkInfo>> {
+    // Command: ip -details link show
+    let output = runner.run("ip", &["-details", "link", "show"])
+        .map_err(|e| FailoverError::io("ip link show", e))?;
+
+    parse_link_show_output(
+        output.status.success(),
+        &String::from_utf8_lossy(&output.stdout),
+        &String::from_utf8_lossy(&output.stderr),
+    )
+}
+
+pub fn list_interfaces(runner: &dyn CommandRunner, filter: impl Fn(&LinkInfo) -> bool) -> FailoverResult<Vec<String>> {
+    Ok(list_links(runner)?
+        .into_iter()
+        .filter(|link| {
+            let keep = filter(link);
+            if !keep {
+                debug!("Excluding interface {} ({:?} {:?})", link.name, link.link_type, link.kinds);
+            }
+            keep
+        })
+        .map(|link| link.name)
+        .collect())
+}
+
+pub fn list_physical_interfaces(runner: &dyn CommandRunner, exclude_patterns: &[String]) -> FailoverResult<Vec<String>> {
+    list_interfaces(runner, |link| is_physical_interface(link) && !is_excluded_interface(&link.name, exclude_patterns))
+}
+
+/// Maps a configured interface name to the kernel's current name for it.
+/// Surrounding whitespace is ignored, and a name matches a link by its kernel
+/// name, an altname (udev records the predictable name there when it keeps the
+/// kernel one, and vice versa) or its ifalias, first exactly and then ignoring
+/// case. Names that match nothing are returned trimmed but otherwise unchanged,
+/// so the usual "interface not found" errors still mention them.
+pub fn normalize_interface_name(name: &str, links: &[LinkInfo]) -> String {
+    let name = name.trim();
+    let find = |eq: &dyn Fn(&str) -> bool| links.iter().find(|link| link.names().any(eq));
+
+    find(&|candidate| candidate == name)
+        .or_else(|| find(&|candidate| candidate.eq_ignore_ascii_case(name)))
+        .map(|link| link.name.clone())
+        .unwrap_or_else(|| name.to_string())
+}
+
+pub fn is_excluded_interface(name: &str, exclude_patterns: &[String]) -> bool {
+    exclude_patterns.iter().any(|pattern| glob_match(pattern, name))
+}
+
+// Shell-style glob match supporting `*` (any run of characters) and `?` (one character)
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None; // (pattern index after '*', name index)
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last '*' swallow one more character and retry
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+pub fn is_valid_mac(mac: &str) -> bool {
+    parse_mac(mac).is_some()
+}
+
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let octets: Vec<&str> = mac.trim().split(':').collect();
+    if octets.len() != 6 || octets.iter().any(|o| o.len() != 2) {
+        return None;
+    }
+    let mut bytes = [0u8; 6];
+    for (byte, octet) in bytes.iter_mut().zip(octets) {
+        *byte = u8::from_str_radix(octet, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+pub fn add_static_arp(runner: &dyn CommandRunner, ip: &str, mac: &str, iface: &str) -> Result<()> {
+    // Command: ip neigh replace <ip> lladdr <mac> dev <iface> nud permanent
+    let output = runner.run("ip", &["neigh", "replace", ip, "lladdr", mac, "dev", iface, "nud", "permanent"])
+        .context("Failed to execute ip neigh command")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("ip neigh replace failed: {}", stderr.trim()));
+    }
+    debug!("Installed static neighbour entry {} -> {} on {}", ip, mac, iface);
+    Ok(())
+}
+
+pub fn delete_static_arp(runner: &dyn CommandRunner, ip: &str, iface: &str) -> Result<()> {
+    // Command: ip neigh del <ip> dev <iface>
+    let output = runner.run("ip", &["neigh", "del", ip, "dev", iface])
+        .context("Failed to execute ip neigh command")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("ip neigh del failed: {}", stderr.trim()));
+    }
+    debug!("Removed static neighbour entry {} on {}", ip, iface);
+    Ok(())
+}
+
+fn interface_mac(runner: &dyn CommandRunner, iface: &str) -> Result<[u8; 6]> {
+    let path = Path::new("/sys/class/net").join(iface).join("address");
+    let mac = runner.read_file(&path).with_context(|| format!("Failed to read {:?}", path))?;
+    parse_mac(&mac).with_context(|| format!("{} has no Ethernet address ({:?})", iface, mac.trim()))
+}
+
+fn interface_index(iface: &str) -> Result<u32> {
+    let name = std::ffi::CString::new(iface).context("Interface name contains a NUL byte")?;
+    // SAFETY: `name` is a valid NUL-terminated string for the duration of the call
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(std::io::Error::last_os_error()).context(format!("No interface index for {}", iface)),
+        index => Ok(index),
+    }
+}
+
+fn raw_socket(domain: libc::c_int, protocol: libc::c_int) -> Result<OwnedFd> {
+    // SAFETY: socket(2) has no memory-safety preconditions; a valid fd is handed to OwnedFd
+    let fd = unsafe { libc::socket(domain, libc::SOCK_RAW, protocol) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to open raw socket (needs CAP_NET_RAW)");
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Broadcast ARP reply announcing `ip` at `mac` (sender and target IP both
+/// `ip`), so neighbours update their caches after we move to this interface.
+fn gratuitous_arp_frame(mac: [u8; 6], ip: Ipv4Addr) -> [u8; 42] {
+    let mut frame = [0u8; 42];
+    frame[0..6].copy_from_slice(&[0xff; 6]);                 // Ethernet destination: broadcast
+    frame[6..12].copy_from_slice(&mac);                      // Ethernet source
+    frame[12..14].copy_from_slice(&0x0806u16.to_be_bytes()); // EtherType ARP
+    frame[14..16].copy_from_slice(&1u16.to_be_bytes());      // hardware type Ethernet
+    frame[16..18].copy_from_slice(&0x0800u16.to_be_bytes()); // protocol type IPv4
+    frame[18] = 6;                                           // hardware address length
+    frame[19] = 4;                                           // protocol address length
+    frame[20..22].copy_from_slice(&2u16.to_be_bytes());      // operation: reply
+    frame[22..28].copy_from_slice(&mac);                     // sender hardware address
+    frame[28..32].copy_from_slice(&ip.octets());             // sender protocol address
+    frame[32..38].copy_from_slice(&[0xff; 6]);               // target hardware address
+    frame[38..42].copy_from_slice(&ip.octets());             // target protocol address
+    frame
+}
+
+fn send_gratuitous_arp(runner: &dyn CommandRunner, iface: &str, ip: Ipv4Addr) -> Result<()> {
+    let frame = gratuitous_arp_frame(interface_mac(runner, iface)?, ip);
+    let protocol = (libc::ETH_P_ARP as u16).to_be();
+    let socket = raw_socket(libc::AF_PACKET, protocol as libc::c_int)?;
+
+    // SAFETY: sockaddr_ll is plain data, all-zero is a valid value
+    let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = protocol;
+    addr.sll_ifindex = interface_index(iface)? as libc::c_int;
+    addr.sll_halen = 6;
+    addr.sll_addr[..6].copy_from_slice(&[0xff; 6]);
+
+    // SAFETY: the buffer and address pointers are valid for the lengths passed
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            frame.as_ptr() as *const libc::c_void,
+            frame.len(),
+            0,
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error()).context(format!("Failed to send gratuitous ARP on {}", iface));
+    }
+    Ok(())
+}
+
+/// ICMPv6 Neighbor Advertisement for `target` with the Override flag and a
+/// target link-layer address option. The kernel fills in the checksum.
+fn unsolicited_na_message(target: Ipv6Addr, mac: [u8; 6]) -> [u8; 32] {
+    let mut message = [0u8; 32];
+    message[0] = 136;                            // type: Neighbor Advertisement
+    message[4] = 0x20;                           // flags: Override
+    message[8..24].copy_from_slice(&target.octets());
+    message[24] = 2;                             // option: target link-layer address
+    message[25] = 1;                             // option length in units of 8 bytes
+    message[26..32].copy_from_slice(&mac);
+    message
+}
+
+fn send_unsolicited_na(runner: &dyn CommandRunner, iface: &str, ip6: Ipv6Addr) -> Result<()> {
+    let message = unsolicited_na_message(ip6, interface_mac(runner, iface)?);
+    let socket = raw_socket(libc::AF_INET6, libc::IPPROTO_ICMPV6)?;
+
+    // Neighbor Discovery messages must arrive with a hop limit of 255
+    let hops: libc::c_int = 255;
+    // SAFETY: the option value points to a c_int of the given size
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MULTICAST_HOPS,
+            &hops as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to set hop limit for neighbor advertisement");
+    }
+
+    // SAFETY: sockaddr_in6 is plain data, all-zero is a valid value
+    let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    addr.sin6_addr.s6_addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1).octets(); // all-nodes
+    addr.sin6_scope_id = interface_index(iface)?;
+
+    // SAFETY: the buffer and address pointers are valid for the lengths passed
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            message.as_ptr() as *const libc::c_void,
+            message.len(),
+            0,
+            &addr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error()).context(format!("Failed to send neighbor advertisement on {}", iface));
+    }
+    Ok(())
+}
+
+/// Announces every global address of `iface` to the LAN: gratuitous ARP for
+/// IPv4, unsolicited Neighbor Advertisements for IPv6. Best effort.
+pub fn announce_addresses(runner: &dyn CommandRunner, iface: &str) {
+    let addresses = match get_interface_addresses(runner, iface) {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            warn!("Could not read addresses of {} to announce: {}", iface, e);
+            return;
+        }
+    };
+    for address in addresses.iter().filter(|a| a.scope == "global") {
+        let result = match address.addr {
+            IpAddr::V4(ip) => send_gratuitous_arp(runner, iface, ip),
+            IpAddr::V6(ip) => send_unsolicited_na(runner, iface, ip),
+        };
+        match result {
+            Ok(()) => info!("Announced {} on {}", address.addr, iface),
+            Err(e) => warn!("Failed to announce {} on {}: {:#}", address.addr, iface, e),
+        }
+    }
+}
+
+pub fn get_route_interface(runner: &dyn CommandRunner, target: &str) -> Option<String> {
+    // Command: ip [-6] route get <target>
+    let output = ip_route(runner, is_ipv6_target(target), &["get", target]).ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let parts: Vec<&str> = stdout.split_whitespace().collect();
+    parts.iter()
+        .position(|p| *p == "dev")
+        .and_then(|i| parts.get(i + 1))
+        .map(|dev| dev.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteInfo {
+    pub dev: String,
+    pub gateway: Option<String>,
+    pub metric: Option<u32>,
+    pub table: Option<String>,
+}
+
+// Parses one line of `ip route show`, e.g. "default via 192.168.1.1 dev eth0 proto dhcp metric 100"
+pub fn parse_route_line(line: &str) -> Option<RouteInfo> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let value_of = |key: &str| parts.iter().position(|p| *p == key).and_then(|i| parts.get(i + 1)).map(|v| v.to_string());
+    Some(RouteInfo {
+        dev: value_of("dev")?,
+        gateway: value_of("via"),
+        metric: value_of("metric").and_then(|m| m.parse().ok()),
+        table: value_of("table"),
+    })
+}
+
+pub fn default_route_info(runner: &dyn CommandRunner, ipv6: bool) -> Result<Option<RouteInfo>> {
+    Ok(routes_for_destination(runner, "default", ipv6)?.into_iter().next())
+}
+
+// Routes in the main table for exactly this destination (not covering prefixes)
+pub fn routes_for_destination(runner: &dyn CommandRunner, dest: &str, ipv6: bool) -> Result<Vec<RouteInfo>> {
+    // Command: ip [-6] route show <dest>
+    let output = ip_route(runner, ipv6, &["show", dest])
+        .context("Failed to execute ip route show")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("ip route show {} failed: {}", dest, stderr.trim()));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.lines().filter_map(parse_route_line).collect())
+}
+
+/// Cheap link check from sysfs. Errors when the interface is not visible in
+/// this namespace, so callers can fall back to probing.
+pub fn carrier_up(runner: &dyn CommandRunner, iface: &str) -> Result<bool> {
+    let base = Path::new("/sys/class/net").join(iface);
+    let operstate = match runner.read_file(&base.join("operstate")) {
+        Ok(operstate) => Some(operstate),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(anyhow::anyhow!("{} not found in /sys/class/net", iface));
+        }
+        Err(_) => None,
+    };
+    // Reading carrier fails with EINVAL while the interface is administratively down
+    let carrier = runner.read_file(&base.join("carrier")).ok();
+    Ok(link_is_up(carrier.as_deref(), operstate.as_deref()))
+}
+
+/// Whether `iface` is administratively up (IFF_UP in sysfs flags), whatever
+/// its carrier.
+pub fn link_admin_up(runner: &dyn CommandRunner, iface: &str) -> Result<bool> {
+    let path = Path::new("/sys/class/net").join(iface).join("flags");
+    let flags = runner.read_file(&path).with_context(|| format!("Failed to read {:?}", path))?;
+    let flags = u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16)
+        .with_context(|| format!("Unexpected flags {:?} in {:?}", flags.trim(), path))?;
+    Ok(flags & libc::IFF_UP as u32 != 0)
+}
+
+pub fn set_link_up(runner: &dyn CommandRunner, iface: &str) -> Result<()> {
+    // Command: ip link set dev <iface> up
+    let output = runner.run("ip", &["link", "set", "dev", iface, "up"])
+        .context("Failed to execute ip link command")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("ip link set up failed: {}", stderr.trim()));
+    }
+    Ok(())
+}
+
+pub fn link_is_up(carrier: Option<&str>, operstate: Option<&str>) -> bool {
+    let carrier_ok = carrier.is_some_and(|c| c.trim() == "1");
+    // "unknown" is normal for tunnels, PPP and some modem drivers
+    let operstate_ok = !matches!(operstate.map(str::trim), Some("down" | "lowerlayerdown" | "notpresent"));
+    carrier_ok && operstate_ok
+}
+
+// A link rate at or above this counts as perfect in the link quality score;
+// the uplink behind the interface rarely carries more
+const LINK_RATE_FULL_MBPS: f64 = 1000.0;
+
+/// Packet and error counters of an interface since it was created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InterfaceStats {
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+}
+
+/// Reads the counters from `/sys/class/net/<iface>/statistics`.
+pub fn get_interface_stats(runner: &dyn CommandRunner, iface: &str) -> FailoverResult<InterfaceStats> {
+    let dir = Path::new("/sys/class/net").join(iface).join("statistics");
+    let counter = |name: &str| -> FailoverResult<u64> {
+        let path = dir.join(name);
+        let text = runner.read_file(&path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => FailoverError::InterfaceNotFound(iface.to_string()),
+            _ => FailoverError::io(&format!("reading {}", path.display()), e),
+        })?;
+        text.trim().parse().map_err(|_| FailoverError::command(&format!("reading {}", path.display()), format!("unexpected {:?}", text.trim())))
+    };
+    Ok(InterfaceStats {
+        rx_packets: counter("rx_packets")?,
+        rx_errors: counter("rx_errors")?,
+        tx_packets: counter("tx_packets")?,
+        tx_errors: counter("tx_errors")?,
+    })
+}
+
+/// Signal level of a wireless interface in dBm, from /proc/net/wireless.
+/// None for interfaces that are not wireless.
+pub fn get_wifi_signal_strength(runner: &dyn CommandRunner, iface: &str) -> Option<i32> {
+    // Inter-| sta-|   Quality        |   Discarded packets ...
+    //  face | tus | link level noise |  nwid  crypt ...
+    // wlan0: 0000   54.  -56.  -256        0      0 ...
+    let text = runner.read_file(Path::new("/proc/net/wireless")).ok()?;
+    text.lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .find(|(name, _)| *name == iface)
+        .and_then(|(_, fields)| fields.split_whitespace().nth(2))
+        .and_then(|level| level.trim_end_matches('.').parse::<f64>().ok())
+        .map(|level| level as i32)
+}
+
+/// Current transmit rate of a wireless interface in Mbit/s, from
+/// `iw dev <iface> link`. None if it is not associated or iw is missing.
+pub fn get_wifi_tx_bitrate(runner: &dyn CommandRunner, iface: &str) -> Option<f64> {
+    // Command: iw dev <iface> link
+    let output = runner.run("iw", &["dev", iface, "link"]).ok().filter(|o| o.status.success())?;
+    // 	tx bitrate: 866.7 MBit/s VHT-MCS 9 80MHz short GI VHT-NSS 2
+    String::from_utf8_lossy(&output.stdout).lines()
+        .find_map(|line| line.trim().strip_prefix("tx bitrate:"))
+        .and_then(|rate| rate.split_whitespace().next())
+        .and_then(|rate| rate.parse().ok())
+}
+
+/// Negotiated link speed in Mbit/s from sysfs. None where the driver has no
+/// fixed speed (wireless, tunnels) or the link is down.
+pub fn get_link_speed(runner: &dyn CommandRunner, iface: &str) -> Option<u64> {
+    let speed = runner.read_file(&Path::new("/sys/class/net").join(iface).join("speed")).ok()?;
+    speed.trim().parse::<i64>().ok().filter(|speed| *speed > 0).map(|speed| speed as u64)
+}
+
+/// How good the link under `iface` is, from 0.0 (useless) to 1.0: the
+/// average of the parts that apply to it. Those are the signal level
+/// (-100 to 0 dBm), the wireless transmit rate and the negotiated link speed
+/// (both up to 1 Gbit/s), and the share of received packets without errors.
+/// Fails only when the interface does not exist or its counters cannot be read.
+pub fn compute_link_quality_score(runner: &dyn CommandRunner, iface: &str) -> FailoverResult<f64> {
+    let stats = get_interface_stats(runner, iface)?;
+    let mut parts = Vec::new();
+    if let Some(dbm) = get_wifi_signal_strength(runner, iface) {
+        parts.push(((dbm as f64 + 100.0) / 100.0).clamp(0.0, 1.0));
+        if let Some(rate) = get_wifi_tx_bitrate(runner, iface) {
+            parts.push((rate / LINK_RATE_FULL_MBPS).min(1.0));
+        }
+    }
+    if let Some(speed) = get_link_speed(runner, iface) {
+        parts.push((speed as f64 / LINK_RATE_FULL_MBPS).min(1.0));
+    }
+    if stats.rx_packets > 0 {
+        parts.push(1.0 - (stats.rx_errors as f64 / stats.rx_packets as f64).min(1.0));
+    }
+    if parts.is_empty() {
+        return Ok(1.0); // nothing says the link is bad
+    }
+    Ok(parts.iter().sum::<f64>() / parts.len() as f64)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterfaceAddress {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+    pub scope: String, // "global", "link", "host", ...
+    pub temporary: bool, // IPv6 privacy address
+}
+
+// Parses `ip -o addr show` output: one address per line, both families, e.g.
+// 2: eth0    inet6 2001:db8::10/64 scope global dynamic mngtmpaddr \    valid_lft ...
+pub fn parse_ip_addr_output(stdout: &str) -> Vec<InterfaceAddress> {
+    stdout.lines()
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let i = parts.iter().position(|p| *p == "inet" || *p == "inet6")?;
+            let (addr, prefix_len) = parts.get(i + 1)?.split_once('/')?;
+            let scope = parts.iter()
+                .position(|p| *p == "scope")
+                .and_then(|i| parts.get(i + 1))
+                .map_or("global", |s| *s);
+            Some(InterfaceAddress {
+                addr: addr.parse().ok()?,
+                prefix_len: prefix_len.parse().ok()?,
+                scope: scope.to_string(),
+                temporary: parts.contains(&"temporary"),
+            })
+        })
+        .collect()
+}
+
+pub fn get_interface_addresses(runner: &dyn CommandRunner, iface: &str) -> Result<Vec<InterfaceAddress>> {
+    // Command: ip -o addr show dev <iface>
+    let output = runner.run("ip", &["-o", "addr", "show", "dev", iface])
+        .context("Failed to execute ip addr show")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("ip addr show failed: {}", stderr.trim()));
+    }
+
+    Ok(parse_ip_addr_output(&String::from_utf8_lossy(&output.stdout)))
+}
+
+const TCP_ESTABLISHED: u8 = 0x01;
+
+/// Parses `/proc/net/tcp` or `/proc/net/tcp6` into (local address, state) pairs.
+/// Addresses are printed as the raw in-memory words, hence the native-endian decoding.
+pub fn parse_proc_net_tcp(content: &str) -> Vec<(IpAddr, u8)> {
+    content.lines()
+        .skip(1) // header
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let local_hex = fields.get(1)?.split(':').next()?;
+            let state = u8::from_str_radix(fields.get(3)?, 16).ok()?;
+            let mut bytes = Vec::with_capacity(16);
+            for word in local_hex.as_bytes().chunks(8) {
+                let word = u32::from_str_radix(std::str::from_utf8(word).ok()?, 16).ok()?;
+                bytes.extend_from_slice(&word.to_ne_bytes());
+            }
+            let addr = match bytes.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?),
+                16 => IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?),
+                _ => return None,
+            };
+            Some((addr, state))
+        })
+        .collect()
+}
+
+/// Counts established TCP connections whose local address belongs to `iface`.
+pub fn count_active_connections_via_iface(runner: &dyn CommandRunner, iface: &str) -> Result<usize> {
+    let addresses: Vec<IpAddr> = get_interface_addresses(runner, iface)?.into_iter().map(|a| a.addr).collect();
+    let mut count = 0;
+    for path in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let content = match runner.read_file(Path::new(path)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue, // no IPv6
+            Err(e) => return Err(e).context(format!("Failed to read {}", path)),
+        };
+        count += parse_proc_net_tcp(&content)
+            .iter()
+            .filter(|(addr, state)| *state == TCP_ESTABLISHED && addresses.contains(addr))
+            .count();
+    }
+    Ok(count)
+}
+
+/// Waits up to `max_wait` for established connections via `iface` to close,
+/// checking every 2 seconds. Returns how many were still open at the end.
+pub fn wait_for_connection_drain(runner: &dyn CommandRunner, clock: &dyn Clock, iface: &str, max_wait: Duration) -> Result<usize> {
+    let deadline = clock.now() + max_wait;
+    loop {
+        let count = count_active_connections_via_iface(runner, iface)?;
+        if count == 0 || clock.now() >= deadline {
+            return Ok(count);
+        }
+        log_with_timestamp(&format!("{} connections still active via {}, waiting for them to drain", count, iface));
+        clock.sleep(CONNECTION_DRAIN_CHECK_INTERVAL.min(deadline.saturating_duration_since(clock.now())));
+    }
+}
+
+pub fn run_traceroute(runner: &dyn CommandRunner, iface: &str, target: &str, max_hops: u8) -> Result<Vec<String>> {
+    // Command: traceroute [-6] -i <iface> -m <max_hops> -n -q 1 -w 1 <target>
+    let max_hops = max_hops.to_string();
+    let mut args = if is_ipv6_target(target) { vec!["-6"] } else { Vec::new() };
+    args.extend(["-i", iface, "-m", &max_hops, "-n", "-q", "1", "-w", "1", target]);
+    let output = runner.run("traceroute", &args)
+        .context("Failed to execute traceroute command")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("traceroute failed: {}", stderr.trim()));
+    }
+
+    // Skip the "traceroute to ..." header line
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip(1)
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+pub const IP_ICMP_HEADER_BYTES: u16 = 28;
+pub const IP6_ICMP_HEADER_BYTES: u16 = 48;
+
+// Ping with the don't-fragment bit set and packets of exactly `mtu` bytes
+pub fn ping_dont_fragment(runner: &dyn CommandRunner, iface: &str, target: &str, mtu: u16, timeout: u8, netns: Option<&str>) -> bool {
+    // Command: ping -I <iface> -M do -s <mtu - 28> -c 2 -W <timeout> <target> (mtu - 48 for IPv6)
+    let headers = if is_ipv6_target(target) { IP6_ICMP_HEADER_BYTES } else { IP_ICMP_HEADER_BYTES };
+    let payload = mtu.saturating_sub(headers);
+    match run_ping(runner, netns, target, &[
+        "-I", iface,
+        "-M", "do",
+        "-s", &payload.to_string(),
+        "-c", "2",
+        "-W", &timeout.to_string(),
+        target,
+    ]) {
+        Ok(out) => {
+            debug!("DF ping via {} ({} bytes): {}", iface, mtu, String::from_utf8_lossy(&out.stdout));
+            out.status.success()
+        }
+        Err(e) => {
+            debug!("Failed to execute ping command: {}", e);
+            false
+        }
+    }
+}
+
+/// Sends one UDP packet to the WireGuard endpoint from a socket bound to `iface`
+/// (SO_BINDTODEVICE, needs CAP_NET_RAW). WireGuard silently drops packets it
+/// cannot authenticate, so silence or any reply counts as reachable, and so
+/// does ICMP port-unreachable (the path works, only the port is closed). Host
+/// or network unreachable errors mean the endpoint cannot be reached this way.
+pub fn probe_wg_endpoint(runner: &dyn CommandRunner, iface: &str, endpoint: SocketAddr, timeout: Duration) -> Result<bool> {
+    runner.probe_udp(iface, endpoint, timeout)
+}
+
+fn send_udp_probe(iface: &str, endpoint: SocketAddr, timeout: Duration) -> Result<bool> {
+    let socket = UdpSocket::bind(if endpoint.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })
+        .context("Failed to create UDP socket")?;
+    bind_to_device(&socket, iface)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(endpoint).context("Failed to connect UDP socket")?;
+
+    if let Err(e) = socket.send(&[0u8]) {
+        debug!("UDP send to {} via {} failed: {}", endpoint, iface, e);
+        return Ok(false);
+    }
+    let mut buf = [0u8; 64];
+    match socket.recv(&mut buf) {
+        Ok(_) => Ok(true),
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => Ok(true),
+        Err(e) => {
+            debug!("UDP probe to {} via {} failed: {}", endpoint, iface, e);
+            Ok(false)
+        }
+    }
+}
+
+fn bind_to_device(socket: &UdpSocket, iface: &str) -> Result<()> {
+    let name = iface.as_bytes();
+    // SAFETY: the fd is owned by `socket`, and the name pointer/length describe a valid buffer
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error()).context(format!("Failed to bind socket to {}", iface));
+    }
+    Ok(())
+}
+
+// Command: ip [-6] route <del|replace> <dest> [via <gateway>] dev <dev> [metric <metric>] [table <table>]
+pub fn route_change(runner: &dyn CommandRunner, action: &str, dest: &str, ipv6: bool, route: &RouteInfo) -> Result<()> {
+    let mut args = vec![action, dest];
+    if let Some(gateway) = &route.gateway {
+        args.extend(["via", gateway]);
+    }
+    args.extend(["dev", &route.dev]);
+    let metric = route.metric.map(|m| m.to_string());
+    if let Some(metric) = &metric {
+        args.extend(["metric", metric]);
+    }
+    if let Some(table) = &route.table {
+        args.extend(["table", table]);
+    }
+    let output = ip_route(runner, ipv6, &args).context("Failed to execute ip route command")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("ip route {} {} failed: {}", action, dest, stderr.trim()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorSeverity;
+    use crate::{InterfaceMetrics, InterfaceStatus};
+
+    const IP_DETAILS_LINK_SHOW: &str = "\
+1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536 qdisc noqueue state UNKNOWN mode DEFAULT group default qlen 1000
+    link/loopback 00:00:00:00:00:00 brd 00:00:00:00:00:00 promiscuity 0 minmtu 0 maxmtu 0 addrgenmode eui64 numtxqueues 1 numrxqueues 1
+2: eno3: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc mq state UP mode DEFAULT group default qlen 1000
+    link/ether 3c:ec:ef:01:02:03 brd ff:ff:ff:ff:ff:ff promiscuity 0 minmtu 68 maxmtu 9216 addrgenmode none numtxqueues 8 numrxqueues 8
+    altname enp3s0f0
+3: wlan0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue state UP mode DORMANT group default qlen 1000
+    link/ether 70:9c:d1:aa:bb:cc brd ff:ff:ff:ff:ff:ff promiscuity 0 minmtu 256 maxmtu 2304 addrgenmode none numtxqueues 4 numrxqueues 1
+4: wg0: <POINTOPOINT,NOARP,UP,LOWER_UP> mtu 1420 qdisc noqueue state UNKNOWN mode DEFAULT group default qlen 1000
+    link/none  promiscuity 0 minmtu 0 maxmtu 2147483552
+    wireguard addrgenmode none numtxqueues 1 numrxqueues 1
+5: br0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue state UP mode DEFAULT group default qlen 1000
+    link/ether 02:42:ac:11:00:01 brd ff:ff:ff:ff:ff:ff promiscuity 0 minmtu 68 maxmtu 65535
+    bridge forward_delay 1500 hello_time 200 max_age 2000 ageing_time 30000 stp_state 0 priority 32768
+6: veth1@if5: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue master br0 state UP mode DEFAULT group default
+    link/ether 9a:1c:2b:3d:4e:5f brd ff:ff:ff:ff:ff:ff link-netnsid 0 promiscuity 1 minmtu 68 maxmtu 65535
+    veth
+    bridge_slave state forwarding priority 32 cost 2 hairpin off guard off root_block off fastleave off
+7: eno4: <BROADCAST,MULTICAST,SLAVE,UP,LOWER_UP> mtu 1500 qdisc mq master bond0 state UP mode DEFAULT group default qlen 1000
+    link/ether 3c:ec:ef:01:02:04 brd ff:ff:ff:ff:ff:ff promiscuity 0 minmtu 68 maxmtu 9216
+    bond_slave state ACTIVE mii_status UP link_failure_count 0 perm_hwaddr 3c:ec:ef:01:02:04 queue_id 0
+";
+
+    #[test]
+    fn parse_link_details_reads_names_types_and_kinds() {
+        let links = parse_link_details(IP_DETAILS_LINK_SHOW);
+        let names: Vec<&str> = links.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["lo", "eno3", "wlan0", "wg0", "br0", "veth1", "eno4"]);
+
+        assert_eq!(links[0].link_type, "loopback");
+        assert_eq!(links[3].link_type, "none");
+        assert!(links[3].kinds.contains(&"wireguard".to_string()));
+        assert_eq!(links[5].master.as_deref(), Some("br0"));
+        assert_eq!(links[6].master.as_deref(), Some("bond0"));
+    }
+
+    #[test]
+    fn physical_filter_excludes_loopback_wireguard_and_slaves() {
+        let physical: Vec<String> = parse_link_details(IP_DETAILS_LINK_SHOW)
+            .into_iter()
+            .filter(is_physical_interface)
+            .map(|l| l.name)
+            .collect();
+        assert_eq!(physical, ["eno3", "wlan0", "br0"]);
+    }
+
+    #[test]
+    fn normalize_interface_name_resolves_altnames_case_and_whitespace() {
+        let mut links = parse_link_details(IP_DETAILS_LINK_SHOW);
+        assert_eq!(links[1].altnames, ["enp3s0f0"]);
+        links[2].alias = Some("uplink-lte".to_string());
+
+        assert_eq!(normalize_interface_name("eno3", &links), "eno3");
+        assert_eq!(normalize_interface_name("enp3s0f0", &links), "eno3");
+        assert_eq!(normalize_interface_name(" WLAN0\n", &links), "wlan0");
+        assert_eq!(normalize_interface_name("uplink-lte", &links), "wlan0");
+        assert_eq!(normalize_interface_name(" eth9 ", &links), "eth9");
+    }
+
+    #[test]
+    fn glob_match_handles_wildcards() {
+        assert!(glob_match("docker*", "docker0"));
+        assert!(glob_match("br-*", "br-3f2a"));
+        assert!(glob_match("veth?", "veth1"));
+        assert!(glob_match("*", "eth0"));
+        assert!(glob_match("v*th*", "veth1234"));
+        assert!(!glob_match("docker*", "eno3"));
+        assert!(!glob_match("veth?", "veth12"));
+        assert!(!glob_match("br-*", "br0"));
+
+        let exclude = vec!["docker*".to_string(), "virbr*".to_string()];
+        assert!(is_excluded_interface("virbr0", &exclude));
+        assert!(!is_excluded_interface("wlan0", &exclude));
+    }
+
+    #[test]
+    fn parse_proc_net_tcp_decodes_addresses_and_states() {
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n   0: 0100007F:0277 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1234 1\n   1: 0A01A8C0:D2F0 0E8CBDCE:01BB 01 00000000:00000000 02:000A3B2C 00000000  1000        0 5678 2\n";
+        let entries = parse_proc_net_tcp(tcp);
+        assert_eq!(entries, vec![
+            ("127.0.0.1".parse().unwrap(), 0x0A),
+            ("192.168.1.10".parse().unwrap(), TCP_ESTABLISHED),
+        ]);
+
+        let tcp6 = "  sl  local_address                         remote_address                        st\n   0: 000080FE00000000FF0E1E0211A98CFE:D2F0 00000000000000000000000000000000:0000 01\n";
+        let entries = parse_proc_net_tcp(tcp6);
+        assert_eq!(entries, vec![("fe80::21e:eff:fe8c:a911".parse().unwrap(), TCP_ESTABLISHED)]);
+    }
+
+    #[test]
+    fn parse_route_line_reads_default_route_details() {
+        assert_eq!(
+            parse_route_line("default via 192.168.1.1 dev eno3 proto dhcp src 192.168.1.10 metric 100"),
+            Some(RouteInfo {
+                dev: "eno3".to_string(),
+                gateway: Some("192.168.1.1".to_string()),
+                metric: Some(100),
+                table: None,
+            })
+        );
+        assert_eq!(
+            parse_route_line("default dev wwan0 table 200 scope link"),
+            Some(RouteInfo { dev: "wwan0".to_string(), gateway: None, metric: None, table: Some("200".to_string()) })
+        );
+        assert_eq!(parse_route_line("unreachable default"), None);
+    }
+
+    #[test]
+    fn gratuitous_arp_and_neighbor_advertisement_layout() {
+        let mac = parse_mac("3c:ec:ef:01:02:03").unwrap();
+        assert_eq!(parse_mac("3c:ec:ef:01:02"), None);
+        assert_eq!(parse_mac("3c:ec:ef:01:02:zz"), None);
+
+        let frame = gratuitous_arp_frame(mac, Ipv4Addr::new(192, 168, 1, 10));
+        assert_eq!(frame[..6], [0xff; 6]);
+        assert_eq!(frame[12..14], [0x08, 0x06]);
+        assert_eq!(frame[20..22], [0, 2]);
+        assert_eq!(frame[22..28], mac);
+        assert_eq!(frame[28..32], [192, 168, 1, 10]);
+        assert_eq!(frame[38..42], [192, 168, 1, 10]);
+
+        let target: Ipv6Addr = "2001:db8::10".parse().unwrap();
+        let message = unsolicited_na_message(target, mac);
+        assert_eq!(message[..5], [136, 0, 0, 0, 0x20]);
+        assert_eq!(message[8..24], target.octets());
+        assert_eq!(message[24..], [2, 1, 0x3c, 0xec, 0xef, 0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn ipv6_targets_are_detected_bare_and_bracketed() {
+        assert_eq!(strip_ip_brackets("[2001:db8::1]"), "2001:db8::1");
+        assert_eq!(strip_ip_brackets("2001:db8::1"), "2001:db8::1");
+        assert_eq!(strip_ip_brackets("[not-an-ip]"), "[not-an-ip]");
+
+        assert!(is_ipv6_target("2001:db8::1"));
+        assert!(is_ipv6_target("[2001:db8::1]"));
+        assert!(is_ipv6_target("2001:db8::/32"));
+        assert!(!is_ipv6_target("192.0.2.1"));
+        assert!(!is_ipv6_target("10.0.0.0/8"));
+        assert!(!is_ipv6_target("vpn.example.com"));
+    }
+
+    #[test]
+    fn ping_summary_parses_gnu_and_busybox_output() {
+        let gnu = parse_ping_summary("10 packets transmitted, 9 received, 10% packet loss, time 9012ms").unwrap();
+        assert_eq!(gnu, PingSummary { transmitted: 10, received: 9, loss_pct: 10.0, time_ms: Some(9012.0) });
+
+        let errors = parse_ping_summary("5 packets transmitted, 0 received, +5 errors, 100% packet loss, time 4005ms").unwrap();
+        assert_eq!((errors.received, errors.loss_pct), (0, 100.0));
+
+        let busybox = parse_ping_summary("3 packets transmitted, 1 packets received, 66% packet loss").unwrap();
+        assert_eq!(busybox, PingSummary { transmitted: 3, received: 1, loss_pct: 66.0, time_ms: None });
+
+        let fractional = parse_ping_summary("3 packets transmitted, 2 received, 33.3333% packet loss, time 2003ms").unwrap();
+        assert!((fractional.loss_pct - 33.3333).abs() < 1e-9);
+
+        assert_eq!(parse_ping_summary("rtt min/avg/max/mdev = 1.1/2.2/3.3/0.4 ms"), None);
+        assert_eq!(ping_loss_pct("ping: connect: Network is unreachable", false), 100.0);
+        assert_eq!(ping_loss_pct("PING 192.0.2.1\n4 packets transmitted, 1 received, 75% packet loss", true), 75.0);
+    }
+
+    #[test]
+    fn bandwidth_estimate_uses_total_ping_time() {
+        let stdout = "\
+PING 203.0.113.7 (203.0.113.7) 1400(1428) bytes of data.
+1408 bytes from 203.0.113.7: icmp_seq=1 ttl=57 time=20.4 ms
+1408 bytes from 203.0.113.7: icmp_seq=2 ttl=57 time=19.9 ms
+1408 bytes from 203.0.113.7: icmp_seq=3 ttl=57 time=20.1 ms
+1408 bytes from 203.0.113.7: icmp_seq=4 ttl=57 time=20.6 ms
+1408 bytes from 203.0.113.7: icmp_seq=5 ttl=57 time=19.8 ms
+1408 bytes from 203.0.113.7: icmp_seq=6 ttl=57 time=20.0 ms
+1408 bytes from 203.0.113.7: icmp_seq=7 ttl=57 time=21.0 ms
+1408 bytes from 203.0.113.7: icmp_seq=8 ttl=57 time=20.2 ms
+1408 bytes from 203.0.113.7: icmp_seq=10 ttl=57 time=20.3 ms
+
+--- 203.0.113.7 ping statistics ---
+10 packets transmitted, 9 received, 10% packet loss, time 184ms
+rtt min/avg/max/mdev = 19.812/20.256/21.034/0.352 ms, ipg/ewma 20.412/20.281 ms
+";
+        let estimate = parse_bandwidth_estimate(stdout).unwrap();
+        // 9 replies x 1408 bytes each way in 184 ms
+        assert!((estimate.throughput_mbps - 9.0 * 1408.0 * 2.0 * 8.0 / 0.184 / 1e6).abs() < 1e-9);
+        assert_eq!(estimate.latency_ms, 20.256);
+        assert_eq!(parse_ping_stats(stdout).unwrap().mdev_ms, 0.352);
+
+        let no_replies = "10 packets transmitted, 0 received, 100% packet loss, time 9203ms\n";
+        assert!(parse_bandwidth_estimate(no_replies).is_none());
+    }
+
+    #[test]
+    fn link_is_up_requires_carrier_and_usable_operstate() {
+        assert!(link_is_up(Some("1\n"), Some("up\n")));
+        assert!(link_is_up(Some("1\n"), Some("unknown\n")));
+        assert!(!link_is_up(Some("0\n"), Some("down\n")));
+        assert!(!link_is_up(Some("1\n"), Some("lowerlayerdown\n")));
+        assert!(!link_is_up(None, Some("down\n"))); // administratively down
+    }
+
+    const IP_O_ADDR_SHOW: &str = "\
+2: eno3    inet 192.168.1.10/24 brd 192.168.1.255 scope global dynamic noprefixroute eno3\\       valid_lft 85732sec preferred_lft 85732sec
+2: eno3    inet6 2001:db8:1::5c3a:9e1f:2b4d:7a10/64 scope global temporary dynamic \\       valid_lft 86121sec preferred_lft 14121sec
+2: eno3    inet6 2001:db8:1::a00:27ff:fe4e:66a1/64 scope global dynamic mngtmpaddr noprefixroute \\       valid_lft 86121sec preferred_lft 14121sec
+2: eno3    inet6 fe80::a00:27ff:fe4e:66a1/64 scope link noprefixroute \\       valid_lft forever preferred_lft forever
+";
+
+    #[test]
+    fn parse_ip_addr_output_handles_both_families() {
+        let addresses = parse_ip_addr_output(IP_O_ADDR_SHOW);
+        assert_eq!(addresses.len(), 4);
+
+        assert_eq!(addresses[0], InterfaceAddress {
+            addr: "192.168.1.10".parse().unwrap(),
+            prefix_len: 24,
+            scope: "global".to_string(),
+            temporary: false,
+        });
+        assert_eq!(addresses[1], InterfaceAddress {
+            addr: "2001:db8:1::5c3a:9e1f:2b4d:7a10".parse().unwrap(),
+            prefix_len: 64,
+            scope: "global".to_string(),
+            temporary: true,
+        });
+        assert!(!addresses[2].temporary);
+        assert_eq!(addresses[2].scope, "global");
+        assert_eq!(addresses[3], InterfaceAddress {
+            addr: "fe80::a00:27ff:fe4e:66a1".parse().unwrap(),
+            prefix_len: 64,
+            scope: "link".to_string(),
+            temporary: false,
+        });
+        assert!(parse_ip_addr_output("").is_empty());
+    }
+
+    #[test]
+    fn interface_metrics_reset_when_stable_addresses_change() {
+        let addresses = parse_ip_addr_output(IP_O_ADDR_SHOW);
+        let mut metrics = InterfaceMetrics::default();
+        assert!(!metrics.update_addresses(&addresses), "first address set is only recorded");
+        assert!(!metrics.update_addresses(&addresses));
+
+        // Losing a temporary address is not a change; losing a global one is
+        let stable: Vec<InterfaceAddress> = addresses.iter().filter(|a| !a.temporary).cloned().collect();
+        assert!(!metrics.update_addresses(&stable));
+        assert!(metrics.update_addresses(&stable[1..]));
+
+        metrics.status = InterfaceStatus::Working;
+        metrics.connectivity_latency_ms = 20.0;
+        metrics.reset();
+        assert_eq!(metrics.status, InterfaceStatus::Unknown);
+        assert_eq!(metrics.connectivity_latency_ms, 0.0);
+        assert!(metrics.addresses.is_some());
+    }
+
+    #[test]
+    fn parse_link_details_handles_empty_output() {
+        assert!(parse_link_details("").is_empty());
+    }
+
+    #[test]
+    fn parse_link_show_output_distinguishes_failure_from_no_links() {
+        let links = parse_link_show_output(true, "", "").expect("empty output is not an error");
+        assert!(links.is_empty());
+
+        let err = parse_link_show_output(false, "", "Cannot open netlink socket: Operation not permitted\n")
+            .expect_err("failed command must be an error");
+        assert!(matches!(err, FailoverError::InsufficientPermissions(_)));
+        assert!(err.to_string().contains("Operation not permitted"));
+
+        let links = parse_link_show_output(true, IP_DETAILS_LINK_SHOW, "").unwrap();
+        assert!(!links.is_empty());
+    }
+
+    fn exited(code: i32, stdout: &str) -> Output {
+        use std::os::unix::process::ExitStatusExt;
+        Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn route_lookups_parse_canned_ip_route_output() {
+        let mut runner = MockCommandRunner::new();
+        runner.expect_run()
+            .withf(|program, args| program == "ip" && args == ["route", "show", "dev", "eth0"])
+            .times(1)
+            .returning(|_, _| Ok(exited(0, "10.0.0.0/24 via 10.0.0.254 proto static\ndefault via 192.168.1.1 proto dhcp metric 100\n")));
+        runner.expect_run()
+            .withf(|program, args| program == "ip" && args == ["-6", "route", "show", "default"])
+            .times(1)
+            .returning(|_, _| Ok(exited(0, "default via fe80::1 dev wlan0 proto ra metric 600 pref medium\ndefault via fe80::2 dev eth0 proto ra metric 100 pref medium\n")));
+        runner.expect_run()
+            .withf(|program, args| program == "ip" && args == ["route", "show", "default"])
+            .times(1)
+            .returning(|_, _| Ok(exited(2, "")));
+
+        assert_eq!(get_gateway_for_interface(&runner, "eth0", false), Some("192.168.1.1".to_string()));
+        let routes = routes_for_destination(&runner, "default", true).unwrap();
+        assert_eq!(routes.iter().map(|r| r.dev.as_str()).collect::<Vec<_>>(), ["wlan0", "eth0"]);
+        assert_eq!(routes[1].metric, Some(100));
+        assert!(default_route_info(&runner, false).is_err());
+    }
+
+    #[test]
+    fn link_quality_combines_signal_rate_speed_and_errors() {
+        let files = HashMap::from([
+            ("/proc/net/wireless", "Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE\n \
+                face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22\n \
+               wlan0: 0000   54.  -60.  -256        0      0      0      0     12        0\n"),
+            ("/sys/class/net/wlan0/statistics/rx_packets", "1000\n"),
+            ("/sys/class/net/wlan0/statistics/rx_errors", "100\n"),
+            ("/sys/class/net/eth0/statistics/rx_packets", "5000\n"),
+            ("/sys/class/net/eth0/statistics/rx_errors", "0\n"),
+            ("/sys/class/net/eth0/speed", "1000\n"),
+            ("/sys/class/net/wlan0/speed", "-1\n"),
+        ]);
+        let mut runner = MockCommandRunner::new();
+        runner.expect_read_file().returning(move |path| {
+            let path = path.to_str().unwrap();
+            match files.get(path) {
+                Some(text) => Ok(text.to_string()),
+                None if path.contains("/statistics/tx_") => Ok("0\n".to_string()),
+                None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, path.to_string())),
+            }
+        });
+        runner.expect_run()
+            .withf(|program, args| program == "iw" && args == ["dev", "wlan0", "link"])
+            .returning(|_, _| Ok(exited(0, "Connected to aa:bb:cc:dd:ee:ff (on wlan0)\n\tsignal: -60 dBm\n\ttx bitrate: 400.0 MBit/s VHT-MCS 9\n")));
+
+        assert_eq!(get_wifi_signal_strength(&runner, "wlan0"), Some(-60));
+        assert_eq!(get_wifi_signal_strength(&runner, "eth0"), None);
+        // signal 0.4, rate 0.4, no speed, 10% rx errors 0.9
+        let wifi = compute_link_quality_score(&runner, "wlan0").unwrap();
+        assert!((wifi - 1.7 / 3.0).abs() < 1e-9, "{}", wifi);
+        assert_eq!(compute_link_quality_score(&runner, "eth0").unwrap(), 1.0);
+        assert!(matches!(compute_link_quality_score(&runner, "eth9"), Err(FailoverError::InterfaceNotFound(_))));
+    }
+
+    #[test]
+    fn measure_latency_reads_canned_ping_output() {
+        let mut runner = MockCommandRunner::new();
+        runner.expect_run()
+            .withf(|program, args| program == "ping" && args == ["-I", "eth0", "-c", "4", "-W", "1", "192.0.2.1"])
+            .times(1)
+            .returning(|_, _| Ok(exited(0, "PING 192.0.2.1 (192.0.2.1) from 192.168.1.10 eth0: 56(84) bytes of data.\n\n\
+                --- 192.0.2.1 ping statistics ---\n\
+                4 packets transmitted, 3 received, 25% packet loss, time 3004ms\n\
+                rtt min/avg/max/mdev = 10.112/12.500/15.020/2.003 ms\n")));
+        runner.expect_run()
+            .withf(|program, args| program == "ping" && args.last() == Some(&"192.0.2.2"))
+            .times(1)
+            .returning(|_, _| Ok(exited(1, "4 packets transmitted, 0 received, 100% packet loss, time 3060ms\n")));
+        runner.expect_run()
+            .withf(|program, args| program == "ip" && args[..4] == ["netns", "exec", "wan", "ping"])
+            .times(1)
+            .returning(|_, _| Err(std::io::Error::from(std::io::ErrorKind::NotFound)));
+
+        assert_eq!(measure_latency(&runner, "eth0", "192.0.2.1", 4, 1, None), (true, 12.5, 25.0));
+        assert_eq!(measure_latency(&runner, "eth0", "192.0.2.2", 4, 1, None), (false, 0.0, 100.0));
+        assert_eq!(measure_latency(&runner, "eth0", "192.0.2.3", 4, 1, Some("wan")), (false, 0.0, 100.0));
+    }
+
+    #[test]
+    fn ping_interface_separates_errors_from_unreachable_targets() {
+        let failing = |stderr: &'static str| move |_: &str, _: &[&str]| Ok(Output { stderr: stderr.as_bytes().to_vec(), ..exited(2, "") });
+        let mut runner = MockCommandRunner::new();
+        runner.expect_run()
+            .withf(|_, args| args.last() == Some(&"192.0.2.1"))
+            .returning(failing("ping: socktype: SOCK_RAW\nping: socket: Operation not permitted\n"));
+        runner.expect_run()
+            .withf(|_, args| args.last() == Some(&"192.0.2.2"))
+            .returning(failing("ping: SO_BINDTODEVICE eth9: No such device\n"));
+        runner.expect_run()
+            .withf(|_, args| args.last() == Some(&"192.0.2.3"))
+            .returning(failing("ping: connect: Network is unreachable\n"));
+        runner.expect_run()
+            .withf(|program, _| program == "ip")
+            .returning(|_, _| Err(std::io::Error::from(std::io::ErrorKind::NotFound)));
+
+        let permissions = ping_interface(&runner, "eth0", "192.0.2.1", 1, 1, None).unwrap_err();
+        assert!(matches!(permissions, FailoverError::InsufficientPermissions(_)), "{:?}", permissions);
+        let missing = ping_interface(&runner, "eth9", "192.0.2.2", 1, 1, None).unwrap_err();
+        assert!(matches!(missing, FailoverError::InterfaceNotFound(ref iface) if iface == "eth9"), "{:?}", missing);
+        assert_eq!(ping_interface(&runner, "eth0", "192.0.2.3", 1, 1, None).unwrap(), (false, 0.0, 100.0));
+
+        let spawn = list_physical_interfaces(&runner, &[]).unwrap_err();
+        assert!(matches!(spawn, FailoverError::Io { ref command, .. } if command == "ip link show"), "{:?}", spawn);
+
+        assert_eq!(permissions.severity(), ErrorSeverity::Critical);
+        assert_eq!(missing.severity(), ErrorSeverity::Permanent);
+        assert_eq!(spawn.severity(), ErrorSeverity::Permanent);
+        assert!(!permissions.is_recoverable() && !missing.is_recoverable() && !spawn.is_recoverable());
+        let timed_out = FailoverError::io("ping", std::io::Error::from(std::io::ErrorKind::TimedOut));
+        assert_eq!(timed_out.severity(), ErrorSeverity::Transient);
+        assert!(timed_out.is_recoverable());
+        assert!(FailoverError::command("ip route replace", "RTNETLINK answers: File exists").is_recoverable());
+        assert!(!FailoverError::UnsupportedOs("CPU affinity".to_string()).is_recoverable());
+    }
+
+    #[test]
+    fn delete_route_is_idempotent_and_honours_the_table() {
+        let failing = |stderr: &'static str| move |_: &str, _: &[&str]| Ok(Output { stderr: stderr.as_bytes().to_vec(), ..exited(2, "") });
+        let mut runner = MockCommandRunner::new();
+        runner.expect_run()
+            .withf(|program, args| program == "ip" && args == ["route", "del", "192.0.2.1", "table", "200"])
+            .times(1)
+            .returning(failing("RTNETLINK answers: No such process\n"));
+        runner.expect_run()
+            .withf(|_, args| args == ["-6", "route", "del", "2001:db8::1"])
+            .times(1)
+            .returning(failing("RTNETLINK answers: Operation not permitted\n"));
+        runner.expect_run()
+            .withf(|_, args| args == ["route", "del", "192.0.2.2"])
+            .times(1)
+            .returning(|_, _| Ok(exited(0, "")));
+
+        assert!(delete_route(&runner, "192.0.2.1", Some(200)).is_ok());
+        let err = delete_route(&runner, "2001:db8::1", None).unwrap_err();
+        assert!(matches!(err, FailoverError::InsufficientPermissions(_)), "{:?}", err);
+        assert!(delete_route(&runner, "192.0.2.2", None).is_ok());
+    }
+}
@@ -0,0 +1,131 @@
+//! The last chosen interface and switch time, kept in a small JSON file so a
+//! restarted daemon carries on where it left off instead of choosing afresh.
+
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+// Unknown keys are ignored, so older daemons can read newer files
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub(crate) struct PersistedState {
+    #[serde(default)]
+    pub(crate) interface: Option<String>,
+    #[serde(default, with = "rfc3339")]
+    pub(crate) last_switch: Option<DateTime<Utc>>,
+}
+
+impl PersistedState {
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("state serializes to JSON")
+    }
+
+    pub(crate) fn from_json(text: &str) -> serde_json::Result<Self> {
+        // Through a map first: serde would also accept a JSON array for a struct
+        let fields: serde_json::Map<String, serde_json::Value> = serde_json::from_str(text)?;
+        let mut state: Self = serde_json::from_value(fields.into())?;
+        state.interface = state.interface.filter(|iface| !iface.is_empty());
+        Ok(state)
+    }
+
+    /// Reads the state file. A missing file is a first start; an unreadable
+    /// or corrupt one is reported and ignored, so the daemon starts fresh.
+    pub(crate) fn load(path: &Path) -> Option<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No state file at {:?}, starting fresh", path);
+                return None;
+            }
+            Err(e) => {
+                warn!("Could not read state file {:?}, starting fresh: {}", path, e);
+                return None;
+            }
+        };
+        match Self::from_json(&text) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                warn!("Ignoring corrupt state file {:?}, starting fresh: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Writes the state file through a temporary file and a rename, so a crash
+    /// mid-write leaves the previous state instead of a truncated file.
+    pub(crate) fn save(&self, path: &Path) {
+        let result = (|| {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)?;
+            }
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, format!("{}\n", self.to_json()))?;
+            std::fs::rename(&tmp, path)
+        })();
+        match result {
+            Ok(()) => info!("Saved active interface {:?} to {:?}", self.interface, path),
+            Err(e) => warn!("Failed to save state to {:?}: {}", path, e),
+        }
+    }
+}
+
+// last_switch as written by DateTime::to_rfc3339, null when there is none
+mod rfc3339 {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(time: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serializer.serialize_str(&time.to_rfc3339()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|text| DateTime::parse_from_rfc3339(&text).map(|t| t.with_timezone(&Utc)).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persisted_state_round_trips_through_json() {
+        let state = PersistedState {
+            interface: Some("wlan\"0".to_string()),
+            last_switch: Some(DateTime::parse_from_rfc3339("2026-10-15T04:47:19+00:00").unwrap().with_timezone(&Utc)),
+        };
+        assert_eq!(state.to_json(), r#"{"interface":"wlan\"0","last_switch":"2026-10-15T04:47:19+00:00"}"#);
+        assert_eq!(PersistedState::from_json(&state.to_json()).ok(), Some(state));
+
+        let pretty = "{\n  \"version\": 1,\n  \"interface\": \"eth0\",\n  \"last_switch\": null\n}\n";
+        assert_eq!(
+            PersistedState::from_json(pretty).ok(),
+            Some(PersistedState { interface: Some("eth0".to_string()), last_switch: None })
+        );
+        assert_eq!(PersistedState::from_json("{}").ok(), Some(PersistedState::default()));
+        assert_eq!(PersistedState::from_json(r#"{"interface":""}"#).ok(), Some(PersistedState::default()));
+    }
+
+    #[test]
+    fn corrupt_or_missing_state_files_start_fresh() {
+        for corrupt in ["", "{", "{\"interface\":\"eth0\"", "{\"interface\":eth0}", "{\"interface\":1}",
+                        "{\"last_switch\":\"yesterday\"}", "{\"interface\":\"eth0\"} trailing", "[\"eth0\"]"] {
+            assert!(PersistedState::from_json(corrupt).is_err(), "{:?}", corrupt);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested/state.json");
+        assert_eq!(PersistedState::load(&path), None);
+
+        let state = PersistedState { interface: Some("eth0".to_string()), last_switch: Some(Utc::now()) };
+        state.save(&path);
+        assert_eq!(PersistedState::load(&path), Some(state));
+
+        std::fs::write(&path, "{\"interface\":").unwrap();
+        assert_eq!(PersistedState::load(&path), None);
+    }
+}
@@ -0,0 +1,257 @@
+//! The per-cycle summary line and the JSON state dump written on SIGUSR2.
+
+use log::{error, info};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::{interface_role, AppState, InterfaceMetrics, InterfaceStatus, RecoveryStats, StatusWindow};
+
+pub(crate) fn json_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub(crate) fn json_str(value: &str) -> String {
+    format!("\"{}\"", json_escape(value))
+}
+
+pub(crate) fn json_f64(value: f64) -> String {
+    if value.is_finite() { value.to_string() } else { "null".to_string() }
+}
+
+pub(crate) fn json_opt<T>(value: Option<T>, encode: impl Fn(T) -> String) -> String {
+    value.map_or_else(|| "null".to_string(), encode)
+}
+
+// `fields` values must already be JSON
+pub(crate) fn json_object(fields: &[(&str, String)]) -> String {
+    let body: Vec<String> = fields.iter().map(|(k, v)| format!("{}:{}", json_str(k), v)).collect();
+    format!("{{{}}}", body.join(","))
+}
+
+impl InterfaceMetrics {
+    pub(crate) fn to_json(&self) -> String {
+        let mut results: Vec<(&String, &bool)> = self.test_results.iter().collect();
+        results.sort();
+        let results: Vec<(&str, String)> = results.into_iter().map(|(ip, ok)| (ip.as_str(), ok.to_string())).collect();
+        json_object(&[
+            ("status", json_str(&format!("{:?}", self.status))),
+            ("state", json_str(&self.state.to_string())),
+            ("connectivity_latency_ms", json_f64(self.connectivity_latency_ms)),
+            ("speed_latency_ms", json_f64(self.speed_latency_ms)),
+            ("packet_loss_pct", json_f64(self.packet_loss_pct)),
+            ("estimated_bandwidth_mbps", json_opt(self.estimated_bandwidth_mbps, json_f64)),
+            ("link_quality_score", json_opt(self.link_quality_score, json_f64)),
+            ("health_score", self.health_score.to_string()),
+            ("gateway_probe_ms", json_f64(self.gateway_probe_ms)),
+            ("working_v4", json_opt(self.working_v4, |ok| ok.to_string())),
+            ("working_v6", json_opt(self.working_v6, |ok| ok.to_string())),
+            ("test_results", json_object(&results)),
+        ])
+    }
+}
+
+impl StatusWindow {
+    pub(crate) fn to_json(&self) -> String {
+        let history: Vec<String> = self.history.iter().map(bool::to_string).collect();
+        json_object(&[("size", self.size.to_string()), ("history", format!("[{}]", history.join(",")))])
+    }
+}
+
+/// One line per cycle for at-a-glance monitoring, e.g.
+/// `cycle 12 | active=eth0 | eth0: up 12.0ms 0% | wlan0: down 100%`.
+/// With `color` the states become green/red check marks (yellow for an
+/// interface that is recovering) and a switch is flagged with a lightning
+/// bolt; otherwise plain ASCII (OK, FAIL, RECOVERING, SWITCH).
+pub(crate) fn format_summary(
+    cycle: u64,
+    active: Option<&str>,
+    recovering: Option<&str>,
+    interfaces: &[(&str, &InterfaceMetrics)],
+    switched_from: Option<Option<&str>>,
+    color: bool,
+) -> String {
+    const GREEN: &str = "\x1b[32m";
+    const RED: &str = "\x1b[31m";
+    const YELLOW: &str = "\x1b[33m";
+    const RESET: &str = "\x1b[0m";
+
+    let mut parts = vec![format!("cycle {}", cycle), format!("active={}", active.unwrap_or("none"))];
+    for (iface, metrics) in interfaces {
+        let loss = if metrics.test_results.is_empty() {
+            String::new()
+        } else {
+            format!(" {:.0}%", metrics.packet_loss_pct)
+        };
+        let is_recovering = metrics.state.is_recovering() || recovering == Some(*iface);
+        let part = match (&metrics.status, color) {
+            (InterfaceStatus::Working, true) if is_recovering => format!(
+                "{}: {}~ recovering{} {:.1}ms{}", iface, YELLOW, RESET, metrics.connectivity_latency_ms, loss),
+            (InterfaceStatus::Working, false) if is_recovering => format!(
+                "{}: RECOVERING {:.1}ms{}", iface, metrics.connectivity_latency_ms, loss),
+            (InterfaceStatus::Working, true) => format!(
+                "{}: {}\u{2713} up{} {:.1}ms{}", iface, GREEN, RESET, metrics.connectivity_latency_ms, loss),
+            (InterfaceStatus::Working, false) => format!("{}: OK up {:.1}ms{}", iface, metrics.connectivity_latency_ms, loss),
+            (InterfaceStatus::Failed, true) => format!("{}: {}\u{2717} down{}{}", iface, RED, RESET, loss),
+            (InterfaceStatus::Failed, false) => format!("{}: FAIL down{}", iface, loss),
+            (InterfaceStatus::Unknown, _) => format!("{}: unknown", iface),
+        };
+        parts.push(part);
+    }
+    if let Some(from) = switched_from {
+        let change = format!("{} -> {}", from.unwrap_or("none"), active.unwrap_or("none"));
+        parts.push(if color {
+            format!("{}\u{26a1} switch{} {}", YELLOW, RESET, change)
+        } else {
+            format!("SWITCH {}", change)
+        });
+    }
+    parts.join(" | ")
+}
+
+/// Everything the monitoring loop keeps between cycles, borrowed at one instant
+/// for the SIGUSR2 state dump.
+pub(crate) struct MonitorSnapshot<'a> {
+    pub(crate) state: &'a AppState,
+    pub(crate) cycle: u64,
+    pub(crate) current_active_interface: Option<&'a str>,
+    pub(crate) recovering_interface: Option<&'a str>,
+    pub(crate) metrics: &'a [InterfaceMetrics],
+    pub(crate) windows: &'a [StatusWindow],
+    pub(crate) outages: &'a [bool],
+    pub(crate) outage_started: Option<Instant>,
+    pub(crate) last_speed_check: Instant,
+    pub(crate) last_traceroutes: &'a HashMap<String, Instant>,
+    pub(crate) recovery_stats: &'a RecoveryStats,
+    pub(crate) capture_file: Option<PathBuf>,
+}
+
+impl MonitorSnapshot<'_> {
+    pub(crate) fn to_json(&self) -> String {
+        let now = self.state.clock.now();
+        let secs_ago = |t: Instant| json_f64(now.saturating_duration_since(t).as_secs_f64());
+        let secs = |d: Duration| json_f64(d.as_secs_f64());
+        let peer_cache = self.state.peer_cache.lock().unwrap_or_else(|e| e.into_inner());
+        let (cache_hits, cache_misses) = self.state.route_cache.stats();
+        let mut traceroutes: Vec<(&str, String)> = self.last_traceroutes.iter().map(|(i, t)| (i.as_str(), secs_ago(*t))).collect();
+        traceroutes.sort();
+        let test_ips: Vec<String> = self.state.test_ips.iter().map(|ip| json_str(ip)).collect();
+        let interfaces: Vec<String> = self.state.interfaces.iter().enumerate()
+            .map(|(i, iface)| json_object(&[
+                ("interface", json_str(iface)),
+                ("role", json_str(&interface_role(i))),
+                ("metrics", self.metrics[i].to_json()),
+                ("window", self.windows[i].to_json()),
+                ("outage", self.outages[i].to_string()),
+            ]))
+            .collect();
+
+        json_object(&[
+            ("timestamp", json_str(&self.state.clock.utc_now().with_timezone(&chrono::Local).to_rfc3339())),
+            ("pid", std::process::id().to_string()),
+            ("cycle", self.cycle.to_string()),
+            ("peer_ip", json_str(&self.state.peer_ip.to_string())),
+            ("test_ips", format!("[{}]", test_ips.join(","))),
+            ("current_active_interface", json_opt(self.current_active_interface, json_str)),
+            ("recovering_interface", json_opt(self.recovering_interface, json_str)),
+            ("interfaces", format!("[{}]", interfaces.join(","))),
+            ("outage_started_secs_ago", json_opt(self.outage_started, secs_ago)),
+            ("last_speed_check_secs_ago", secs_ago(self.last_speed_check)),
+            ("last_traceroutes_secs_ago", json_object(&traceroutes)),
+            ("recovery", json_object(&[
+                ("count", self.recovery_stats.count.to_string()),
+                ("average_secs", json_opt(self.recovery_stats.average(), secs)),
+                ("min_secs", json_opt(self.recovery_stats.min, secs)),
+                ("max_secs", json_opt(self.recovery_stats.max, secs)),
+            ])),
+            ("peer_cache", json_object(&[
+                ("consecutive_failures", peer_cache.consecutive_failures.to_string()),
+                ("excluded", peer_cache.is_excluded(now).to_string()),
+                ("excluded_for_secs", json_opt(peer_cache.excluded_until.map(|u| u.saturating_duration_since(now)), secs)),
+            ])),
+            ("capture_file", json_opt(self.capture_file.as_ref(), |p| json_str(&p.display().to_string()))),
+            ("route_cache", json_object(&[("hits", cache_hits.to_string()), ("misses", cache_misses.to_string())])),
+            ("timers", json_object(&[
+                ("check_interval_secs", secs(self.state.check_interval)),
+                ("speed_check_interval_secs", secs(self.state.speed_check_interval)),
+            ])),
+        ])
+    }
+}
+
+pub(crate) fn write_state_dump(json: &str, path: Option<&Path>) {
+    match path {
+        Some(path) => match std::fs::write(path, format!("{}\n", json)) {
+            Ok(()) => info!("State dump written to {:?}", path),
+            Err(e) => error!("Failed to write state dump to {:?}: {}", path, e),
+        },
+        None => info!("State dump: {}", json),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interface_metrics_serialize_to_json() {
+        let metrics = InterfaceMetrics {
+            status: InterfaceStatus::Working,
+            connectivity_latency_ms: 12.5,
+            packet_loss_pct: 50.0,
+            test_results: HashMap::from([("8.8.8.8".to_string(), true), ("1.1.1.1".to_string(), false)]),
+            working_v4: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            metrics.to_json(),
+            r#"{"status":"Working","state":"unknown","connectivity_latency_ms":12.5,"speed_latency_ms":0,"packet_loss_pct":50,"estimated_bandwidth_mbps":null,"link_quality_score":null,"health_score":0,"gateway_probe_ms":0,"working_v4":true,"working_v6":null,"test_results":{"1.1.1.1":false,"8.8.8.8":true}}"#
+        );
+        assert_eq!(json_str("a\"b\\c\n"), r#""a\"b\\c\n""#);
+    }
+
+    #[test]
+    fn summary_line_is_plain_without_color() {
+        let up = InterfaceMetrics {
+            status: InterfaceStatus::Working,
+            connectivity_latency_ms: 12.0,
+            test_results: HashMap::from([("1.1.1.1".to_string(), true), ("8.8.8.8".to_string(), true)]),
+            ..Default::default()
+        };
+        let down = InterfaceMetrics {
+            status: InterfaceStatus::Failed,
+            packet_loss_pct: 100.0,
+            test_results: HashMap::from([("1.1.1.1".to_string(), false), ("8.8.8.8".to_string(), false)]),
+            ..Default::default()
+        };
+        let ifaces = [("eth0", &up), ("wlan0", &down)];
+
+        assert_eq!(
+            format_summary(7, Some("eth0"), None, &ifaces, None, false),
+            "cycle 7 | active=eth0 | eth0: OK up 12.0ms 0% | wlan0: FAIL down 100%"
+        );
+        assert_eq!(
+            format_summary(8, Some("eth0"), None, &ifaces, Some(Some("wlan0")), false),
+            "cycle 8 | active=eth0 | eth0: OK up 12.0ms 0% | wlan0: FAIL down 100% | SWITCH wlan0 -> eth0"
+        );
+        assert!(format_summary(7, Some("eth0"), None, &ifaces, None, true).contains("\x1b[32m\u{2713} up"));
+
+        let back = [("eth0", &up), ("wlan0", &up)];
+        assert_eq!(
+            format_summary(9, Some("wlan0"), Some("eth0"), &back, None, false),
+            "cycle 9 | active=wlan0 | eth0: RECOVERING 12.0ms 0% | wlan0: OK up 12.0ms 0%"
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! wg-quick style WireGuard configs, read and written back, so a changed
+//! peer endpoint can be persisted for the next start.
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+use crate::error::{FailoverError, FailoverResult};
+
+/// The `[Interface]` and `[Peer]` sections of a wg-quick config. Every key
+/// is kept, in file order and with its original spelling, so writing the
+/// config back loses nothing but comments and blank lines.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WireGuardQuickConfig {
+    pub interface: WgSection,
+    pub peers: Vec<WgSection>,
+}
+
+/// The `Key = Value` lines of one section.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WgSection {
+    pub entries: Vec<(String, String)>,
+}
+
+impl WgSection {
+    /// The value of the first `key`, compared case-insensitively like wg-quick does.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str())
+    }
+
+    /// Replaces the value of the first `key`, or appends the key.
+    pub fn set(&mut self, key: &str, value: impl Into<String>) {
+        match self.entries.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            Some((_, v)) => *v = value.into(),
+            None => self.entries.push((key.to_string(), value.into())),
+        }
+    }
+}
+
+impl WireGuardQuickConfig {
+    /// The `Endpoint` of the first peer, which is the one the daemon fails over.
+    pub fn endpoint(&self) -> Option<&str> {
+        self.peers.first().and_then(|peer| peer.get("Endpoint"))
+    }
+
+    /// The config in wg-quick format: `[Interface]` first, then each `[Peer]`
+    /// in order, separated by blank lines.
+    pub fn to_ini(&self) -> String {
+        let mut out = String::new();
+        let sections = std::iter::once(("Interface", &self.interface)).chain(self.peers.iter().map(|peer| ("Peer", peer)));
+        for (i, (name, section)) in sections.enumerate() {
+            if i > 0 {
+                out.push('\n');
+            }
+            let _ = writeln!(out, "[{}]", name);
+            for (key, value) in &section.entries {
+                let _ = writeln!(out, "{} = {}", key, value);
+            }
+        }
+        out
+    }
+}
+
+/// Parses a wg-quick config. `#` starts a comment anywhere on a line. A
+/// missing `[Interface]` section reads as an empty one.
+pub fn parse_wg_config(content: &str) -> FailoverResult<WireGuardQuickConfig> {
+    let invalid = |number: usize, what: &str| FailoverError::InvalidConfig(format!("line {}: {}", number + 1, what));
+    let mut config = WireGuardQuickConfig::default();
+    let mut section: Option<&mut WgSection> = None;
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = if name.trim().eq_ignore_ascii_case("interface") {
+                Some(&mut config.interface)
+            } else if name.trim().eq_ignore_ascii_case("peer") {
+                config.peers.push(WgSection::default());
+                config.peers.last_mut()
+            } else {
+                return Err(invalid(number, &format!("unknown section [{}]", name)));
+            };
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| invalid(number, "expected Key = Value"))?;
+        let section = section.as_deref_mut().ok_or_else(|| invalid(number, "key outside of a section"))?;
+        section.entries.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(config)
+}
+
+/// Writes `config` to `path` through `<path>.tmp` and a rename, so wg-quick
+/// never sees a half-written file. The permissions of an existing file are
+/// kept; a new one is readable by its owner only, as it holds the private key.
+pub fn write_wg_config(config: &WireGuardQuickConfig, path: &Path) -> FailoverResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+    let permissions = std::fs::metadata(path).map(|m| m.permissions()).unwrap_or_else(|_| std::fs::Permissions::from_mode(0o600));
+    let what = |verb: &str, p: &Path| format!("{} {}", verb, p.display());
+    std::fs::write(tmp, config.to_ini()).map_err(|e| FailoverError::io(&what("writing", tmp), e))?;
+    std::fs::set_permissions(tmp, permissions).map_err(|e| FailoverError::io(&what("chmod", tmp), e))?;
+    std::fs::rename(tmp, path).map_err(|e| FailoverError::io(&what("renaming to", path), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+# Site A
+[Interface]
+PrivateKey = aGVsbG8=
+Address = 10.0.0.2/32, fd00::2/128
+PostUp = iptables -A FORWARD -i %i -j ACCEPT
+
+[Peer]
+PublicKey = d29ybGQ=
+PresharedKey = c2VjcmV0
+AllowedIPs = 0.0.0.0/0, ::/0
+endpoint = 203.0.113.7:51820 # trailing comment
+PersistentKeepalive = 25
+
+[Peer]
+PublicKey = b3RoZXI=
+Endpoint = [2001:db8::1]:51820
+";
+
+    #[test]
+    fn wg_config_round_trips_through_the_writer() {
+        let config = parse_wg_config(CONFIG).unwrap();
+        assert_eq!(config.interface.get("address"), Some("10.0.0.2/32, fd00::2/128"));
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(config.endpoint(), Some("203.0.113.7:51820"));
+
+        let written = config.to_ini();
+        assert!(written.starts_with("[Interface]\nPrivateKey = aGVsbG8=\n"));
+        assert_eq!(written.matches("[Peer]").count(), 2);
+        assert_eq!(parse_wg_config(&written).unwrap(), config);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wg0.conf");
+        let mut updated = config.clone();
+        updated.peers[0].set("Endpoint", "198.51.100.9:51820");
+        write_wg_config(&updated, &path).unwrap();
+        let reread = parse_wg_config(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(reread, updated);
+        assert_eq!(reread.endpoint(), Some("198.51.100.9:51820"));
+        assert!(!dir.path().join("wg0.conf.tmp").exists());
+
+        assert!(parse_wg_config("PrivateKey = x\n[Interface]\n").is_err());
+        assert!(parse_wg_config("[Interface]\nListenPort\n").is_err());
+        assert!(parse_wg_config("[Peers]\n").is_err());
+    }
+}